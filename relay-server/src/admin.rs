@@ -0,0 +1,334 @@
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::json;
+use std::fmt;
+use std::sync::Arc;
+use tower_http::trace::TraceLayer;
+use tracing::warn;
+
+use crate::config::{ConfigError, Values};
+use crate::dns::AppSource;
+use crate::hostname::{normalize_host, RequestHost};
+use crate::reload::Reloader;
+use crate::{metrics, request_span, shutdown, AppState, RelayMode, RelayState};
+
+/// Bearer token required by the admin endpoints (ADMIN_TOKEN); kept out of Debug output
+#[derive(Clone, PartialEq)]
+pub struct AdminToken(String);
+
+impl AdminToken {
+    /// Shortest accepted token, so it can't be guessed
+    pub const MIN_LEN: usize = 16;
+
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    /// Read ADMIN_TOKEN; `None` leaves the admin endpoints open
+    pub fn from_values(values: &Values) -> Result<Option<Self>, ConfigError> {
+        values.parse_with("ADMIN_TOKEN", |s| {
+            if s.len() < Self::MIN_LEN {
+                Err(format!("must be at least {} characters", Self::MIN_LEN))
+            } else {
+                Ok(Self::new(s))
+            }
+        })
+    }
+
+    /// Whether `headers` carry `Authorization: Bearer {token}`, compared in constant time
+    pub fn authorizes(&self, headers: &HeaderMap) -> bool {
+        let Some((scheme, token)) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+        else {
+            return false;
+        };
+        let token = token.trim().as_bytes();
+        let expected = self.0.as_bytes();
+        scheme.eq_ignore_ascii_case("bearer")
+            && token.len() == expected.len()
+            && token.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AdminToken(<redacted>)")
+    }
+}
+
+/// State of the admin router
+#[derive(Clone)]
+struct Admin {
+    app: AppState,
+    /// Reloads the configuration for `POST /reload`, when the server has one
+    reloader: Option<Arc<Reloader>>,
+}
+
+/// Build the router for admin listeners (ADMIN_LISTEN)
+///
+/// `/health` and `/ready` are always open, for probes. The metrics, the info
+/// page and the management endpoints need the bearer token when ADMIN_TOKEN is
+/// set. These answer for any Host header; nothing is relayed.
+pub fn build_admin_router(state: impl Into<AppState>, reloader: Option<Arc<Reloader>>) -> Router {
+    let admin = Admin {
+        app: state.into(),
+        reloader,
+    };
+    let protected = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/", get(info_handler))
+        .route("/gateways", get(gateways_handler))
+        .route("/resolve/:domain", get(resolve_handler))
+        .route("/reload", post(reload_handler))
+        .route("/dns-cache/clear", post(clear_dns_cache_handler))
+        .route_layer(middleware::from_fn_with_state(admin.clone(), require_token));
+
+    Router::new()
+        .route("/health", get(health_handler))
+        .route("/ready", get(ready_handler))
+        .merge(protected)
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(middleware::from_fn_with_state(admin.app.shutdown().clone(), shutdown::track_requests))
+        .with_state(admin)
+}
+
+/// Reject requests without the bearer token, when one is configured
+async fn require_token(State(admin): State<Admin>, req: Request, next: Next) -> Response {
+    let state = admin.app.load();
+    match state.admin_token {
+        Some(ref token) if !token.authorizes(req.headers()) => {
+            warn!("Rejected admin request for {}: missing or wrong bearer token", req.uri().path());
+            metrics::inc_admin_auth_failures();
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "Unauthorized",
+            )
+                .into_response()
+        }
+        _ => next.run(req).await,
+    }
+}
+
+/// Prometheus metrics, in the text format
+async fn metrics_handler() -> Response {
+    let metrics = metrics::gather_metrics();
+    (
+        StatusCode::OK,
+        [("content-type", "text/plain; version=0.0.4")],
+        metrics,
+    )
+        .into_response()
+}
+
+/// Liveness: unhealthy only while draining on shutdown
+async fn health_handler(State(admin): State<Admin>) -> Response {
+    // Load balancers take the relay out of rotation while it drains
+    if admin.app.shutdown().is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, "Draining").into_response()
+    } else {
+        (StatusCode::OK, "OK").into_response()
+    }
+}
+
+/// Readiness: not draining, and at least one gateway is healthy
+async fn ready_handler(State(admin): State<Admin>) -> Response {
+    if admin.app.shutdown().is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Draining").into_response();
+    }
+    let state = admin.app.load();
+    if !state.dns_resolver.gateways().iter().any(|gateway| gateway.is_healthy()) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "No healthy gateway").into_response();
+    }
+    (StatusCode::OK, "Ready").into_response()
+}
+
+async fn info_handler(State(admin): State<Admin>) -> Response {
+    info_page(&admin.app.load())
+}
+
+/// Each gateway with its pattern and health
+async fn gateways_handler(State(admin): State<Admin>) -> Response {
+    let state = admin.app.load();
+    let gateways: Vec<_> = state
+        .dns_resolver
+        .gateways()
+        .iter()
+        .map(|gateway| {
+            json!({
+                "name": gateway.name(),
+                "pattern": gateway.pattern(),
+                "healthy": gateway.is_healthy(),
+            })
+        })
+        .collect();
+    Json(gateways).into_response()
+}
+
+/// Resolve a custom domain like a request for it would be, listing the candidate targets
+async fn resolve_handler(State(admin): State<Admin>, Path(domain): Path<String>) -> Response {
+    let domain = match normalize_host(&domain) {
+        Ok(RequestHost::Domain(domain)) => domain,
+        _ => {
+            let error = json!({ "error": format!("not a domain name: {}", domain) });
+            return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+        }
+    };
+
+    let state = admin.app.load();
+    match state.dns_resolver.resolve_app_targets(&domain, "/").await {
+        Ok(targets) => {
+            let targets: Vec<_> = targets
+                .iter()
+                .map(|target| json!({ "url": target.url, "gateway": target.gateway.name() }))
+                .collect();
+            Json(json!({ "domain": domain, "targets": targets })).into_response()
+        }
+        Err(e) => {
+            let error = json!({ "domain": domain, "error": e.to_string() });
+            (StatusCode::BAD_GATEWAY, Json(error)).into_response()
+        }
+    }
+}
+
+/// Reload the configuration, like SIGHUP does
+async fn reload_handler(State(admin): State<Admin>) -> Response {
+    let Some(reloader) = admin.reloader else {
+        return (StatusCode::NOT_FOUND, "Reloading is not available").into_response();
+    };
+    match reloader.reload() {
+        Ok(()) => (StatusCode::OK, "Configuration reloaded").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to reload, keeping the previous configuration: {}", e)).into_response(),
+    }
+}
+
+/// Drop cached DNS answers, so the next requests look their domains up again
+async fn clear_dns_cache_handler(State(admin): State<Admin>) -> Response {
+    admin.app.load().dns_resolver.clear_cache();
+    (StatusCode::OK, "DNS cache cleared").into_response()
+}
+
+/// The info page: relay mode, policies, resolution sources and gateways
+fn info_page(state: &RelayState) -> Response {
+    let relay_mode = state.relay_mode;
+    let txt_relay_mode = state.txt_relay_mode;
+    let challenge_only = match &state.challenge_only {
+        Some(challenge_only) => format!(
+            "enabled (other requests get {}, challenges time out after {}s)",
+            challenge_only.status,
+            challenge_only.timeout.as_secs()
+        ),
+        None => "disabled".to_string(),
+    };
+    let gateways: String = state
+        .dns_resolver
+        .gateways()
+        .iter()
+        .map(|gateway| {
+            format!(
+                "- {} ({}): {}\n",
+                gateway.name(),
+                gateway.pattern().unwrap_or("any name"),
+                if gateway.is_healthy() { "healthy" } else { "unhealthy" }
+            )
+        })
+        .collect();
+    let domain_map = state.dns_resolver.domain_map().map(|domain_map| {
+        let current = domain_map.current();
+        (domain_map.path().display().to_string(), current.len(), current.policy_count())
+    });
+    let http_client = state.proxy_client.settings();
+    let mode_description = match relay_mode {
+        RelayMode::Redirect => "307 redirect (default)",
+        RelayMode::Proxy => "HTTP proxy/tunnel",
+        RelayMode::HttpsRedirect => "307 redirect for challenges, HTTPS redirect on the custom domain otherwise",
+        RelayMode::Deny => "deny (only domains with a policy are relayed)",
+    };
+
+    let policy_sources = format!(
+        "- Mapping file policies: {}\n- TXT record mode= attribute: {}\n",
+        domain_map.as_ref().map_or(0, |(_, _, policies)| *policies),
+        if txt_relay_mode { "honored" } else { "ignored" }
+    );
+
+    let domain_map_source = match domain_map {
+        Some((path, entries, _)) => format!(
+            "- Domain mapping file {} ({} entries): {} requests answered\n",
+            path,
+            entries,
+            metrics::app_resolutions(AppSource::DomainMap.as_str())
+        ),
+        None => "- Domain mapping file: not configured\n".to_string(),
+    };
+
+    let info = format!(
+        r#"
+dstack HTTP-01 ACME Challenge Relay Server
+
+This server relays ACME HTTP-01 challenges to dstack applications.
+
+Current Mode: {}
+Challenge-Only: {}
+
+Per-Domain Relay Policies (override the mode above):
+{}
+Relay Endpoints:
+- /.well-known/acme-challenge/:token - ACME challenge endpoint
+- Any other path on a dstack custom domain is relayed per the mode above
+
+Admin Endpoints (this listener):
+- /metrics - Prometheus metrics
+- /health - Health check
+- /ready - Readiness check
+- /gateways - Gateway health (JSON)
+- /resolve/:domain - Resolve a custom domain (JSON)
+- POST /reload - Reload the configuration
+- POST /dns-cache/clear - Drop cached DNS answers
+
+How it works:
+1. Let's Encrypt requests http://{{custom-domain}}/.well-known/acme-challenge/{{token}}
+2. This server looks up DNS records:
+   - TXT _dstack-app-address.{{custom-domain}} -> {{app-id}}:port
+   - CNAME {{custom-domain}} -> _.{{gateway-base-domain}}
+3. In redirect mode: Returns 307 redirect to https://{{app-id}}[-{{port}}].{{gateway-base-domain}}/.well-known/acme-challenge/{{token}}
+   In proxy mode: Proxies the request directly to the target HTTPS endpoint
+   In https-redirect mode: Challenges are redirected as in redirect mode; any other
+   path gets a 301/308 redirect to https://{{custom-domain}}{{path}}
+4. The ACME client in dstack responds with the challenge
+
+Resolution Sources (in order):
+{}- DNS TXT records: {} requests answered
+
+Gateways (in order of matching):
+{}
+Proxy Mode Features:
+- Connection pooling ({} client, up to {} idle connections per host)
+- Request streaming for efficient memory usage
+- WebSocket and other HTTP Upgrade requests are tunneled to the app
+- Timeouts: {}s to connect, {}s for the gateway to answer
+- Optimized for high traffic scenarios
+
+Status: Running
+"#,
+        mode_description,
+        challenge_only,
+        policy_sources,
+        domain_map_source,
+        metrics::app_resolutions(AppSource::Dns.as_str()),
+        gateways,
+        state.proxy_client.name(),
+        http_client.pool_max_idle_per_host,
+        http_client.connect_timeout.as_secs(),
+        http_client.request_timeout.as_secs()
+    );
+
+    (StatusCode::OK, info).into_response()
+}
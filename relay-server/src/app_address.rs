@@ -0,0 +1,254 @@
+use crate::dns::DnsError;
+use crate::hostname::is_valid_label;
+use crate::policy::RelayMode;
+
+/// Priority of records that don't set one; lower values are tried first
+pub const DEFAULT_PRIORITY: u16 = 10;
+
+/// One candidate target parsed from a `_dstack-app-address` TXT record
+///
+/// Supported formats:
+/// - `app-id:port`
+/// - `app-id:port@gateway` - explicit gateway instead of the CNAME
+/// - `v=dstack1; app=app-id; port=port; gw=gateway; prio=priority; mode=mode` -
+///   `port` defaults to 80, `gw`, `prio` and `mode` are optional and unknown keys
+///   are ignored
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppAddress {
+    pub app_id: String,
+    pub port: u16,
+    /// Explicit gateway base domain; when `None`, the CNAME decides
+    pub gateway: Option<String>,
+    pub priority: u16,
+    /// Relay mode requested by the record (`mode=`), honored only when enabled
+    pub mode: Option<RelayMode>,
+}
+
+impl AppAddress {
+    /// Parse a single TXT record value
+    pub fn parse(txt: &str) -> Result<Self, DnsError> {
+        let txt = txt.trim();
+        let address = if txt.contains('=') {
+            Self::parse_key_value(txt)?
+        } else {
+            Self::parse_compact(txt)?
+        };
+
+        address.validate(&format!("TXT record: {}", txt))
+    }
+
+    /// Check app-id and gateway are plain DNS names, naming `source` in the error
+    pub(crate) fn validate(self, source: &str) -> Result<Self, DnsError> {
+        if !is_valid_label(&self.app_id) {
+            return Err(DnsError::ParseError(format!(
+                "Invalid app-id '{}' in {}",
+                self.app_id, source
+            )));
+        }
+        if let Some(ref gateway) = self.gateway {
+            if !gateway.split('.').all(|label| label == "_" || is_valid_label(label)) {
+                return Err(DnsError::ParseError(format!(
+                    "Invalid gateway '{}' in {}",
+                    gateway, source
+                )));
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Parse and order all TXT record values of one name by priority
+    ///
+    /// Unparseable records are skipped as long as at least one record is valid;
+    /// ties keep the order of the record values so the result is deterministic.
+    pub fn parse_all(records: &[String]) -> Result<Vec<Self>, DnsError> {
+        let mut records: Vec<&String> = records.iter().collect();
+        records.sort();
+
+        let mut addresses = Vec::new();
+        let mut last_error = None;
+        for record in records {
+            match Self::parse(record) {
+                Ok(address) => addresses.push(address),
+                Err(e) => {
+                    tracing::warn!("Skipping TXT record: {}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        if addresses.is_empty() {
+            return Err(last_error
+                .unwrap_or_else(|| DnsError::NoRecordsFound("No TXT records".to_string())));
+        }
+
+        addresses.sort_by_key(|address| address.priority);
+        Ok(addresses)
+    }
+
+    /// `app-id:port` or `app-id:port@gateway`
+    fn parse_compact(txt: &str) -> Result<Self, DnsError> {
+        let (address, gateway) = match txt.split_once('@') {
+            Some((address, gateway)) => (address, Some(normalize_gateway(gateway))),
+            None => (txt, None),
+        };
+
+        let parts: Vec<&str> = address.split(':').collect();
+        if parts.len() != 2 {
+            return Err(DnsError::ParseError(format!(
+                "Expected 'app-id:port' format, got: {}",
+                txt
+            )));
+        }
+
+        Ok(Self {
+            app_id: parts[0].to_string(),
+            port: parse_port(parts[1], txt)?,
+            gateway,
+            priority: DEFAULT_PRIORITY,
+            mode: None,
+        })
+    }
+
+    /// `v=dstack1; app=...; port=...; gw=...; prio=...`
+    fn parse_key_value(txt: &str) -> Result<Self, DnsError> {
+        let mut version = None;
+        let mut app_id = None;
+        let mut port = 80;
+        let mut gateway = None;
+        let mut priority = DEFAULT_PRIORITY;
+        let mut mode = None;
+
+        for field in txt.split(';').map(str::trim).filter(|field| !field.is_empty()) {
+            let (key, value) = field.split_once('=').ok_or_else(|| {
+                DnsError::ParseError(format!("Expected 'key=value' field '{}' in TXT record: {}", field, txt))
+            })?;
+            let value = value.trim();
+
+            match key.trim() {
+                "v" => version = Some(value),
+                "app" => app_id = Some(value.to_string()),
+                "port" => port = parse_port(value, txt)?,
+                "gw" => gateway = Some(normalize_gateway(value)),
+                "prio" => {
+                    priority = value.parse().map_err(|_| {
+                        DnsError::ParseError(format!("Invalid priority in TXT record: {}", txt))
+                    })?
+                }
+                "mode" => {
+                    mode = Some(RelayMode::parse(value).ok_or_else(|| {
+                        DnsError::ParseError(format!("Invalid mode in TXT record: {}", txt))
+                    })?)
+                }
+                _ => {}
+            }
+        }
+
+        if version != Some("dstack1") {
+            return Err(DnsError::ParseError(format!(
+                "Expected 'v=dstack1' in TXT record: {}",
+                txt
+            )));
+        }
+
+        let app_id = app_id
+            .ok_or_else(|| DnsError::ParseError(format!("Missing 'app' in TXT record: {}", txt)))?;
+
+        Ok(Self {
+            app_id,
+            port,
+            gateway,
+            priority,
+            mode,
+        })
+    }
+}
+
+fn parse_port(port: &str, txt: &str) -> Result<u16, DnsError> {
+    port.trim()
+        .parse::<u16>()
+        .map_err(|_| DnsError::ParseError(format!("Invalid port in TXT record: {}", txt)))
+}
+
+pub(crate) fn normalize_gateway(gateway: &str) -> String {
+    gateway.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(app_id: &str, port: u16, gateway: Option<&str>, priority: u16) -> AppAddress {
+        AppAddress {
+            app_id: app_id.to_string(),
+            port,
+            gateway: gateway.map(str::to_string),
+            priority,
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_compact() {
+        assert_eq!(AppAddress::parse("my-app:80"), Ok(address("my-app", 80, None, DEFAULT_PRIORITY)));
+        assert_eq!(
+            AppAddress::parse("my-app:8080@Prod5.phala.network."),
+            Ok(address("my-app", 8080, Some("prod5.phala.network"), DEFAULT_PRIORITY))
+        );
+    }
+
+    #[test]
+    fn test_parse_key_value() {
+        assert_eq!(
+            AppAddress::parse("v=dstack1; app=my-app; port=8080; gw=prod7.phala.network; prio=5; x=y"),
+            Ok(address("my-app", 8080, Some("prod7.phala.network"), 5))
+        );
+        assert_eq!(
+            AppAddress::parse("v=dstack1;app=my-app"),
+            Ok(address("my-app", 80, None, DEFAULT_PRIORITY))
+        );
+        assert_eq!(
+            AppAddress::parse("v=dstack1; app=my-app; mode=proxy"),
+            Ok(AppAddress {
+                mode: Some(RelayMode::Proxy),
+                ..address("my-app", 80, None, DEFAULT_PRIORITY)
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_records() {
+        assert!(AppAddress::parse("my-app").is_err());
+        assert!(AppAddress::parse("my-app:80:90").is_err());
+        assert!(AppAddress::parse("my-app:http").is_err());
+        assert!(AppAddress::parse("evil.com/x:80").is_err());
+        assert!(AppAddress::parse("my-app:80@evil.com/x").is_err());
+        assert!(AppAddress::parse("app=my-app; port=80").is_err());
+        assert!(AppAddress::parse("v=dstack2; app=my-app").is_err());
+        assert!(AppAddress::parse("v=dstack1; port=80").is_err());
+        assert!(AppAddress::parse("v=dstack1; app=my-app; mode=tunnel").is_err());
+    }
+
+    #[test]
+    fn test_parse_all_orders_by_priority() {
+        let records = vec![
+            "v=dstack1; app=new-app; prio=20".to_string(),
+            "garbage".to_string(),
+            "old-app:80".to_string(),
+            "v=dstack1; app=first-app; prio=1".to_string(),
+        ];
+
+        let addresses = AppAddress::parse_all(&records).unwrap();
+        let app_ids: Vec<&str> = addresses.iter().map(|a| a.app_id.as_str()).collect();
+        assert_eq!(app_ids, vec!["first-app", "old-app", "new-app"]);
+    }
+
+    #[test]
+    fn test_parse_all_fails_without_valid_records() {
+        assert!(matches!(
+            AppAddress::parse_all(&["garbage".to_string()]),
+            Err(DnsError::ParseError(_))
+        ));
+        assert!(matches!(AppAddress::parse_all(&[]), Err(DnsError::NoRecordsFound(_))));
+    }
+}
@@ -0,0 +1,116 @@
+use axum::http::{Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use std::time::Duration;
+
+use crate::config::{ConfigError, Values};
+
+/// Shortest accepted token: RFC 8555 requires at least 128 bits of entropy,
+/// which is 22 base64url characters
+pub const MIN_TOKEN_LEN: usize = 22;
+
+/// Longest accepted token; CAs issue 43-character tokens (32 random bytes)
+pub const MAX_TOKEN_LEN: usize = 128;
+
+/// Default time allowed for resolving and relaying one challenge
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether `token` looks like an ACME token: unpadded base64url of a sane length
+pub fn is_valid_token(token: &str) -> bool {
+    (MIN_TOKEN_LEN..=MAX_TOKEN_LEN).contains(&token.len())
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Challenge-only mode: nothing but `/.well-known/acme-challenge/{token}` is relayed
+#[derive(Clone, Debug)]
+pub struct ChallengeOnly {
+    /// Bound on DNS resolution plus the redirect or proxied request for a challenge
+    pub timeout: Duration,
+    /// Response for everything that isn't a valid challenge request
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl Default for ChallengeOnly {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            status: StatusCode::NOT_FOUND,
+            body: "Not Found".to_string(),
+        }
+    }
+}
+
+impl ChallengeOnly {
+    /// Read CHALLENGE_ONLY, CHALLENGE_TIMEOUT, CHALLENGE_ONLY_STATUS and CHALLENGE_ONLY_BODY;
+    /// `None` unless CHALLENGE_ONLY is true
+    pub fn from_values(values: &Values) -> Result<Option<Self>, ConfigError> {
+        if !values.flag("CHALLENGE_ONLY", false)? {
+            return Ok(None);
+        }
+
+        let default = Self::default();
+        let timeout = values.seconds("CHALLENGE_TIMEOUT")?.unwrap_or(default.timeout);
+        if timeout.is_zero() {
+            return Err(ConfigError::new("CHALLENGE_TIMEOUT", "must be at least 1 second"));
+        }
+        let status = values.parse_with("CHALLENGE_ONLY_STATUS", |s| {
+            match s.parse::<u16>() {
+                Ok(code @ 200..=599) => Ok(StatusCode::from_u16(code).unwrap()),
+                _ => Err("expected an HTTP status code between 200 and 599".to_string()),
+            }
+        })?;
+
+        Ok(Some(Self {
+            timeout,
+            status: status.unwrap_or(default.status),
+            body: values.var("CHALLENGE_ONLY_BODY").unwrap_or(default.body),
+        }))
+    }
+
+    /// Why a challenge request is refused, if it is: "method" or "token"
+    pub fn check(&self, method: &Method, token: &str) -> Option<&'static str> {
+        if method != Method::GET && method != Method::HEAD {
+            Some("method")
+        } else if !is_valid_token(token) {
+            Some("token")
+        } else {
+            None
+        }
+    }
+
+    /// The configured response for requests that are not relayed
+    pub fn reject(&self) -> Response {
+        (self.status, self.body.clone()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_validation() {
+        assert!(is_valid_token("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0"));
+        assert!(is_valid_token(&"a-_Z9".repeat(5)[..22]));
+        assert!(is_valid_token(&"A".repeat(MAX_TOKEN_LEN)));
+
+        assert!(!is_valid_token("token-123"));
+        assert!(!is_valid_token(&"A".repeat(MAX_TOKEN_LEN + 1)));
+        assert!(!is_valid_token("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0="));
+        assert!(!is_valid_token("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEu+/"));
+        assert!(!is_valid_token("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjE%2e"));
+    }
+
+    #[test]
+    fn test_check_methods() {
+        let challenge_only = ChallengeOnly::default();
+        let token = "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0";
+
+        assert_eq!(challenge_only.check(&Method::GET, token), None);
+        assert_eq!(challenge_only.check(&Method::HEAD, token), None);
+        assert_eq!(challenge_only.check(&Method::POST, token), Some("method"));
+        assert_eq!(challenge_only.check(&Method::GET, "../etc"), Some("token"));
+    }
+}
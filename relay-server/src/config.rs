@@ -0,0 +1,800 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::admin::AdminToken;
+use crate::challenge::ChallengeOnly;
+use crate::dns::{DnsError, DnsSettings, HostTemplate, UpstreamSettings};
+use crate::forwarded::TrustedProxies;
+use crate::gateway::DEFAULT_PATTERN;
+use crate::listener::{self, ListenAddr, ListenerConfig, ListenerRole};
+use crate::policy::RelayMode;
+use crate::proxy::{HttpClientSettings, ProxyClient};
+use crate::proxy_protocol::ProxyVersion;
+use crate::tunnel::TunnelClient;
+
+/// A setting whose value can't be used
+///
+/// Invalid values are never replaced with defaults: running with a weaker
+/// configuration than the one asked for (e.g. no gateway allowlist) is worse
+/// than not starting.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    pub setting: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(setting: &str, message: impl Into<String>) -> Self {
+        Self {
+            setting: setting.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// Attribute an error loading a file or template to the setting naming it
+    pub fn from_dns(setting: &str, e: DnsError) -> Self {
+        match e {
+            DnsError::InvalidConfig(message) => Self::new(setting, message),
+            other => Self::new(setting, other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.setting, self.message)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// A setting the relay understands
+pub struct Setting {
+    /// Environment variable; the config file key is its lowercase form and the
+    /// command line flag its lowercase form with dashes (`--relay-mode`)
+    pub name: &'static str,
+    /// Value used when the setting is unset, as shown by `--print-config`
+    pub default: Option<&'static str>,
+    /// Whether a configuration reload applies it, rather than only a restart
+    pub reloadable: bool,
+    /// Whether the value is hidden by `--print-config`
+    pub secret: bool,
+}
+
+const fn setting(name: &'static str, default: Option<&'static str>) -> Setting {
+    Setting {
+        name,
+        default,
+        reloadable: true,
+        secret: false,
+    }
+}
+
+/// A setting of the listeners or HTTP clients, which only change on restart
+const fn restart(name: &'static str, default: Option<&'static str>) -> Setting {
+    Setting {
+        name,
+        default,
+        reloadable: false,
+        secret: false,
+    }
+}
+
+/// A credential, never printed
+const fn secret(name: &'static str) -> Setting {
+    Setting {
+        name,
+        default: None,
+        reloadable: true,
+        secret: true,
+    }
+}
+
+/// Every setting, in the order `--print-config` lists them
+pub const SETTINGS: &[Setting] = &[
+    // Listeners
+    restart("BIND_ADDRESS", Some("0.0.0.0")),
+    restart("PORT", Some("8081")),
+    restart("LISTEN", None),
+    restart("ADMIN_LISTEN", Some(DEFAULT_ADMIN_LISTEN)),
+    secret("ADMIN_TOKEN"),
+    restart("TLS_PASSTHROUGH_PORT", None),
+    restart("TLS_PASSTHROUGH_LISTEN", None),
+    restart("PROXY_PROTOCOL", Some("false")),
+    restart("CONFIG_RELOAD_INTERVAL", Some("5")),
+    restart("SHUTDOWN_TIMEOUT", Some("25")),
+    setting("TRUSTED_PROXIES", Some("127.0.0.0/8,::1")),
+    // Relaying
+    setting("RELAY_MODE", Some("redirect")),
+    setting("TXT_RELAY_MODE", Some("false")),
+    setting("CHALLENGE_ONLY", Some("false")),
+    setting("CHALLENGE_ONLY_STATUS", Some("404")),
+    setting("CHALLENGE_ONLY_BODY", Some("Not Found")),
+    setting("CHALLENGE_TIMEOUT", Some("5")),
+    // Connections to the gateway
+    restart("PROXY_CLIENT", Some("hyper")),
+    restart("GATEWAY_CONNECT_TIMEOUT", Some("10")),
+    restart("GATEWAY_REQUEST_TIMEOUT", Some("30")),
+    restart("GATEWAY_POOL_MAX_IDLE", Some("100")),
+    restart("GATEWAY_POOL_IDLE_TIMEOUT", Some("90")),
+    restart("TUNNEL_IDLE_TIMEOUT", Some("300")),
+    restart("UPSTREAM_PROXY_PROTOCOL", None),
+    // Gateways
+    setting("GATEWAYS_FILE", None),
+    setting("ALLOWED_DOMAIN_REGEX", Some(DEFAULT_PATTERN)),
+    setting("GATEWAY_DOMAIN_CAPTURE_GROUP", Some("1")),
+    setting("TARGET_HOST_TEMPLATE", Some(HostTemplate::DEFAULT)),
+    setting("TARGET_DEFAULT_PORT", Some("80")),
+    setting("FALLBACK_GATEWAY_DOMAIN", None),
+    setting("CNAME_MAX_DEPTH", Some("8")),
+    // DNS
+    setting("DNS_SERVERS", None),
+    setting("DNS_USE_SYSTEM_CONFIG", Some("false")),
+    setting("DNS_TIMEOUT", None),
+    setting("DNS_ATTEMPTS", None),
+    setting("DNSSEC_REQUIRED_ZONES", None),
+    setting("DNS_CACHE_MIN_TTL", Some("30")),
+    setting("DNS_CACHE_MAX_TTL", Some("300")),
+    setting("DNS_CACHE_MAX_ENTRIES", Some("10000")),
+    setting("DNS_NEGATIVE_CACHE_TTL", Some("60")),
+    setting("DOMAIN_MAP_FILE", None),
+    setting("DOMAIN_MAP_RELOAD_INTERVAL", Some("5")),
+];
+
+/// Look up a setting by environment variable name
+pub fn find_setting(name: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.name == name)
+}
+
+/// Where the value of a setting came from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    File(PathBuf),
+    Env,
+    Cli,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Env => write!(f, "environment"),
+            Source::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// Command line: `[--config FILE] [--print-config] [--SETTING VALUE]...`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Args {
+    /// TOML config file, overriding CONFIG_FILE
+    pub config_file: Option<PathBuf>,
+    /// Print the effective settings and their sources
+    pub print_config: bool,
+    pub help: bool,
+    /// Settings given as flags, e.g. `--relay-mode proxy` sets RELAY_MODE
+    pub settings: Vec<(&'static str, String)>,
+}
+
+impl Args {
+    /// Parse the command line arguments (without the program name)
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let invalid = |message: String| ConfigError::new("command line", message);
+
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(invalid(format!("unexpected argument '{}'", arg)));
+            };
+            let (flag, inline_value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (flag, None),
+            };
+
+            match flag {
+                "print-config" => parsed.print_config = true,
+                "help" => parsed.help = true,
+                _ => {
+                    // `--config FILE`, or a setting named like its variable: `--relay-mode` for RELAY_MODE
+                    let setting = match flag {
+                        "config" => None,
+                        _ => Some(
+                            find_setting(&flag.to_ascii_uppercase().replace('-', "_"))
+                                .ok_or_else(|| invalid(format!("unknown option --{}", flag)))?,
+                        ),
+                    };
+                    let value = inline_value
+                        .or_else(|| args.next())
+                        .ok_or_else(|| invalid(format!("--{} needs a value", flag)))?;
+                    match setting {
+                        Some(setting) => parsed.settings.push((setting.name, value)),
+                        None => parsed.config_file = Some(PathBuf::from(value)),
+                    }
+                }
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// The config file: `--config`, else CONFIG_FILE
+    pub fn config_file(&self) -> Option<PathBuf> {
+        self.config_file_with(|name| std::env::var(name).ok())
+    }
+
+    fn config_file_with(&self, env: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+        self.config_file
+            .clone()
+            .or_else(|| env("CONFIG_FILE").filter(|path| !path.trim().is_empty()).map(PathBuf::from))
+    }
+
+    /// Usage text for `--help`
+    pub fn usage() -> String {
+        let mut usage = String::from(
+            "Usage: relay-server [--config FILE] [--print-config] [--SETTING VALUE]...\n\n\
+             Settings are read from the config file (CONFIG_FILE), then environment variables,\n\
+             then command line flags, later ones overriding earlier ones.\n\nSettings:\n",
+        );
+        for setting in SETTINGS {
+            let flag = format!("--{}", setting.name.to_ascii_lowercase().replace('_', "-"));
+            match setting.default {
+                Some(default) => usage.push_str(&format!("  {:<32} {} (default: {})\n", flag, setting.name, default)),
+                None => usage.push_str(&format!("  {:<32} {}\n", flag, setting.name)),
+            }
+        }
+        usage
+    }
+}
+
+/// Raw setting values, layered from the config file, environment variables and
+/// command line flags (later layers override earlier ones)
+#[derive(Clone, Debug, Default)]
+pub struct Values {
+    values: BTreeMap<&'static str, (String, Source)>,
+}
+
+impl Values {
+    /// Settings from the environment only
+    pub fn from_env() -> Self {
+        let mut values = Self::default();
+        values.merge_env(|name| std::env::var(name).ok());
+        values
+    }
+
+    /// Read the config file (`--config`, else CONFIG_FILE), then the environment,
+    /// then the command line flags in `args`
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        Self::load_with(args, |name| std::env::var(name).ok())
+    }
+
+    /// `load`, reading environment variables with `env`
+    pub fn load_with(args: &Args, env: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut values = Self::default();
+
+        if let Some(path) = args.config_file_with(&env) {
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| ConfigError::new("CONFIG_FILE", format!("{}: {}", path.display(), e)))?;
+            values.merge_file(&path, &content)?;
+        }
+
+        values.merge_env(env);
+
+        for (name, value) in &args.settings {
+            values.set(name, value, Source::Cli);
+        }
+
+        Ok(values)
+    }
+
+    /// Layer a TOML config file with one key per setting, e.g. `relay_mode = "proxy"`
+    ///
+    /// Arrays are joined with commas, so lists can be written either way.
+    pub fn merge_file(&mut self, path: &Path, content: &str) -> Result<(), ConfigError> {
+        let invalid = |message: String| ConfigError::new("CONFIG_FILE", format!("{}: {}", path.display(), message));
+
+        let table: toml::Table = toml::from_str(content).map_err(|e| invalid(e.to_string()))?;
+        for (key, value) in table {
+            let setting = find_setting(&key.to_ascii_uppercase())
+                .filter(|_| key == key.to_ascii_lowercase())
+                .ok_or_else(|| invalid(format!("unknown setting '{}'", key)))?;
+            let value = match value {
+                toml::Value::Array(items) => items
+                    .iter()
+                    .map(scalar)
+                    .collect::<Option<Vec<_>>>()
+                    .map(|items| items.join(",")),
+                value => scalar(&value),
+            }
+            .ok_or_else(|| invalid(format!("{}: expected a string, number, boolean or list of those", key)))?;
+            self.set(setting.name, &value, Source::File(path.to_path_buf()));
+        }
+
+        Ok(())
+    }
+
+    /// Layer the environment variables of all known settings; empty ones count as unset
+    fn merge_env(&mut self, env: impl Fn(&str) -> Option<String>) {
+        for setting in SETTINGS {
+            if let Some(value) = env(setting.name).filter(|value| !value.trim().is_empty()) {
+                self.set(setting.name, &value, Source::Env);
+            }
+        }
+    }
+
+    /// Set a setting, replacing any value from an earlier layer
+    pub fn set(&mut self, name: &'static str, value: &str, source: Source) {
+        self.values.insert(name, (value.to_string(), source));
+    }
+
+    /// Set a setting as if given on the command line
+    pub fn with(mut self, name: &'static str, value: &str) -> Self {
+        self.set(name, value, Source::Cli);
+        self
+    }
+
+    /// Where a setting comes from, `None` when it is unset
+    pub fn source(&self, name: &str) -> Option<&Source> {
+        self.values.get(name).map(|(_, source)| source)
+    }
+
+    /// The value of a setting; empty counts as unset
+    pub fn var(&self, name: &str) -> Option<String> {
+        self.values
+            .get(name)
+            .map(|(value, _)| value.clone())
+            .filter(|value| !value.trim().is_empty())
+    }
+
+    /// Parse a setting with `parse`, `None` when unset
+    pub fn parse_with<T>(
+        &self,
+        name: &str,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, ConfigError> {
+        self.var(name)
+            .map(|value| {
+                parse(value.trim()).map_err(|e| ConfigError::new(name, format!("invalid value '{}': {}", value, e)))
+            })
+            .transpose()
+    }
+
+    /// Parse a setting with `FromStr`, `None` when unset
+    pub fn parse<T>(&self, name: &str) -> Result<Option<T>, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.parse_with(name, |value| value.parse::<T>().map_err(|e| e.to_string()))
+    }
+
+    /// A boolean setting ("true" or "false"), `default` when unset
+    pub fn flag(&self, name: &str, default: bool) -> Result<bool, ConfigError> {
+        Ok(self
+            .parse_with(name, |value| value.parse::<bool>().map_err(|_| "expected true or false".to_string()))?
+            .unwrap_or(default))
+    }
+
+    /// A duration in whole seconds
+    pub fn seconds(&self, name: &str) -> Result<Option<Duration>, ConfigError> {
+        Ok(self.parse::<u64>(name)?.map(Duration::from_secs))
+    }
+
+    /// A TCP port to listen on; 0 is rejected rather than binding a random port
+    pub fn port(&self, name: &str) -> Result<Option<u16>, ConfigError> {
+        self.parse_with(name, |value| match value.parse::<u16>() {
+            Ok(0) => Err("port must be between 1 and 65535".to_string()),
+            Ok(port) => Ok(port),
+            Err(e) => Err(e.to_string()),
+        })
+    }
+
+    /// Every setting with its effective value and where it comes from, one per line
+    pub fn describe(&self) -> String {
+        SETTINGS
+            .iter()
+            .map(|setting| match (self.values.get(setting.name), setting.default) {
+                (Some((_, source)), _) if setting.secret => format!("{}=<redacted>  # {}\n", setting.name, source),
+                (Some((value, source)), _) => format!("{}={}  # {}\n", setting.name, value, source),
+                (None, Some(default)) => format!("{}={}  # default\n", setting.name, default),
+                (None, None) => format!("# {} is unset\n", setting.name),
+            })
+            .collect()
+    }
+}
+
+/// A TOML string, number or boolean as a setting value
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// The relay's configuration, validated
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Listeners: LISTEN (or BIND_ADDRESS and PORT) relay, TLS_PASSTHROUGH_LISTEN (or
+    /// BIND_ADDRESS and TLS_PASSTHROUGH_PORT) pass TLS through, ADMIN_LISTEN serve operators
+    pub listeners: Vec<ListenerConfig>,
+    /// Expect a PROXY protocol header on accepted connections
+    pub proxy_protocol: bool,
+    pub trusted_proxies: TrustedProxies,
+    pub relay_mode: RelayMode,
+    pub txt_relay_mode: bool,
+    pub challenge_only: Option<ChallengeOnly>,
+    pub admin_token: Option<AdminToken>,
+    /// "hyper" or "reqwest"
+    pub proxy_client: String,
+    pub http_client: HttpClientSettings,
+    pub tunnel_idle_timeout: Duration,
+    pub upstream_proxy_protocol: Option<ProxyVersion>,
+    pub upstream: UpstreamSettings,
+    pub dns: DnsSettings,
+    pub domain_map_file: Option<PathBuf>,
+    pub domain_map_reload_interval: Duration,
+    /// How often the config file is checked for changes (zero disables)
+    pub reload_interval: Duration,
+    /// How long requests and tunnels may run after SIGTERM/SIGINT
+    pub shutdown_timeout: Duration,
+}
+
+/// Admin listener when ADMIN_LISTEN is unset: local clients only
+const DEFAULT_ADMIN_LISTEN: &str = "127.0.0.1:9090";
+
+/// LISTEN (BIND_ADDRESS:PORT when unset) as relay listeners, then
+/// TLS_PASSTHROUGH_LISTEN (BIND_ADDRESS:TLS_PASSTHROUGH_PORT when unset), then ADMIN_LISTEN
+fn listeners(
+    values: &Values,
+    default: SocketAddr,
+    tls_passthrough_default: Option<SocketAddr>,
+) -> Result<Vec<ListenerConfig>, ConfigError> {
+    let relay = values
+        .parse_with("LISTEN", listener::parse_list)?
+        .unwrap_or_else(|| vec![ListenAddr::Tcp(default)]);
+    if relay.is_empty() {
+        return Err(ConfigError::new("LISTEN", "needs at least one address"));
+    }
+    let (tls_passthrough_setting, tls_passthrough) = match values.parse_with("TLS_PASSTHROUGH_LISTEN", listener::parse_list)? {
+        Some(list) if list.is_empty() => {
+            return Err(ConfigError::new("TLS_PASSTHROUGH_LISTEN", "needs at least one address"));
+        }
+        Some(list) => ("TLS_PASSTHROUGH_LISTEN", list),
+        None => ("TLS_PASSTHROUGH_PORT", tls_passthrough_default.map(ListenAddr::Tcp).into_iter().collect()),
+    };
+    if let Some(addr) = tls_passthrough.iter().find(|addr| matches!(addr, ListenAddr::Unix(_))) {
+        return Err(ConfigError::new(tls_passthrough_setting, format!("{} is not a TCP address", addr)));
+    }
+    // "none" turns the admin listener off
+    let admin = match values.var("ADMIN_LISTEN").as_deref().unwrap_or(DEFAULT_ADMIN_LISTEN) {
+        "none" => Vec::new(),
+        list => listener::parse_list(list).map_err(|message| ConfigError::new("ADMIN_LISTEN", message))?,
+    };
+
+    let mut listeners: Vec<ListenerConfig> = Vec::new();
+    let all = relay
+        .into_iter()
+        .map(|addr| ListenerConfig::new(addr, ListenerRole::Relay))
+        .chain(tls_passthrough.into_iter().map(|addr| ListenerConfig::new(addr, ListenerRole::TlsPassthrough)))
+        .chain(admin.into_iter().map(|addr| ListenerConfig::new(addr, ListenerRole::Admin)));
+    for listener in all {
+        if listeners.iter().any(|other| other.addr == listener.addr) {
+            let setting = match listener.role {
+                ListenerRole::Relay => "LISTEN",
+                ListenerRole::TlsPassthrough => tls_passthrough_setting,
+                ListenerRole::Admin => "ADMIN_LISTEN",
+            };
+            return Err(ConfigError::new(setting, format!("{} is listed twice", listener.addr)));
+        }
+        listeners.push(listener);
+    }
+
+    // TLS and HTTP can't share a port, even on different addresses of the same host
+    let port = |listener: &ListenerConfig| match listener.addr {
+        ListenAddr::Tcp(addr) => Some(addr.port()),
+        ListenAddr::Unix(_) => None,
+    };
+    for tls in listeners.iter().filter(|listener| listener.role == ListenerRole::TlsPassthrough) {
+        let taken = listeners
+            .iter()
+            .find(|other| other.role != ListenerRole::TlsPassthrough && port(other) == port(tls));
+        if let Some(other) = taken {
+            return Err(ConfigError::new(
+                tls_passthrough_setting,
+                format!("{} is on the same port as {}", tls.addr, other.addr),
+            ));
+        }
+    }
+    Ok(listeners)
+}
+
+impl Config {
+    /// Validate raw values, using defaults for anything unset
+    /// Fails on the first invalid setting, or a gateway file that can't be loaded
+    pub fn from_values(values: &Values) -> Result<Self, ConfigError> {
+        let bind_address = values
+            .parse::<IpAddr>("BIND_ADDRESS")?
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let port = values.port("PORT")?.unwrap_or(8081);
+        let tls_passthrough_port = values.port("TLS_PASSTHROUGH_PORT")?;
+        let listeners = listeners(
+            values,
+            SocketAddr::new(bind_address, port),
+            tls_passthrough_port.map(|port| SocketAddr::new(bind_address, port)),
+        )?;
+
+        let proxy_client = values
+            .parse_with("PROXY_CLIENT", |s| match s {
+                "hyper" | "reqwest" => Ok(s.to_string()),
+                _ => Err("expected hyper or reqwest".to_string()),
+            })?
+            .unwrap_or_else(|| "hyper".to_string());
+
+        // Tunnels without traffic are closed after this many seconds (0 disables)
+        let tunnel_idle_timeout = values
+            .seconds("TUNNEL_IDLE_TIMEOUT")?
+            .unwrap_or(Duration::from_secs(300));
+        let upstream_proxy_protocol = values.parse_with("UPSTREAM_PROXY_PROTOCOL", |s| {
+            ProxyVersion::parse(s).ok_or_else(|| "expected v1 or v2".to_string())
+        })?;
+
+        // Mapping file checked for changes every DOMAIN_MAP_RELOAD_INTERVAL seconds (0 disables)
+        let domain_map_reload_interval = values
+            .seconds("DOMAIN_MAP_RELOAD_INTERVAL")?
+            .unwrap_or(Duration::from_secs(5));
+
+        Ok(Self {
+            listeners,
+            proxy_protocol: values.flag("PROXY_PROTOCOL", false)?,
+            trusted_proxies: TrustedProxies::from_values(values)?,
+            relay_mode: RelayMode::from_values(values)?,
+            txt_relay_mode: values.flag("TXT_RELAY_MODE", false)?,
+            challenge_only: ChallengeOnly::from_values(values)?,
+            admin_token: AdminToken::from_values(values)?,
+            proxy_client,
+            http_client: HttpClientSettings::from_values(values)?,
+            tunnel_idle_timeout,
+            upstream_proxy_protocol,
+            upstream: UpstreamSettings::from_values(values)?,
+            dns: DnsSettings::from_values(values)?,
+            domain_map_file: values.var("DOMAIN_MAP_FILE").map(PathBuf::from),
+            domain_map_reload_interval,
+            reload_interval: values
+                .seconds("CONFIG_RELOAD_INTERVAL")?
+                .unwrap_or(Duration::from_secs(5)),
+            shutdown_timeout: values
+                .seconds("SHUTDOWN_TIMEOUT")?
+                .unwrap_or(Duration::from_secs(25)),
+        })
+    }
+
+    /// The configuration from environment variables alone
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_values(&Values::from_env())
+    }
+
+    /// Client for proxied requests to the gateway
+    pub fn proxy_client(&self) -> ProxyClient {
+        ProxyClient::new(&self.proxy_client, self.http_client)
+    }
+
+    /// Client for upgrade requests (WebSocket) to the gateway
+    pub fn tunnel_client(&self) -> TunnelClient {
+        TunnelClient::new(self.tunnel_idle_timeout)
+            .with_http_settings(self.http_client)
+            .with_proxy_protocol(self.upstream_proxy_protocol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, ConfigError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_unset_and_empty() {
+        let values = Values::default().with("PORT", " ");
+        assert_eq!(values.parse::<u16>("PORT"), Ok(None));
+        assert_eq!(values.parse::<u16>("TLS_PASSTHROUGH_PORT"), Ok(None));
+        assert_eq!(values.flag("PROXY_PROTOCOL", true), Ok(true));
+    }
+
+    #[test]
+    fn test_invalid_values_are_errors() {
+        let values = Values::default()
+            .with("PORT", "80a")
+            .with("TLS_PASSTHROUGH_PORT", "0")
+            .with("PROXY_PROTOCOL", "yes");
+
+        let e = values.port("PORT").unwrap_err();
+        assert_eq!(e.setting, "PORT");
+        assert!(e.to_string().starts_with("PORT: invalid value '80a'"), "{}", e);
+        assert!(values.port("TLS_PASSTHROUGH_PORT").is_err());
+        assert!(values.flag("PROXY_PROTOCOL", false).is_err());
+
+        let e = Config::from_values(&Values::default().with("TLS_PASSTHROUGH_PORT", "8081")).unwrap_err();
+        assert_eq!(e.setting, "TLS_PASSTHROUGH_PORT");
+    }
+
+    #[test]
+    fn test_valid_values() {
+        let values = Values::default()
+            .with("DNS_TIMEOUT", "30")
+            .with("PROXY_PROTOCOL", "true");
+
+        assert_eq!(values.seconds("DNS_TIMEOUT"), Ok(Some(Duration::from_secs(30))));
+        assert_eq!(values.flag("PROXY_PROTOCOL", false), Ok(true));
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args(&["--config", "relay.toml", "--relay-mode", "proxy", "--port=80", "--print-config"]).unwrap();
+        assert_eq!(parsed.config_file, Some(PathBuf::from("relay.toml")));
+        assert!(parsed.print_config);
+        assert_eq!(parsed.settings, vec![("RELAY_MODE", "proxy".to_string()), ("PORT", "80".to_string())]);
+
+        assert!(args(&["--no-such-setting", "1"]).is_err());
+        assert!(args(&["--port"]).is_err());
+        assert!(args(&["proxy"]).is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        let path = Path::new("relay.toml");
+        let mut values = Values::default();
+        values
+            .merge_file(path, "port = 80\nrelay_mode = \"proxy\"\ntrusted_proxies = [\"10.0.0.0/8\", \"::1\"]\n")
+            .unwrap();
+        values.merge_env(|name| match name {
+            "RELAY_MODE" => Some("https-redirect".to_string()),
+            "TXT_RELAY_MODE" => Some("".to_string()),
+            _ => None,
+        });
+        let parsed = args(&["--port", "8080"]).unwrap();
+        for (name, value) in &parsed.settings {
+            values.set(name, value, Source::Cli);
+        }
+
+        assert_eq!(values.var("PORT").as_deref(), Some("8080"));
+        assert_eq!(values.source("PORT"), Some(&Source::Cli));
+        assert_eq!(values.var("RELAY_MODE").as_deref(), Some("https-redirect"));
+        assert_eq!(values.source("RELAY_MODE"), Some(&Source::Env));
+        assert_eq!(values.var("TRUSTED_PROXIES").as_deref(), Some("10.0.0.0/8,::1"));
+        assert_eq!(values.source("TRUSTED_PROXIES"), Some(&Source::File(path.to_path_buf())));
+        assert_eq!(values.source("TXT_RELAY_MODE"), None);
+
+        let described = values.describe();
+        assert!(described.contains("PORT=8080  # command line\n"), "{}", described);
+        assert!(described.contains("RELAY_MODE=https-redirect  # environment\n"), "{}", described);
+        assert!(described.contains("TRUSTED_PROXIES=10.0.0.0/8,::1  # relay.toml\n"), "{}", described);
+        assert!(described.contains("CNAME_MAX_DEPTH=8  # default\n"), "{}", described);
+        assert!(described.contains("# FALLBACK_GATEWAY_DOMAIN is unset\n"), "{}", described);
+
+        let config = Config::from_values(&values).unwrap();
+        assert_eq!(
+            config.listeners,
+            vec![
+                ListenerConfig::new(ListenAddr::Tcp("0.0.0.0:8080".parse().unwrap()), ListenerRole::Relay),
+                ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:9090".parse().unwrap()), ListenerRole::Admin),
+            ]
+        );
+        assert_eq!(config.relay_mode, RelayMode::HttpsRedirect);
+    }
+
+    #[test]
+    fn test_listeners() {
+        let config = Config::from_values(
+            &Values::default()
+                .with("LISTEN", "0.0.0.0:80,[::]:80,unix:/run/relay.sock")
+                .with("ADMIN_LISTEN", "10.0.0.5:9090,unix:/run/relay-admin.sock")
+                .with("PORT", "8080"),
+        )
+        .unwrap();
+        let roles: Vec<(String, ListenerRole)> = config
+            .listeners
+            .iter()
+            .map(|listener| (listener.addr.to_string(), listener.role))
+            .collect();
+        assert_eq!(
+            roles,
+            vec![
+                ("0.0.0.0:80".to_string(), ListenerRole::Relay),
+                ("[::]:80".to_string(), ListenerRole::Relay),
+                ("unix:/run/relay.sock".to_string(), ListenerRole::Relay),
+                ("10.0.0.5:9090".to_string(), ListenerRole::Admin),
+                ("unix:/run/relay-admin.sock".to_string(), ListenerRole::Admin),
+            ]
+        );
+
+        let config = Config::from_values(&Values::default().with("ADMIN_LISTEN", "none")).unwrap();
+        assert!(config.listeners.iter().all(|listener| listener.role == ListenerRole::Relay));
+
+        let invalid = |name: &'static str, value: &str| {
+            Config::from_values(&Values::default().with(name, value)).unwrap_err().setting
+        };
+        assert_eq!(invalid("LISTEN", "localhost:80"), "LISTEN");
+        assert_eq!(invalid("LISTEN", ","), "LISTEN");
+        assert_eq!(invalid("ADMIN_LISTEN", "0.0.0.0:8081"), "ADMIN_LISTEN");
+        assert_eq!(invalid("TLS_PASSTHROUGH_PORT", "8081"), "TLS_PASSTHROUGH_PORT");
+        assert_eq!(invalid("TLS_PASSTHROUGH_PORT", "9090"), "TLS_PASSTHROUGH_PORT");
+        assert_eq!(invalid("TLS_PASSTHROUGH_LISTEN", "[::]:8081"), "TLS_PASSTHROUGH_LISTEN");
+        assert_eq!(invalid("TLS_PASSTHROUGH_LISTEN", "unix:/run/relay-tls.sock"), "TLS_PASSTHROUGH_LISTEN");
+    }
+
+    #[test]
+    fn test_tls_passthrough_listeners() {
+        let tls_passthrough = |values: Values| -> Vec<String> {
+            Config::from_values(&values)
+                .unwrap()
+                .listeners
+                .iter()
+                .filter(|listener| listener.role == ListenerRole::TlsPassthrough)
+                .map(|listener| listener.addr.to_string())
+                .collect()
+        };
+
+        assert!(tls_passthrough(Values::default()).is_empty());
+        assert_eq!(
+            tls_passthrough(Values::default().with("BIND_ADDRESS", "::").with("TLS_PASSTHROUGH_PORT", "443")),
+            vec!["[::]:443"]
+        );
+        // TLS_PASSTHROUGH_LISTEN replaces BIND_ADDRESS:TLS_PASSTHROUGH_PORT
+        assert_eq!(
+            tls_passthrough(
+                Values::default()
+                    .with("TLS_PASSTHROUGH_PORT", "443")
+                    .with("TLS_PASSTHROUGH_LISTEN", "0.0.0.0:443,[::]:443")
+            ),
+            vec!["0.0.0.0:443", "[::]:443"]
+        );
+    }
+
+    #[test]
+    fn test_admin_token_is_never_shown() {
+        let values = Values::default().with("ADMIN_TOKEN", "s3cret-s3cret-s3cret");
+        let described = values.describe();
+        assert!(described.contains("ADMIN_TOKEN=<redacted>  # command line\n"), "{}", described);
+        assert!(!described.contains("s3cret"), "{}", described);
+
+        let config = Config::from_values(&values).unwrap();
+        assert!(!format!("{:?}", config).contains("s3cret"));
+
+        let short = Values::default().with("ADMIN_TOKEN", "s3cret");
+        assert_eq!(Config::from_values(&short).unwrap_err().setting, "ADMIN_TOKEN");
+    }
+
+    #[test]
+    fn test_file_rejects_unknown_settings() {
+        let path = Path::new("relay.toml");
+        assert!(Values::default().merge_file(path, "prot = 80").is_err());
+        assert!(Values::default().merge_file(path, "PORT = 80").is_err());
+        assert!(Values::default().merge_file(path, "[port]\nvalue = 80").is_err());
+        assert!(Values::default().merge_file(path, "port = ").is_err());
+    }
+
+    #[test]
+    fn test_defaults_match_settings() {
+        // Setting every documented default explicitly changes nothing
+        let mut defaults = Values::default();
+        for setting in SETTINGS {
+            if let Some(default) = setting.default {
+                defaults.set(setting.name, default, Source::Cli);
+            }
+        }
+
+        let implicit = Config::from_values(&Values::default()).unwrap();
+        let explicit = Config::from_values(&defaults).unwrap();
+        assert_eq!(format!("{:?}", implicit), format!("{:?}", explicit));
+    }
+}
@@ -1,16 +1,33 @@
-use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use async_trait::async_trait;
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::proto::error::ProtoErrorKind;
 use hickory_resolver::proto::rr::RecordType;
 use hickory_resolver::TokioAsyncResolver;
-use regex::Regex;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
 use tracing::{debug, info, warn};
 
-#[derive(Debug)]
+use crate::app_address::AppAddress;
+use crate::config::{Config, ConfigError, Values};
+use crate::domain_map::DomainMapFile;
+use crate::gateway::{Gateway, GatewayMatch, GatewayRegistry};
+use crate::metrics;
+use crate::policy::RelayMode;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum DnsError {
     LookupFailed(String),
     NoRecordsFound(String),
     ParseError(String),
+    InvalidConfig(String),
+    DnssecFailed(String),
 }
 
 impl fmt::Display for DnsError {
@@ -19,190 +36,1184 @@ impl fmt::Display for DnsError {
             DnsError::LookupFailed(msg) => write!(f, "DNS lookup failed: {}", msg),
             DnsError::NoRecordsFound(msg) => write!(f, "No DNS records found: {}", msg),
             DnsError::ParseError(msg) => write!(f, "Failed to parse DNS record: {}", msg),
+            DnsError::InvalidConfig(msg) => write!(f, "Invalid DNS configuration: {}", msg),
+            DnsError::DnssecFailed(msg) => write!(f, "DNSSEC validation failed: {}", msg),
         }
     }
 }
 
 impl Error for DnsError {}
 
+impl DnsError {
+    /// Whether this failure is determined by the published records (NXDOMAIN,
+    /// no TXT, unparseable values) rather than a transient resolver problem,
+    /// and can therefore be cached
+    fn is_cacheable(&self) -> bool {
+        matches!(self, DnsError::NoRecordsFound(_) | DnsError::ParseError(_))
+    }
+
+    /// Status label for the `dns_lookups_total` metric
+    pub fn metric_status(&self) -> &'static str {
+        match self {
+            DnsError::DnssecFailed(_) => "dnssec_failure",
+            _ => "failure",
+        }
+    }
+
+    /// Map a hickory error, keeping "no such record" answers distinct from lookup failures
+    /// When `validated` is set, answers that are unsigned or fail verification become `DnssecFailed`
+    fn from_resolve_error(e: ResolveError, context: String, validated: bool) -> Self {
+        match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => {
+                DnsError::NoRecordsFound(format!("{}: {}", context, e))
+            }
+            ResolveErrorKind::Proto(proto) if validated && is_validation_failure(proto.kind()) => {
+                DnsError::DnssecFailed(format!("{}: {}", context, e))
+            }
+            _ => DnsError::LookupFailed(format!("{}: {}", context, e)),
+        }
+    }
+}
+
+/// Messages hickory's DNSSEC validation (`DnssecDnsHandle`) fails with; other
+/// `Message` errors are ordinary protocol or transport failures
+///
+/// hickory has no error kinds for these, so they are copied from hickory-proto
+/// 0.24.4 and need checking on upgrades; `test_dnssec_handle_errors_are_dnssec_failures`
+/// catches changes to those it can trigger. Rejected keys (revoked, wrong
+/// algorithm, ...) surface as "validation failed".
+const VALIDATION_FAILURES: &[&str] = &[
+    "validation failed",
+    "Could not validate all DNSKEYs",
+    "could not validate negative response missing SOA",
+    "could not validate negative response with NSEC",
+    "exceeded max validation depth",
+    "no results to verify",
+];
+
+/// Whether a protocol error means the answer failed DNSSEC validation
+fn is_validation_failure(kind: &ProtoErrorKind) -> bool {
+    match kind {
+        // Unsigned answer, or a signature that doesn't verify
+        ProtoErrorKind::RrsigsNotPresent { .. } | ProtoErrorKind::Ring(_) => true,
+        ProtoErrorKind::Message(message) => VALIDATION_FAILURES.contains(message),
+        _ => false,
+    }
+}
+
+/// Where the app address of a resolved domain came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppSource {
+    /// An entry of the local domain mapping file
+    DomainMap,
+    /// The `_dstack-app-address` TXT record
+    Dns,
+}
+
+impl AppSource {
+    /// Label value used in metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppSource::DomainMap => "domain_map",
+            AppSource::Dns => "dns",
+        }
+    }
+}
+
+/// The result of resolving a custom domain: app-id and port from the TXT record,
+/// plus the gateway base domain from the CNAME record (or the fallback)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvedApp {
+    pub app_id: String,
+    pub port: u16,
+    pub gateway_domain: String,
+    /// Registry entry the gateway domain was matched with
+    pub gateway: Arc<Gateway>,
+    pub source: AppSource,
+    /// Relay mode requested by the TXT record
+    pub mode: Option<RelayMode>,
+}
+
+/// A candidate URL for a request, with the gateway serving it
+#[derive(Clone, Debug)]
+pub struct AppTarget {
+    pub url: String,
+    pub gateway: Arc<Gateway>,
+}
+
+/// Template for the target host on the dstack gateway
+///
+/// Placeholders: `{app_id}`, `{port}`, `{gateway}` and `{port_suffix}`, which
+/// expands to `-{port}` unless the port is `default_port` (then to nothing).
+/// The default `{app_id}{port_suffix}.{gateway}` follows the dstack gateway
+/// convention: `my-app.gateway` for port 80, `my-app-8080.gateway` otherwise.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostTemplate {
+    template: String,
+    default_port: u16,
+}
+
+const HOST_TEMPLATE_PLACEHOLDERS: &[&str] = &["{app_id}", "{port}", "{gateway}", "{port_suffix}"];
+
+impl HostTemplate {
+    pub const DEFAULT: &'static str = "{app_id}{port_suffix}.{gateway}";
+
+    /// Parse a template, rejecting unknown placeholders and templates without `{app_id}`
+    pub fn new(template: &str, default_port: u16) -> Result<Self, DnsError> {
+        let invalid = |reason: String| DnsError::InvalidConfig(format!("host template '{}': {}", template, reason));
+
+        if !template.contains("{app_id}") {
+            return Err(invalid("must contain {app_id}".to_string()));
+        }
+
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid("unclosed '{'".to_string()))?;
+            let placeholder = &rest[start..start + end + 1];
+            if !HOST_TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+                return Err(invalid(format!("unknown placeholder {}", placeholder)));
+            }
+            rest = &rest[start + end + 1..];
+        }
+
+        Ok(Self {
+            template: template.to_string(),
+            default_port,
+        })
+    }
+
+    /// Build the target host for a resolved app
+    pub fn render(&self, app: &ResolvedApp) -> String {
+        let port_suffix = if app.port == self.default_port {
+            String::new()
+        } else {
+            format!("-{}", app.port)
+        };
+
+        self.template
+            .replace("{app_id}", &app.app_id)
+            .replace("{port_suffix}", &port_suffix)
+            .replace("{port}", &app.port.to_string())
+            .replace("{gateway}", &app.gateway_domain)
+    }
+}
+
+impl Default for HostTemplate {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT, 80).unwrap()
+    }
+}
+
+/// Normalize a domain for use as a cache key (lowercase, no trailing dot)
+fn cache_key(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+struct CacheEntry {
+    result: Result<Vec<ResolvedApp>, DnsError>,
+    expires_at: Instant,
+}
+
+/// TTL-aware cache of resolved apps, keyed on the normalized domain
+///
+/// Entry lifetimes follow the record TTLs, clamped to `[min_ttl, max_ttl]`.
+/// Failed lookups are kept for `negative_ttl` (0 disables negative caching).
+/// When the cache is full, expired entries are purged first, then the entry
+/// closest to expiry is evicted.
+struct DnsCache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+}
+
+impl DnsCache {
+    fn new(min_ttl: Duration, max_ttl: Duration, negative_ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            min_ttl,
+            max_ttl: max_ttl.max(min_ttl),
+            negative_ttl,
+            max_entries,
+        }
+    }
+
+    fn get(&self, domain: &str) -> Option<Result<Vec<ResolvedApp>, DnsError>> {
+        let key = cache_key(domain);
+        let mut entries = self.entries.lock().unwrap();
+
+        let result = match entries.get(&key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.result.clone()),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        };
+
+        metrics::set_dns_cache_entries(entries.len());
+        result
+    }
+
+    fn insert(&self, domain: &str, apps: Vec<ResolvedApp>, ttl: Duration) {
+        let ttl = ttl.clamp(self.min_ttl, self.max_ttl);
+        self.store(domain, Ok(apps), ttl);
+    }
+
+    fn insert_negative(&self, domain: &str, error: DnsError) {
+        if self.negative_ttl.is_zero() {
+            return;
+        }
+        self.store(domain, Err(error), self.negative_ttl);
+    }
+
+    fn store(&self, domain: &str, result: Result<Vec<ResolvedApp>, DnsError>, ttl: Duration) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        let key = cache_key(domain);
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if !entries.contains_key(&key) && entries.len() >= self.max_entries {
+            entries.retain(|_, entry| entry.expires_at > now);
+
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+
+        entries.insert(key, CacheEntry { result, expires_at: now + ttl });
+        metrics::set_dns_cache_entries(entries.len());
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+        metrics::set_dns_cache_entries(0);
+    }
+}
+
+/// Records returned by a single lookup, with how long they may be cached
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LookupAnswer {
+    pub records: Vec<String>,
+    pub ttl: Duration,
+}
+
+/// Source of the TXT and CNAME records used to discover dstack apps
+///
+/// `HickoryResolver` queries real DNS; `StaticResolver` answers from memory so the
+/// relay can be exercised without network access.
+#[async_trait]
+pub trait AppAddressResolver: Send + Sync {
+    /// Look up the TXT records for `name`
+    async fn lookup_txt(&self, name: &str) -> Result<LookupAnswer, DnsError>;
+
+    /// Look up the CNAME records for `name` (targets without the trailing dot)
+    async fn lookup_cname(&self, name: &str) -> Result<LookupAnswer, DnsError>;
+}
+
+/// Which upstream nameservers hickory queries, and how
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamSettings {
+    /// Start from the system configuration (`/etc/resolv.conf`) instead of the built-in default (Google)
+    pub use_system_config: bool,
+    /// Additional nameservers; when set without `use_system_config`, these are the only upstreams
+    pub servers: Vec<NameServerConfig>,
+    /// Per-query timeout (hickory default when `None`)
+    pub timeout: Option<Duration>,
+    /// Number of attempts per query (hickory default when `None`)
+    pub attempts: Option<usize>,
+    /// Zones whose records must be DNSSEC-signed; lookups under them are validated
+    /// and unsigned or bogus answers are rejected (empty disables validation)
+    pub dnssec_required_zones: Vec<String>,
+}
+
+impl UpstreamSettings {
+    /// Read the DNS_* settings
+    /// Fails if DNS_SERVERS contains an entry that can't be parsed, or another setting is invalid
+    pub fn from_values(values: &Values) -> Result<Self, ConfigError> {
+        // Comma-separated nameservers, e.g. "udp://10.0.0.2,https://10.0.0.5#doh.internal"
+        let servers = match values.var("DNS_SERVERS") {
+            Some(specs) => specs
+                .split(',')
+                .map(str::trim)
+                .filter(|spec| !spec.is_empty())
+                .map(parse_name_servers)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| ConfigError::from_dns("DNS_SERVERS", e))?
+                .into_iter()
+                .flatten()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let use_system_config = values.flag("DNS_USE_SYSTEM_CONFIG", false)?;
+
+        let timeout = values.seconds("DNS_TIMEOUT")?;
+        if timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(ConfigError::new("DNS_TIMEOUT", "must be at least 1 second"));
+        }
+
+        let attempts = values.parse::<usize>("DNS_ATTEMPTS")?;
+        if attempts == Some(0) {
+            return Err(ConfigError::new("DNS_ATTEMPTS", "must be at least 1"));
+        }
+
+        // Comma-separated zones that must be DNSSEC-signed, e.g. "example.com,example.org"
+        let dnssec_required_zones = values.var("DNSSEC_REQUIRED_ZONES")
+            .map(|zones| {
+                zones
+                    .split(',')
+                    .map(cache_key)
+                    .filter(|zone| !zone.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            use_system_config,
+            servers,
+            timeout,
+            attempts,
+            dnssec_required_zones,
+        })
+    }
+}
+
+/// Parse a nameserver spec of the form `[protocol://]ip[:port][#tls-name]`
+///
+/// Protocols are `udp` (default, with TCP fallback for truncated answers), `tcp`,
+/// `tls` (DNS-over-TLS, port 853) and `https` (DNS-over-HTTPS on `/dns-query`, port 443).
+/// `tls` and `https` need the server's certificate name after `#`.
+pub fn parse_name_servers(spec: &str) -> Result<Vec<NameServerConfig>, DnsError> {
+    let invalid = |reason: &str| DnsError::InvalidConfig(format!("nameserver '{}': {}", spec, reason));
+
+    let (scheme, rest) = spec.split_once("://").unwrap_or(("udp", spec));
+    let (address, tls_dns_name) = match rest.split_once('#') {
+        Some((address, name)) if !name.is_empty() => (address, Some(name.to_string())),
+        Some(_) => return Err(invalid("empty TLS name after '#'")),
+        None => (rest, None),
+    };
+
+    let (protocols, default_port): (&[Protocol], u16) = match scheme {
+        "udp" => (&[Protocol::Udp, Protocol::Tcp], 53),
+        "tcp" => (&[Protocol::Tcp], 53),
+        "tls" => (&[Protocol::Tls], 853),
+        "https" => (&[Protocol::Https], 443),
+        other => return Err(invalid(&format!("unsupported protocol '{}'", other))),
+    };
+
+    let socket_addr = address
+        .parse::<SocketAddr>()
+        .or_else(|_| {
+            address
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, default_port))
+        })
+        .map_err(|_| invalid("expected an IP address with optional port"))?;
+
+    let needs_tls_name = matches!(protocols, [Protocol::Tls] | [Protocol::Https]);
+    if needs_tls_name && tls_dns_name.is_none() {
+        return Err(invalid("TLS and HTTPS nameservers need a certificate name, e.g. 'tls://1.1.1.1#cloudflare-dns.com'"));
+    }
+
+    Ok(protocols
+        .iter()
+        .map(|protocol| {
+            let mut config = NameServerConfig::new(socket_addr, *protocol);
+            config.tls_dns_name = tls_dns_name.clone();
+            config
+        })
+        .collect())
+}
+
+/// Record lookups against real DNS through hickory
+pub struct HickoryResolver {
+    resolver: TokioAsyncResolver,
+    /// Validating resolver for names under `dnssec_required_zones`
+    validating_resolver: Option<TokioAsyncResolver>,
+    dnssec_required_zones: Vec<String>,
+}
+
+impl HickoryResolver {
+    /// Create a resolver for the given upstream nameservers
+    pub fn new(upstream: &UpstreamSettings) -> Result<Self, DnsError> {
+        let (mut config, mut opts) = if upstream.use_system_config {
+            hickory_resolver::system_conf::read_system_conf()
+                .map_err(|e| DnsError::InvalidConfig(format!("failed to read system DNS configuration: {}", e)))?
+        } else if upstream.servers.is_empty() {
+            (ResolverConfig::default(), ResolverOpts::default())
+        } else {
+            (ResolverConfig::new(), ResolverOpts::default())
+        };
+
+        for server in &upstream.servers {
+            config.add_name_server(server.clone());
+        }
+
+        if let Some(timeout) = upstream.timeout {
+            opts.timeout = timeout;
+        }
+        if let Some(attempts) = upstream.attempts {
+            opts.attempts = attempts;
+        }
+
+        if config.name_servers().is_empty() {
+            return Err(DnsError::InvalidConfig("no upstream nameservers configured".to_string()));
+        }
+
+        let servers: Vec<String> = config.name_servers().iter().map(|server| server.to_string()).collect();
+        info!(
+            "Upstream DNS servers: {} (timeout {}s, {} attempts)",
+            servers.join(", "),
+            opts.timeout.as_secs(),
+            opts.attempts
+        );
+
+        let validating_resolver = if upstream.dnssec_required_zones.is_empty() {
+            None
+        } else {
+            info!("DNSSEC validation required for zones: {}", upstream.dnssec_required_zones.join(", "));
+            let mut validating_opts = opts.clone();
+            validating_opts.validate = true;
+            Some(TokioAsyncResolver::tokio(config.clone(), validating_opts))
+        };
+
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio(config, opts),
+            validating_resolver,
+            dnssec_required_zones: upstream.dnssec_required_zones.clone(),
+        })
+    }
+
+    /// Pick the resolver for a name: the validating one if the name is in a must-be-signed zone
+    fn resolver_for(&self, name: &str) -> (&TokioAsyncResolver, bool) {
+        match self.validating_resolver {
+            Some(ref validating) if in_zones(name, &self.dnssec_required_zones) => (validating, true),
+            _ => (&self.resolver, false),
+        }
+    }
+
+    fn lookup_error(&self, e: ResolveError, record_type: &str, name: &str, validated: bool) -> DnsError {
+        let error = DnsError::from_resolve_error(e, format!("{} lookup failed for {}", record_type, name), validated);
+        if let DnsError::DnssecFailed(ref msg) = error {
+            warn!("{}", msg);
+            metrics::inc_dns_lookups(&record_type.to_ascii_lowercase(), error.metric_status());
+        }
+        error
+    }
+}
+
+/// Whether `name` equals or is a subdomain of one of `zones` (normalized, no trailing dot)
+fn in_zones(name: &str, zones: &[String]) -> bool {
+    let name = cache_key(name);
+    zones.iter().any(|zone| {
+        name == *zone
+            || name
+                .strip_suffix(zone.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+#[async_trait]
+impl AppAddressResolver for HickoryResolver {
+    async fn lookup_txt(&self, name: &str) -> Result<LookupAnswer, DnsError> {
+        let (resolver, validated) = self.resolver_for(name);
+        let response = resolver.txt_lookup(name)
+            .await
+            .map_err(|e| self.lookup_error(e, "TXT", name, validated))?;
+
+        Ok(LookupAnswer {
+            records: response.iter().map(|record| record.to_string()).collect(),
+            ttl: response.valid_until().saturating_duration_since(Instant::now()),
+        })
+    }
+
+    async fn lookup_cname(&self, name: &str) -> Result<LookupAnswer, DnsError> {
+        let (resolver, validated) = self.resolver_for(name);
+        let response = resolver.lookup(name, RecordType::CNAME)
+            .await
+            .map_err(|e| self.lookup_error(e, "CNAME", name, validated))?;
+
+        let records = response
+            .record_iter()
+            .filter_map(|record| record.data().and_then(|data| data.as_cname()))
+            .map(|cname| cname.to_string().trim_end_matches('.').to_string())
+            .collect();
+
+        Ok(LookupAnswer {
+            records,
+            ttl: response.valid_until().saturating_duration_since(Instant::now()),
+        })
+    }
+}
+
+/// In-memory record lookups, for tests and offline use
+///
+/// Names are matched case-insensitively and without the trailing dot. Names
+/// without records answer with `DnsError::NoRecordsFound`, like NXDOMAIN.
+#[derive(Default)]
+pub struct StaticResolver {
+    txt: HashMap<String, Vec<String>>,
+    cname: HashMap<String, Vec<String>>,
+    failures: HashMap<String, DnsError>,
+    ttl: Duration,
+    delay: Duration,
+    lookups: AtomicUsize,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self {
+            ttl: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    /// Add a TXT record for `name`
+    pub fn with_txt(mut self, name: &str, value: &str) -> Self {
+        self.txt.entry(cache_key(name)).or_default().push(value.to_string());
+        self
+    }
+
+    /// Add a CNAME record for `name`
+    pub fn with_cname(mut self, name: &str, target: &str) -> Self {
+        self.cname
+            .entry(cache_key(name))
+            .or_default()
+            .push(target.trim_end_matches('.').to_string());
+        self
+    }
+
+    /// Make every lookup of `name` fail with `error`
+    pub fn with_failure(mut self, name: &str, error: DnsError) -> Self {
+        self.failures.insert(cache_key(name), error);
+        self
+    }
+
+    /// Set the TTL reported with every answer
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Delay every answer, to simulate a slow upstream
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Number of lookups served so far
+    pub fn lookup_count(&self) -> usize {
+        self.lookups.load(Ordering::SeqCst)
+    }
+
+    async fn answer(
+        &self,
+        records: &HashMap<String, Vec<String>>,
+        record_type: &str,
+        name: &str,
+    ) -> Result<LookupAnswer, DnsError> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+
+        if let Some(error) = self.failures.get(&cache_key(name)) {
+            return Err(error.clone());
+        }
+
+        match records.get(&cache_key(name)) {
+            Some(records) => Ok(LookupAnswer {
+                records: records.clone(),
+                ttl: self.ttl,
+            }),
+            None => Err(DnsError::NoRecordsFound(format!("No {} records for {}", record_type, name))),
+        }
+    }
+}
+
+#[async_trait]
+impl AppAddressResolver for StaticResolver {
+    async fn lookup_txt(&self, name: &str) -> Result<LookupAnswer, DnsError> {
+        self.answer(&self.txt, "TXT", name).await
+    }
+
+    async fn lookup_cname(&self, name: &str) -> Result<LookupAnswer, DnsError> {
+        self.answer(&self.cname, "CNAME", name).await
+    }
+}
+
+/// Settings controlling gateway discovery and caching
+#[derive(Clone, Debug)]
+pub struct DnsSettings {
+    /// Gateway domain used when the CNAME is missing or not allowed
+    pub fallback_gateway_domain: Option<String>,
+    /// Gateways CNAME targets and explicit gateways must belong to
+    pub gateways: GatewayRegistry,
+    /// Maximum number of CNAME hops followed when looking for an allowed gateway
+    pub cname_max_depth: usize,
+    pub cache_min_ttl: Duration,
+    pub cache_max_ttl: Duration,
+    pub negative_cache_ttl: Duration,
+    pub cache_max_entries: usize,
+}
+
+impl Default for DnsSettings {
+    fn default() -> Self {
+        Self {
+            fallback_gateway_domain: None,
+            gateways: GatewayRegistry::default(),
+            cname_max_depth: 8,
+            cache_min_ttl: Duration::from_secs(30),
+            cache_max_ttl: Duration::from_secs(300),
+            negative_cache_ttl: Duration::from_secs(60),
+            cache_max_entries: 10_000,
+        }
+    }
+}
+
+impl DnsSettings {
+    /// Read settings, using defaults for anything unset
+    /// Fails on any invalid value, or if the gateways can't be loaded (see `GatewayRegistry::from_values`)
+    pub fn from_values(values: &Values) -> Result<Self, ConfigError> {
+        let defaults = Self::default();
+
+        let fallback_gateway_domain = values.var("FALLBACK_GATEWAY_DOMAIN");
+
+        // GATEWAYS_FILE, or a single gateway from ALLOWED_DOMAIN_REGEX and friends
+        let gateways = GatewayRegistry::from_values(values)?;
+
+        // How many CNAME hops to follow before giving up (default: 8)
+        let cname_max_depth = values.parse::<usize>("CNAME_MAX_DEPTH")?.unwrap_or(defaults.cname_max_depth);
+        if cname_max_depth == 0 {
+            return Err(ConfigError::new("CNAME_MAX_DEPTH", "must be at least 1"));
+        }
+
+        // Cache bounds: record TTLs are clamped to [DNS_CACHE_MIN_TTL, DNS_CACHE_MAX_TTL] seconds,
+        // and at most DNS_CACHE_MAX_ENTRIES domains are kept (0 disables the cache)
+        let cache_min_ttl = values.seconds("DNS_CACHE_MIN_TTL")?.unwrap_or(defaults.cache_min_ttl);
+        let cache_max_ttl = values.seconds("DNS_CACHE_MAX_TTL")?.unwrap_or(defaults.cache_max_ttl);
+        if cache_min_ttl > cache_max_ttl {
+            return Err(ConfigError::new(
+                "DNS_CACHE_MIN_TTL",
+                format!(
+                    "{}s is above DNS_CACHE_MAX_TTL ({}s)",
+                    cache_min_ttl.as_secs(),
+                    cache_max_ttl.as_secs()
+                ),
+            ));
+        }
+        let cache_max_entries = values.parse::<usize>("DNS_CACHE_MAX_ENTRIES")?.unwrap_or(defaults.cache_max_entries);
+
+        // How long failed lookups (NXDOMAIN, no TXT, parse errors) are cached, in seconds (0 disables)
+        let negative_cache_ttl = values.seconds("DNS_NEGATIVE_CACHE_TTL")?.unwrap_or(defaults.negative_cache_ttl);
+
+        Ok(Self {
+            fallback_gateway_domain,
+            gateways,
+            cname_max_depth,
+            cache_min_ttl,
+            cache_max_ttl,
+            negative_cache_ttl,
+            cache_max_entries,
+        })
+    }
+}
+
+/// Shared slot for a lookup in flight, so concurrent requests wait on one query
+type InflightLookup = Arc<OnceCell<Result<Vec<ResolvedApp>, DnsError>>>;
+
+/// Takes a lookup's slot out of the in-flight map once it is no longer needed:
+/// when the result is in (and cached), or when the last waiter gives up, so
+/// cancelled lookups don't leave empty slots behind
+struct InflightGuard<'a> {
+    inflight: &'a Mutex<HashMap<String, InflightLookup>>,
+    key: String,
+    slot: InflightLookup,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        let mut inflight = self.inflight.lock().unwrap();
+        // Waiters clone the slot under this lock, so the map and this guard
+        // holding the only references means nobody else is waiting
+        let unused = self.slot.initialized() || Arc::strong_count(&self.slot) == 2;
+        if unused && inflight.get(&self.key).is_some_and(|current| Arc::ptr_eq(current, &self.slot)) {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
 /// DNS resolver for looking up dstack app configuration
 pub struct DnsResolver {
-    resolver: TokioAsyncResolver,
+    resolver: Arc<dyn AppAddressResolver>,
+    cache: DnsCache,
+    inflight: Mutex<HashMap<String, InflightLookup>>,
     fallback_gateway_domain: Option<String>,
-    allowed_domain_regex: Option<Regex>,
-    gateway_domain_capture_group: usize,
+    gateways: GatewayRegistry,
+    cname_max_depth: usize,
+    domain_map: Option<Arc<DomainMapFile>>,
 }
 
 impl DnsResolver {
-    /// Create a new DNS resolver with default configuration
-    pub fn new() -> Result<Self, DnsError> {
-        let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default());
-
-        // Read environment variables
-        let fallback_gateway_domain = std::env::var("FALLBACK_GATEWAY_DOMAIN").ok();
-
-        // ALLOWED_DOMAIN_REGEX should include a capture group to extract the gateway domain
-        // Default: ^_\.(.+\.phala\.network)$ - matches "_.prod5.phala.network" and captures "prod5.phala.network"
-        let allowed_domain_regex = std::env::var("ALLOWED_DOMAIN_REGEX")
-            .ok()
-            .or_else(|| Some(r"^_\.(.+\.phala\.network)$".to_string()))
-            .and_then(|pattern| {
-                Regex::new(&pattern).ok()
-            });
-
-        // Which capture group to use for extracting the gateway domain (default: 1)
-        let gateway_domain_capture_group = std::env::var("GATEWAY_DOMAIN_CAPTURE_GROUP")
-            .ok()
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(1);
+    /// Create a new DNS resolver querying real DNS
+    /// Fails if the upstream nameservers or the domain mapping file can't be used
+    pub fn new(config: &Config) -> Result<Self, DnsError> {
+        let upstream = HickoryResolver::new(&config.upstream)?;
+        let resolver = Self::with_resolver(Arc::new(upstream), config.dns.clone());
+        Ok(match config.domain_map_file {
+            Some(ref path) => {
+                let domain_map = DomainMapFile::load(path, config.domain_map_reload_interval)?;
+                resolver.with_domain_map(Arc::new(domain_map))
+            }
+            None => resolver,
+        })
+    }
 
-        if let Some(ref domain) = fallback_gateway_domain {
+    /// Create a DNS resolver on top of any record source
+    pub fn with_resolver(resolver: Arc<dyn AppAddressResolver>, settings: DnsSettings) -> Self {
+        if let Some(ref domain) = settings.fallback_gateway_domain {
             info!("Using fallback gateway domain: {}", domain);
         }
 
-        if let Some(ref regex) = allowed_domain_regex {
-            info!("Using allowed domain regex: {} (capture group {} will be used as gateway domain)", regex.as_str(), gateway_domain_capture_group);
+        for gateway in settings.gateways.iter() {
+            info!("Using gateway: {:?}", gateway);
         }
 
-        Ok(Self {
+        info!(
+            "DNS cache: ttl {}s-{}s, negative ttl {}s, max {} entries",
+            settings.cache_min_ttl.as_secs(),
+            settings.cache_max_ttl.as_secs(),
+            settings.negative_cache_ttl.as_secs(),
+            settings.cache_max_entries
+        );
+
+        Self {
             resolver,
-            fallback_gateway_domain,
-            allowed_domain_regex,
-            gateway_domain_capture_group,
-        })
+            cache: DnsCache::new(
+                settings.cache_min_ttl,
+                settings.cache_max_ttl,
+                settings.negative_cache_ttl,
+                settings.cache_max_entries,
+            ),
+            inflight: Mutex::new(HashMap::new()),
+            fallback_gateway_domain: settings.fallback_gateway_domain,
+            gateways: settings.gateways,
+            cname_max_depth: settings.cname_max_depth,
+            domain_map: None,
+        }
+    }
+
+    /// Consult a domain mapping file before the `_dstack-app-address` TXT record
+    pub fn with_domain_map(mut self, domain_map: Arc<DomainMapFile>) -> Self {
+        info!(
+            "Using domain mapping file: {} (reload check every {}s)",
+            domain_map.path().display(),
+            domain_map.reload_interval().as_secs()
+        );
+        self.domain_map = Some(domain_map);
+        self
+    }
+
+    /// The gateways the relay sends traffic to
+    pub fn gateways(&self) -> &GatewayRegistry {
+        &self.gateways
+    }
+
+    /// The domain mapping file, if one is configured
+    pub fn domain_map(&self) -> Option<&DomainMapFile> {
+        self.domain_map.as_deref()
     }
 
-    /// Look up the TXT record for _dstack-app-address.{domain}
-    /// Returns the app-id and port in format "app-id:port"
-    pub async fn lookup_app_address(&self, domain: &str) -> Result<(String, String), DnsError> {
+    /// Drop every cached answer, so domains are looked up again
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// Start reloading the domain mapping file when it changes on disk
+    /// Cached answers are dropped on every reload so new mappings apply immediately
+    pub fn watch_domain_map(self: &Arc<Self>) {
+        let Some(domain_map) = self.domain_map.clone() else {
+            return;
+        };
+
+        let resolver = Arc::downgrade(self);
+        domain_map.watch(move || {
+            if let Some(resolver) = resolver.upgrade() {
+                resolver.cache.clear();
+            }
+        });
+    }
+
+    /// Look up the TXT records for _dstack-app-address.{domain}
+    /// Returns the candidate addresses ordered by priority (see `AppAddress` for the
+    /// accepted formats), plus the remaining TTL of the TXT answer
+    pub async fn lookup_app_addresses(&self, domain: &str) -> Result<(Vec<AppAddress>, Duration), DnsError> {
         let txt_domain = format!("_dstack-app-address.{}", domain);
 
         info!("Looking up TXT record for: {}", txt_domain);
 
-        let response = self.resolver.txt_lookup(&txt_domain)
-            .await
-            .map_err(|e| DnsError::LookupFailed(format!("TXT lookup failed for {}: {}", txt_domain, e)))?;
-
-        // Get the first TXT record
-        let record = response.iter().next()
-            .ok_or_else(|| DnsError::NoRecordsFound(format!("No TXT records for {}", txt_domain)))?;
-
-        // Parse the TXT record - it should be in format "app-id:port"
-        let txt_value = record.to_string();
-        debug!("Found TXT record: {}", txt_value);
-
-        let parts: Vec<&str> = txt_value.split(':').collect();
-        if parts.len() != 2 {
-            return Err(DnsError::ParseError(format!(
-                "Expected 'app-id:port' format, got: {}",
-                txt_value
-            )));
-        }
-
-        Ok((parts[0].to_string(), parts[1].to_string()))
-    }
-
-    /// Look up the CNAME record for {domain}
-    /// Returns the gateway base domain (e.g., "_.prod5.phala.network" or "prod5.phala.network")
-    /// Falls back to FALLBACK_GATEWAY_DOMAIN if CNAME doesn't match ALLOWED_DOMAIN_REGEX
-    pub async fn lookup_gateway_domain(&self, domain: &str) -> Result<String, DnsError> {
-        info!("Looking up CNAME record for: {}", domain);
-
-        let cname_result = self.resolver.lookup(domain, RecordType::CNAME).await;
-
-        let gateway_domain = match cname_result {
-            Ok(response) => {
-                // Get the first CNAME record
-                let record = response.record_iter().next()
-                    .ok_or_else(|| DnsError::NoRecordsFound(format!("No CNAME records for {}", domain)))?;
-
-                let cname_value = record.data()
-                    .ok_or_else(|| DnsError::NoRecordsFound(format!("CNAME record has no data for {}", domain)))?
-                    .as_cname()
-                    .ok_or_else(|| DnsError::ParseError(format!("Expected CNAME record for {}", domain)))?
-                    .to_string();
-
-                debug!("Found CNAME record: {}", cname_value);
-
-                // Extract gateway domain (remove trailing dot if present)
-                let gateway = cname_value.trim_end_matches('.').to_string();
-
-                // Check if CNAME matches the allowed domain regex and extract gateway domain
-                if let Some(ref regex) = self.allowed_domain_regex {
-                    if let Some(captures) = regex.captures(&gateway) {
-                        // Try to get the specified capture group (the gateway domain)
-                        if let Some(captured_gateway) = captures.get(self.gateway_domain_capture_group) {
-                            let extracted_domain = captured_gateway.as_str().to_string();
-                            info!("CNAME '{}' matches allowed domain regex, extracted gateway from group {}: {}",
-                                  gateway, self.gateway_domain_capture_group, extracted_domain);
-                            extracted_domain
-                        } else {
-                            // Capture group doesn't exist, use the whole match
-                            warn!("CNAME '{}' matches regex but capture group {} not found, using whole match",
-                                  gateway, self.gateway_domain_capture_group);
-                            gateway
-                        }
-                    } else {
-                        warn!("CNAME '{}' does not match allowed domain regex", gateway);
-                        // Fall back to fallback domain
-                        if let Some(ref fallback) = self.fallback_gateway_domain {
-                            warn!("Using fallback gateway domain: {}", fallback);
-                            fallback.clone()
-                        } else {
-                            return Err(DnsError::ParseError(format!(
-                                "CNAME '{}' does not match allowed domain regex and no fallback configured",
-                                gateway
-                            )));
-                        }
-                    }
-                } else {
-                    // No regex check, use CNAME as-is (strip "_." prefix if present)
-                    if gateway.starts_with("_.") {
-                        gateway[2..].to_string()
+        let response = self.resolver.lookup_txt(&txt_domain).await?;
+        if response.records.is_empty() {
+            return Err(DnsError::NoRecordsFound(format!("No TXT records for {}", txt_domain)));
+        }
+
+        debug!("Found TXT records: {:?}", response.records);
+
+        let addresses = AppAddress::parse_all(&response.records)?;
+        Ok((addresses, response.ttl))
+    }
+
+    /// Discover the gateway for {domain}
+    /// Returns the registry gateway with the base domain (e.g., "prod5.phala.network") and
+    /// the shortest TTL of the answers used (`None` if every lookup failed). Sources, in order:
+    /// 1. the CNAME chain of {domain}, followed up to `cname_max_depth` hops, where the
+    ///    first hop belonging to a registry gateway wins
+    /// 2. the `_dstack-gateway.{domain}` TXT record, for apexes whose CNAME is flattened
+    /// 3. FALLBACK_GATEWAY_DOMAIN, on the gateway it belongs to (the first one otherwise)
+    pub async fn lookup_gateway_domain(&self, domain: &str) -> Result<(GatewayMatch, Option<Duration>), DnsError> {
+        let mut ttl: Option<Duration> = None;
+        let mut observe_ttl = |answer_ttl: Duration| {
+            ttl = Some(ttl.map_or(answer_ttl, |ttl| ttl.min(answer_ttl)));
+        };
+
+        let cname_error = match self.follow_cname_chain(domain, &mut observe_ttl).await {
+            Ok(gateway_domain) => return Ok((gateway_domain, ttl)),
+            Err(e) => e,
+        };
+
+        let txt_error = match self.lookup_gateway_txt(domain, &mut observe_ttl).await {
+            Ok(gateway_domain) => return Ok((gateway_domain, ttl)),
+            Err(e) => {
+                debug!("No usable gateway TXT record for {}: {}", domain, e);
+                e
+            }
+        };
+
+        // Fall back to fallback domain
+        if let Some(ref fallback) = self.fallback_gateway_domain {
+            warn!("Using fallback gateway domain: {}", fallback);
+            let gateway = self.gateways.match_explicit(fallback).unwrap_or_else(|| GatewayMatch {
+                gateway: self.gateways.first().clone(),
+                base_domain: fallback.clone(),
+            });
+            return Ok((gateway, ttl));
+        }
+
+        // Report why the CNAME was unusable, unless there was no CNAME and the TXT record was the
+        // problem, or the TXT lookup failed transiently and the answer mustn't be cached
+        match (&cname_error, &txt_error) {
+            (DnsError::NoRecordsFound(_), DnsError::ParseError(_)) => Err(txt_error),
+            _ if !txt_error.is_cacheable() => Err(txt_error),
+            _ => Err(cname_error),
+        }
+    }
+
+    /// Walk the CNAME chain of {domain}, returning the gateway of the first allowed hop
+    async fn follow_cname_chain(
+        &self,
+        domain: &str,
+        observe_ttl: &mut impl FnMut(Duration),
+    ) -> Result<GatewayMatch, DnsError> {
+        let mut name = domain.to_string();
+        let mut visited = vec![cache_key(domain)];
+
+        for depth in 0..self.cname_max_depth {
+            info!("Looking up CNAME record for: {}", name);
+
+            let response = match self.resolver.lookup_cname(&name).await {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("CNAME lookup failed for {}: {}", name, e);
+                    // Only a hop without a CNAME ends the chain; a transient failure must not be cached
+                    return Err(if depth == 0 || !e.is_cacheable() {
+                        e
                     } else {
-                        gateway
-                    }
+                        DnsError::ParseError(format!(
+                            "CNAME chain of {} ends at '{}' without matching an allowed gateway",
+                            domain, name
+                        ))
+                    });
                 }
+            };
+            observe_ttl(response.ttl);
+
+            // Get the first CNAME record (already without the trailing dot)
+            let target = response.records.into_iter().next()
+                .ok_or_else(|| DnsError::NoRecordsFound(format!("No CNAME records for {}", name)))?;
+
+            debug!("Found CNAME record: {} -> {}", name, target);
+
+            // Check if CNAME belongs to a registry gateway and extract the gateway domain
+            if let Some(gateway) = self.gateways.match_name(&target) {
+                info!(
+                    "'{}' matches gateway {}, gateway domain: {}",
+                    target,
+                    gateway.gateway.name(),
+                    gateway.base_domain
+                );
+                return Ok(gateway);
             }
-            Err(e) => {
-                warn!("CNAME lookup failed for {}: {}", domain, e);
-                // Fall back to fallback domain
-                if let Some(ref fallback) = self.fallback_gateway_domain {
-                    warn!("Using fallback gateway domain: {}", fallback);
-                    fallback.clone()
-                } else {
-                    return Err(DnsError::LookupFailed(format!("CNAME lookup failed for {}: {}", domain, e)));
+            warn!("CNAME '{}' does not match any allowed gateway", target);
+
+            if visited.contains(&cache_key(&target)) {
+                return Err(DnsError::ParseError(format!("CNAME loop at '{}' for {}", target, domain)));
+            }
+            visited.push(cache_key(&target));
+            name = target;
+        }
+
+        Err(DnsError::ParseError(format!(
+            "CNAME chain of {} does not match an allowed gateway within {} hops",
+            domain, self.cname_max_depth
+        )))
+    }
+
+    /// Look up the gateway from the `_dstack-gateway.{domain}` TXT record
+    async fn lookup_gateway_txt(
+        &self,
+        domain: &str,
+        observe_ttl: &mut impl FnMut(Duration),
+    ) -> Result<GatewayMatch, DnsError> {
+        let txt_domain = format!("_dstack-gateway.{}", domain);
+
+        info!("Looking up TXT record for: {}", txt_domain);
+
+        let response = self.resolver.lookup_txt(&txt_domain).await?;
+        observe_ttl(response.ttl);
+
+        let gateway = response.records.first()
+            .ok_or_else(|| DnsError::NoRecordsFound(format!("No TXT records for {}", txt_domain)))?
+            .trim()
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+
+        debug!("Found gateway TXT record: {}", gateway);
+
+        self.match_explicit_gateway(&gateway)
+    }
+
+    /// Check an explicit gateway from a TXT record against the gateway registry
+    /// The gateway is tried both as written and in CNAME form (`_.{gateway}`)
+    fn match_explicit_gateway(&self, gateway: &str) -> Result<GatewayMatch, DnsError> {
+        self.gateways.match_explicit(gateway).ok_or_else(|| {
+            DnsError::ParseError(format!(
+                "Gateway '{}' from TXT record does not match an allowed gateway",
+                gateway
+            ))
+        })
+    }
+
+    /// Resolve the candidate apps (app-id, port and gateway domain) for a custom domain,
+    /// ordered by priority; never empty on success
+    /// Answers from the cache when possible; concurrent misses for the same domain
+    /// share a single lookup of the TXT and CNAME records
+    pub async fn resolve_apps(&self, custom_domain: &str) -> Result<Vec<ResolvedApp>, DnsError> {
+        if let Some(result) = self.cache_lookup(custom_domain) {
+            return result;
+        }
+
+        let key = cache_key(custom_domain);
+        let slot = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = InflightGuard {
+            inflight: &self.inflight,
+            key,
+            slot,
+        };
+
+        let mut leader = false;
+        let result = guard
+            .slot
+            .get_or_init(|| {
+                leader = true;
+                self.resolve_apps_uncached(custom_domain)
+            })
+            .await
+            .clone();
+
+        if !leader {
+            debug!("Joined in-flight DNS lookup for {}", custom_domain);
+            metrics::inc_dns_cache("coalesced");
+        }
+
+        result
+    }
+
+    /// Check the cache for a domain, recording the outcome in the cache metrics
+    fn cache_lookup(&self, domain: &str) -> Option<Result<Vec<ResolvedApp>, DnsError>> {
+        match self.cache.get(domain) {
+            Some(Ok(apps)) => {
+                debug!("DNS cache hit for {}", domain);
+                metrics::inc_dns_cache("hit");
+                Some(Ok(apps))
+            }
+            Some(Err(e)) => {
+                debug!("DNS negative cache hit for {}: {}", domain, e);
+                metrics::inc_dns_cache("negative_hit");
+                Some(Err(e))
+            }
+            None => None,
+        }
+    }
+
+    /// Look up both TXT and CNAME records and cache the outcome
+    async fn resolve_apps_uncached(&self, custom_domain: &str) -> Result<Vec<ResolvedApp>, DnsError> {
+        metrics::inc_dns_cache("miss");
+
+        let result = self.fetch_apps(custom_domain).await;
+        match &result {
+            Ok((apps, ttl)) => self.cache.insert(custom_domain, apps.clone(), *ttl),
+            Err(e) if e.is_cacheable() => self.cache.insert_negative(custom_domain, e.clone()),
+            Err(_) => {}
+        }
+
+        result.map(|(apps, _)| apps)
+    }
+
+    /// Look up the TXT records, and the CNAME record if any candidate needs it,
+    /// returning the candidate apps and how long they may be cached
+    ///
+    /// Candidates that can't be completed (CNAME failed, explicit gateway not
+    /// allowed) are dropped; the lookup fails only if none remain.
+    async fn fetch_apps(&self, custom_domain: &str) -> Result<(Vec<ResolvedApp>, Duration), DnsError> {
+        let mapped = self.domain_map.as_ref().and_then(|domain_map| {
+            domain_map.current().lookup(custom_domain).map(<[AppAddress]>::to_vec)
+        });
+        let (addresses, txt_ttl, source) = match mapped {
+            Some(addresses) => {
+                debug!("Domain mapping found for {}", custom_domain);
+                // Mappings have no TTL: they are cached for the maximum TTL, and a reload clears the cache
+                (addresses, Duration::MAX, AppSource::DomainMap)
+            }
+            None => {
+                let (addresses, ttl) = self.lookup_app_addresses(custom_domain).await?;
+                (addresses, ttl, AppSource::Dns)
+            }
+        };
+
+        let cname_gateway = if addresses.iter().any(|address| address.gateway.is_none()) {
+            Some(self.lookup_gateway_domain(custom_domain).await)
+        } else {
+            None
+        };
+
+        let mut apps = Vec::with_capacity(addresses.len());
+        let mut last_error = None;
+        for address in addresses {
+            let gateway = match (&address.gateway, &cname_gateway) {
+                (Some(gateway), _) => self.match_explicit_gateway(gateway),
+                (None, Some(Ok((gateway, _)))) => Ok(gateway.clone()),
+                (None, Some(Err(e))) => Err(e.clone()),
+                (None, None) => unreachable!("CNAME is looked up when a candidate has no gateway"),
+            };
+
+            match gateway {
+                Ok(GatewayMatch { gateway, base_domain }) => apps.push(ResolvedApp {
+                    app_id: address.app_id,
+                    port: address.port,
+                    gateway_domain: base_domain,
+                    gateway,
+                    source,
+                    mode: address.mode,
+                }),
+                Err(e) => {
+                    warn!("Skipping candidate {}:{} for {}: {}", address.app_id, address.port, custom_domain, e);
+                    last_error = Some(e);
                 }
             }
+        }
+
+        if apps.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                DnsError::NoRecordsFound(format!("No usable app address for {}", custom_domain))
+            }));
+        }
+
+        // The entry is only as fresh as the shorter-lived of the two answers
+        let cname_ttl = match cname_gateway {
+            Some(Ok((_, cname_ttl))) => cname_ttl,
+            _ => None,
         };
+        let ttl = cname_ttl.map_or(txt_ttl, |cname_ttl| txt_ttl.min(cname_ttl));
 
-        Ok(gateway_domain)
+        Ok((apps, ttl))
     }
 
     /// Resolve the complete app URL for a given custom domain
-    /// Returns the full https:// URL to redirect to
+    /// Returns the full https:// URL of the highest-priority candidate
     pub async fn resolve_app_url(&self, custom_domain: &str, path: &str) -> Result<String, DnsError> {
+        let mut urls = self.resolve_app_urls(custom_domain, path).await?;
+        Ok(urls.swap_remove(0))
+    }
+
+    /// Resolve the app URLs of all candidates for a given custom domain, in failover order
+    pub async fn resolve_app_urls(&self, custom_domain: &str, path: &str) -> Result<Vec<String>, DnsError> {
+        let targets = self.resolve_app_targets(custom_domain, path).await?;
+        Ok(targets.into_iter().map(|target| target.url).collect())
+    }
+
+    /// Resolve the app URLs of all candidates with their gateways, in failover order
+    pub async fn resolve_app_targets(&self, custom_domain: &str, path: &str) -> Result<Vec<AppTarget>, DnsError> {
         info!("Resolving app URL for domain: {} with path: {}", custom_domain, path);
 
-        // Look up both TXT and CNAME records
-        let (app_id, _port) = self.lookup_app_address(custom_domain).await?;
-        let gateway_domain = self.lookup_gateway_domain(custom_domain).await?;
+        // Construct the full URLs: https://{target-host}{path}, e.g. https://{app-id}-{port}.{gateway-domain}{path}
+        let targets: Vec<AppTarget> = self
+            .resolve_failover_order(custom_domain)
+            .await?
+            .into_iter()
+            .map(|app| AppTarget {
+                url: format!("https://{}{}", app.gateway.render(&app), path),
+                gateway: app.gateway,
+            })
+            .collect();
 
-        // Construct the full URL: https://{app-id}.{gateway-domain}{path}
-        let app_url = format!("https://{}.{}{}", app_id, gateway_domain, path);
+        info!(
+            "Resolved app URL: {}",
+            targets.iter().map(|target| target.url.as_str()).collect::<Vec<_>>().join(", ")
+        );
+        Ok(targets)
+    }
 
-        info!("Resolved app URL: {}", app_url);
-        Ok(app_url)
+    /// Resolve the target hosts on the gateway (`host[:port]`) of all candidates, in failover order
+    pub async fn resolve_app_hosts(&self, custom_domain: &str) -> Result<Vec<String>, DnsError> {
+        let apps = self.resolve_failover_order(custom_domain).await?;
+        Ok(apps.iter().map(|app| app.gateway.render(app)).collect())
+    }
+
+    /// The candidate apps by priority, with those on unhealthy gateways moved to the end
+    async fn resolve_failover_order(&self, custom_domain: &str) -> Result<Vec<ResolvedApp>, DnsError> {
+        let mut apps = self.resolve_apps(custom_domain).await?;
+        metrics::inc_app_resolutions(apps[0].source.as_str());
+
+        apps.sort_by_key(|app| !app.gateway.is_healthy());
+        Ok(apps)
     }
 
     /// Check if a domain is a dstack custom domain by verifying DNS records exist
-    /// Returns true if both TXT and CNAME records are found
+    /// Returns true if the domain resolves to a dstack app; goes through the same
+    /// cached and coalesced path as `resolve_apps`, so unknown hosts cost at most one lookup
     pub async fn is_dstack_custom_domain(&self, domain: &str) -> bool {
-        // Try to resolve the app address (TXT record)
-        let has_txt = self.lookup_app_address(domain).await.is_ok();
-
-        // We only need to check TXT record - if it exists, it's a dstack custom domain
-        has_txt
+        self.resolve_apps(domain).await.is_ok()
     }
 }
 
@@ -212,7 +1223,388 @@ mod tests {
 
     #[test]
     fn test_dns_resolver_creation() {
-        let resolver = DnsResolver::new();
+        let resolver = DnsResolver::new(&Config::from_values(&Values::default()).unwrap());
         assert!(resolver.is_ok());
     }
+
+    fn app(app_id: &str) -> ResolvedApp {
+        ResolvedApp {
+            app_id: app_id.to_string(),
+            port: 80,
+            gateway_domain: "prod5.phala.network".to_string(),
+            gateway: Arc::new(Gateway::default()),
+            source: AppSource::Dns,
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn test_host_template_default() {
+        let template = HostTemplate::default();
+        assert_eq!(template.render(&app("my-app")), "my-app.prod5.phala.network");
+
+        let app = ResolvedApp { port: 8080, ..app("my-app") };
+        assert_eq!(template.render(&app), "my-app-8080.prod5.phala.network");
+    }
+
+    #[test]
+    fn test_host_template_custom() {
+        let template = HostTemplate::new("{app_id}-{port}s.{gateway}", 443).unwrap();
+        assert_eq!(template.render(&app("my-app")), "my-app-80s.prod5.phala.network");
+
+        let template = HostTemplate::new("{app_id}{port_suffix}.{gateway}", 443).unwrap();
+        let app = ResolvedApp { port: 443, ..app("my-app") };
+        assert_eq!(template.render(&app), "my-app.prod5.phala.network");
+    }
+
+    #[test]
+    fn test_host_template_rejects_invalid() {
+        assert!(HostTemplate::new("{gateway}", 80).is_err());
+        assert!(HostTemplate::new("{app_id}.{gw}", 80).is_err());
+        assert!(HostTemplate::new("{app_id}.{gateway", 80).is_err());
+    }
+
+    #[test]
+    fn test_cache_normalizes_domain() {
+        let cache = DnsCache::new(Duration::from_secs(30), Duration::from_secs(300), Duration::from_secs(60), 10);
+        cache.insert("Example.COM.", vec![app("my-app")], Duration::from_secs(60));
+
+        assert_eq!(cache.get("example.com"), Some(Ok(vec![app("my-app")])));
+        assert_eq!(cache.get("EXAMPLE.com."), Some(Ok(vec![app("my-app")])));
+        assert_eq!(cache.get("other.com"), None);
+    }
+
+    #[test]
+    fn test_cache_clamps_ttl() {
+        let cache = DnsCache::new(Duration::from_secs(30), Duration::from_secs(300), Duration::from_secs(60), 10);
+        let before = Instant::now();
+        cache.insert("short.com", vec![app("a")], Duration::from_secs(1));
+        cache.insert("long.com", vec![app("b")], Duration::from_secs(86400));
+
+        let entries = cache.entries.lock().unwrap();
+        let short = entries["short.com"].expires_at - before;
+        let long = entries["long.com"].expires_at - before;
+        assert!(short >= Duration::from_secs(30) && short < Duration::from_secs(31));
+        assert!(long >= Duration::from_secs(300) && long < Duration::from_secs(301));
+    }
+
+    #[test]
+    fn test_cache_expired_entry_is_a_miss() {
+        let cache = DnsCache::new(Duration::ZERO, Duration::ZERO, Duration::ZERO, 10);
+        cache.insert("example.com", vec![app("my-app")], Duration::from_secs(60));
+
+        assert_eq!(cache.get("example.com"), None);
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_when_full() {
+        let cache = DnsCache::new(Duration::ZERO, Duration::from_secs(300), Duration::ZERO, 2);
+        cache.insert("a.com", vec![app("a")], Duration::from_secs(10));
+        cache.insert("b.com", vec![app("b")], Duration::from_secs(200));
+        cache.insert("c.com", vec![app("c")], Duration::from_secs(100));
+
+        // a.com expires first, so it is the one evicted
+        assert_eq!(cache.get("a.com"), None);
+        assert_eq!(cache.get("b.com"), Some(Ok(vec![app("b")])));
+        assert_eq!(cache.get("c.com"), Some(Ok(vec![app("c")])));
+    }
+
+    #[test]
+    fn test_cache_disabled_with_zero_entries() {
+        let cache = DnsCache::new(Duration::from_secs(30), Duration::from_secs(300), Duration::from_secs(60), 0);
+        cache.insert("example.com", vec![app("my-app")], Duration::from_secs(60));
+
+        assert_eq!(cache.get("example.com"), None);
+    }
+
+    #[test]
+    fn test_cache_negative_entries() {
+        let cache = DnsCache::new(Duration::from_secs(30), Duration::from_secs(300), Duration::from_secs(60), 10);
+        cache.insert_negative("unknown.com", DnsError::NoRecordsFound("no TXT".to_string()));
+
+        assert!(matches!(cache.get("unknown.com"), Some(Err(DnsError::NoRecordsFound(_)))));
+    }
+
+    #[test]
+    fn test_cache_negative_disabled_with_zero_ttl() {
+        let cache = DnsCache::new(Duration::from_secs(30), Duration::from_secs(300), Duration::ZERO, 10);
+        cache.insert_negative("unknown.com", DnsError::NoRecordsFound("no TXT".to_string()));
+
+        assert!(cache.get("unknown.com").is_none());
+    }
+
+    fn resolver_with(records: StaticResolver) -> (Arc<StaticResolver>, DnsResolver) {
+        let records = Arc::new(records);
+        let resolver = DnsResolver::with_resolver(records.clone(), DnsSettings::default());
+        (records, resolver)
+    }
+
+    #[test]
+    fn test_parse_name_servers() {
+        let servers = parse_name_servers("10.0.0.2").unwrap();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].protocol, Protocol::Udp);
+        assert_eq!(servers[1].protocol, Protocol::Tcp);
+        assert_eq!(servers[0].socket_addr, "10.0.0.2:53".parse().unwrap());
+
+        let servers = parse_name_servers("tcp://[2001:db8::1]:5353").unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].socket_addr, "[2001:db8::1]:5353".parse().unwrap());
+
+        let servers = parse_name_servers("tls://1.1.1.1#cloudflare-dns.com").unwrap();
+        assert_eq!(servers[0].protocol, Protocol::Tls);
+        assert_eq!(servers[0].socket_addr, "1.1.1.1:853".parse().unwrap());
+        assert_eq!(servers[0].tls_dns_name.as_deref(), Some("cloudflare-dns.com"));
+
+        let servers = parse_name_servers("https://10.0.0.5:8443#doh.internal").unwrap();
+        assert_eq!(servers[0].protocol, Protocol::Https);
+        assert_eq!(servers[0].socket_addr, "10.0.0.5:8443".parse().unwrap());
+    }
+
+    #[test]
+    fn test_parse_name_servers_rejects_invalid_specs() {
+        assert!(parse_name_servers("quic://1.1.1.1#dns").is_err());
+        assert!(parse_name_servers("dns.google").is_err());
+        assert!(parse_name_servers("tls://1.1.1.1").is_err());
+        assert!(parse_name_servers("https://1.1.1.1#").is_err());
+    }
+
+    #[test]
+    fn test_in_zones() {
+        let zones = vec!["example.com".to_string()];
+        assert!(in_zones("example.com", &zones));
+        assert!(in_zones("_dstack-app-address.App.Example.com.", &zones));
+        assert!(!in_zones("badexample.com", &zones));
+        assert!(!in_zones("example.org", &zones));
+    }
+
+    #[tokio::test]
+    async fn test_dnssec_failures_are_not_cached() {
+        let (records, resolver) = resolver_with(StaticResolver::new().with_failure(
+            "_dstack-app-address.example.com",
+            DnsError::DnssecFailed("no RRSIGs".to_string()),
+        ));
+
+        for _ in 0..2 {
+            let error = resolver.resolve_apps("example.com").await.unwrap_err();
+            assert_eq!(error.metric_status(), "dnssec_failure");
+        }
+        assert_eq!(records.lookup_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_hickory_resolver_with_custom_servers() {
+        let upstream = UpstreamSettings {
+            servers: parse_name_servers("https://10.0.0.5#doh.internal").unwrap(),
+            timeout: Some(Duration::from_secs(2)),
+            attempts: Some(1),
+            dnssec_required_zones: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+        let resolver = HickoryResolver::new(&upstream).unwrap();
+        assert!(resolver.resolver_for("_dstack-app-address.example.com").1);
+        assert!(!resolver.resolver_for("_dstack-app-address.example.org").1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_app_is_cached() {
+        let (records, resolver) = resolver_with(
+            StaticResolver::new()
+                .with_txt("_dstack-app-address.example.com", "my-app:80")
+                .with_cname("example.com", "_.prod5.phala.network"),
+        );
+
+        assert_eq!(resolver.resolve_apps("example.com").await, Ok(vec![app("my-app")]));
+        assert_eq!(resolver.resolve_apps("EXAMPLE.com.").await, Ok(vec![app("my-app")]));
+        assert!(resolver.is_dstack_custom_domain("example.com").await);
+        assert_eq!(records.lookup_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_domain_is_negatively_cached() {
+        let (records, resolver) = resolver_with(StaticResolver::new());
+
+        assert!(!resolver.is_dstack_custom_domain("scanner.example").await);
+        assert!(!resolver.is_dstack_custom_domain("scanner.example").await);
+        assert!(resolver.resolve_apps("scanner.example").await.is_err());
+        assert_eq!(records.lookup_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_are_coalesced() {
+        let (records, resolver) = resolver_with(StaticResolver::new().with_delay(Duration::from_millis(50)));
+
+        let lookups = (0..10).map(|_| resolver.is_dstack_custom_domain("scanner.example"));
+        let results = futures_util::future::join_all(lookups).await;
+
+        assert!(results.iter().all(|is_dstack| !is_dstack));
+        assert_eq!(records.lookup_count(), 1);
+        assert!(resolver.inflight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_lookups_leave_no_inflight_slot() {
+        let (_, resolver) = resolver_with(StaticResolver::new().with_delay(Duration::from_secs(5)));
+
+        let lookup = tokio::time::timeout(Duration::from_millis(10), resolver.resolve_apps("scanner.example"));
+        assert!(lookup.await.is_err());
+        assert!(resolver.inflight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transient_failure_mid_cname_chain_is_not_cached() {
+        let (records, resolver) = resolver_with(
+            StaticResolver::new()
+                .with_txt("_dstack-app-address.example.com", "my-app:80")
+                .with_cname("example.com", "hop.example.net")
+                .with_failure("hop.example.net", DnsError::LookupFailed("SERVFAIL".to_string())),
+        );
+
+        let error = resolver.resolve_apps("example.com").await.unwrap_err();
+        assert!(matches!(error, DnsError::LookupFailed(_)), "{:?}", error);
+        let lookups = records.lookup_count();
+        assert!(resolver.resolve_apps("example.com").await.is_err());
+        assert_eq!(records.lookup_count(), 2 * lookups);
+    }
+
+    #[tokio::test]
+    async fn test_transient_gateway_txt_failure_is_not_cached() {
+        let (records, resolver) = resolver_with(
+            StaticResolver::new()
+                .with_txt("_dstack-app-address.example.com", "my-app:80")
+                .with_failure("_dstack-gateway.example.com", DnsError::LookupFailed("timeout".to_string())),
+        );
+
+        let error = resolver.resolve_apps("example.com").await.unwrap_err();
+        assert!(matches!(error, DnsError::LookupFailed(_)), "{:?}", error);
+        let lookups = records.lookup_count();
+        assert!(resolver.resolve_apps("example.com").await.is_err());
+        assert_eq!(records.lookup_count(), 2 * lookups);
+    }
+
+    #[test]
+    fn test_only_validation_errors_are_dnssec_failures() {
+        let classify = |kind: ProtoErrorKind, validated: bool| {
+            let error = ResolveError::from(ProtoError::from(kind));
+            DnsError::from_resolve_error(error, "example.com".to_string(), validated).metric_status()
+        };
+        let unsigned = || ProtoErrorKind::RrsigsNotPresent {
+            name: Name::from_ascii("example.com.").unwrap(),
+            record_type: RecordType::TXT,
+        };
+
+        assert_eq!(classify(unsigned(), true), "dnssec_failure");
+        assert_eq!(classify(ProtoErrorKind::Message("validation failed"), true), "dnssec_failure");
+        assert_eq!(classify(unsigned(), false), "failure");
+        // Transport and protocol trouble on a validated zone is still a lookup failure
+        assert_eq!(classify(ProtoErrorKind::Timeout, true), "failure");
+        assert_eq!(classify(ProtoErrorKind::Message("no connections available"), true), "failure");
+        assert_eq!(classify(ProtoErrorKind::Msg("connection reset by peer".to_string()), true), "failure");
+    }
+
+    use hickory_resolver::proto::error::ProtoError;
+    use hickory_resolver::proto::op::{Message, MessageType, Query};
+    use hickory_resolver::proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, RRSIG};
+    use hickory_resolver::proto::rr::dnssec::{Algorithm, PublicKeyBuf, TrustAnchor};
+    use hickory_resolver::proto::rr::rdata::TXT;
+    use hickory_resolver::proto::rr::{Name, RData, Record};
+    use hickory_resolver::proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions, DnsResponse, DnssecDnsHandle, FirstAnswer};
+
+    /// Nameserver answering every query from a fixed function, for driving hickory's
+    /// DNSSEC validation without a network
+    #[derive(Clone)]
+    struct FakeNameserver(fn(&Query) -> Message);
+
+    impl DnsHandle for FakeNameserver {
+        type Response = futures_util::stream::Once<futures_util::future::Ready<Result<DnsResponse, ProtoError>>>;
+        type Error = ProtoError;
+
+        fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&self, request: R) -> Self::Response {
+            let request = request.into();
+            let query = request.queries()[0].clone();
+            let mut message = (self.0)(&query);
+            message.set_id(request.id()).set_message_type(MessageType::Response).add_query(query);
+            futures_util::stream::once(futures_util::future::ready(DnsResponse::from_message(message)))
+        }
+    }
+
+    const ZONE_KEY: [u8; 32] = [7; 32];
+
+    fn zone_record(rdata: RData) -> Record {
+        Record::from_rdata(Name::from_ascii("example.com.").unwrap(), 300, rdata)
+    }
+
+    fn zone_key() -> Record {
+        zone_record(RData::DNSSEC(DNSSECRData::DNSKEY(DNSKEY::new(true, true, false, Algorithm::ED25519, ZONE_KEY.to_vec()))))
+    }
+
+    /// A TXT record with a signature that doesn't verify, and the (trusted) zone key
+    fn badly_signed(query: &Query) -> Message {
+        let mut message = Message::new();
+        if query.query_type() == RecordType::DNSKEY {
+            message.add_answer(zone_key());
+        } else {
+            let signer = Name::from_ascii("example.com.").unwrap();
+            let rrsig = RRSIG::new(RecordType::TXT, Algorithm::ED25519, 2, 300, u32::MAX, 0, 0, signer, vec![0; 64]);
+            message
+                .add_answer(zone_record(RData::TXT(TXT::new(vec!["my-app:80".to_string()]))))
+                .add_answer(zone_record(RData::DNSSEC(DNSSECRData::RRSIG(rrsig))));
+        }
+        message
+    }
+
+    async fn validation_error(nameserver: fn(&Query) -> Message, options: DnsRequestOptions) -> ProtoError {
+        let mut trust_anchor = TrustAnchor::new();
+        trust_anchor.insert_trust_anchor(&PublicKeyBuf::new(ZONE_KEY.to_vec()));
+        let handle = DnssecDnsHandle::with_trust_anchor(FakeNameserver(nameserver), trust_anchor);
+        let query = Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::TXT);
+        handle.lookup(query, options).first_answer().await.unwrap_err()
+    }
+
+    /// Pins `VALIDATION_FAILURES` to the errors the hickory version in use actually returns
+    #[tokio::test]
+    async fn test_dnssec_handle_errors_are_dnssec_failures() {
+        let unsigned = |_: &Query| {
+            let mut message = Message::new();
+            message.add_answer(zone_record(RData::TXT(TXT::new(vec!["my-app:80".to_string()]))));
+            message
+        };
+        let negative_without_soa = |_: &Query| {
+            let mut message = Message::new();
+            message.add_name_server(zone_key());
+            message
+        };
+        let mut shallow = DnsRequestOptions::default();
+        shallow.max_request_depth = 1;
+
+        let errors = [
+            validation_error(|_| Message::new(), DnsRequestOptions::default()).await,
+            validation_error(unsigned, DnsRequestOptions::default()).await,
+            validation_error(badly_signed, DnsRequestOptions::default()).await,
+            validation_error(badly_signed, shallow).await,
+            validation_error(negative_without_soa, DnsRequestOptions::default()).await,
+        ];
+
+        assert!(matches!(errors[1].kind(), ProtoErrorKind::RrsigsNotPresent { .. }), "{}", errors[1]);
+        let messages = [
+            "no results to verify",
+            "validation failed",
+            "exceeded max validation depth",
+            "could not validate negative response missing SOA",
+        ];
+        for (error, expected) in [&errors[0], &errors[2], &errors[3], &errors[4]].into_iter().zip(messages) {
+            assert!(matches!(error.kind(), ProtoErrorKind::Message(message) if *message == expected), "{}", error);
+        }
+        for error in &errors {
+            assert!(is_validation_failure(error.kind()), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_transient_errors_are_not_cacheable() {
+        assert!(DnsError::NoRecordsFound(String::new()).is_cacheable());
+        assert!(DnsError::ParseError(String::new()).is_cacheable());
+        assert!(!DnsError::LookupFailed(String::new()).is_cacheable());
+    }
 }
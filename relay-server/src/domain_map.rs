@@ -0,0 +1,453 @@
+use crate::app_address::{normalize_gateway, AppAddress, DEFAULT_PRIORITY};
+use crate::dns::DnsError;
+use crate::hostname::{normalize_host, RequestHost};
+use crate::metrics;
+use crate::policy::{PolicyOverride, RelayMode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+/// One entry of the mapping file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DomainMapEntry {
+    /// Exact domain (`app.example.com`) or wildcard (`*.example.com`)
+    domain: String,
+    app_id: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    /// Gateway base domain; when missing, gateway discovery via DNS decides
+    gateway: Option<String>,
+    #[serde(default = "default_priority")]
+    priority: u16,
+}
+
+fn default_port() -> u16 {
+    80
+}
+
+fn default_priority() -> u16 {
+    DEFAULT_PRIORITY
+}
+
+/// One relay policy of the mapping file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyEntry {
+    /// Exact domain (`app.example.com`) or wildcard (`*.example.com`)
+    domain: String,
+    mode: Option<RelayMode>,
+    relay_paths: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DomainMapConfig {
+    #[serde(default)]
+    domains: Vec<DomainMapEntry>,
+    #[serde(default)]
+    policies: Vec<PolicyEntry>,
+}
+
+/// Values keyed by exact domain or `*.suffix` wildcard
+///
+/// Exact domains take precedence over wildcards, and `*.example.com` matches any
+/// name below `example.com` (the longest matching suffix wins).
+#[derive(Debug)]
+struct DomainPatterns<T> {
+    exact: HashMap<String, T>,
+    /// Wildcard suffixes (".example.com"), longest first
+    wildcards: Vec<(String, T)>,
+}
+
+impl<T> Default for DomainPatterns<T> {
+    fn default() -> Self {
+        Self {
+            exact: HashMap::new(),
+            wildcards: Vec::new(),
+        }
+    }
+}
+
+impl<T: Default> DomainPatterns<T> {
+    /// The value for a pattern, starting out as `T::default()`
+    fn entry(&mut self, pattern: &str) -> Result<&mut T, DnsError> {
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => {
+                let suffix = format!(".{}", normalize_pattern(suffix, pattern)?);
+                let index = match self.wildcards.iter().position(|(existing, _)| *existing == suffix) {
+                    Some(index) => index,
+                    None => {
+                        self.wildcards.push((suffix, T::default()));
+                        self.wildcards.len() - 1
+                    }
+                };
+                Ok(&mut self.wildcards[index].1)
+            }
+            None => Ok(self.exact.entry(normalize_pattern(pattern, pattern)?).or_default()),
+        }
+    }
+}
+
+impl<T> DomainPatterns<T> {
+    /// Order the wildcards longest first, once all patterns are in
+    fn finish(&mut self) {
+        self.wildcards.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.exact.values_mut().chain(self.wildcards.iter_mut().map(|(_, value)| value))
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len() + self.wildcards.len()
+    }
+
+    fn lookup(&self, domain: &str) -> Option<&T> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        if let Some(value) = self.exact.get(&domain) {
+            return Some(value);
+        }
+
+        self.wildcards
+            .iter()
+            .find(|(suffix, _)| domain.ends_with(suffix.as_str()) && domain.len() > suffix.len())
+            .map(|(_, value)| value)
+    }
+}
+
+/// Static domain → app address mappings, consulted before the TXT record, and
+/// per-domain relay policies
+///
+/// Exact domains take precedence over wildcards, and `*.example.com` matches any
+/// name below `example.com` (the longest matching suffix wins). Several entries for
+/// the same pattern are failover candidates ordered by priority, like TXT records.
+#[derive(Debug, Default)]
+pub struct DomainMap {
+    addresses: DomainPatterns<Vec<AppAddress>>,
+    policies: DomainPatterns<Option<PolicyOverride>>,
+    entries: usize,
+}
+
+impl DomainMap {
+    /// Parse a mapping file, picking TOML, YAML or JSON from the file extension
+    pub fn parse(path: &Path, content: &str) -> Result<Self, DnsError> {
+        let invalid = |e: String| DnsError::InvalidConfig(format!("{}: {}", path.display(), e));
+
+        let config: DomainMapConfig = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(content).map_err(|e| invalid(e.to_string()))?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(content).map_err(|e| invalid(e.to_string()))?,
+            Some("json") => serde_json::from_str(content).map_err(|e| invalid(e.to_string()))?,
+            _ => return Err(invalid("expected a .toml, .yaml, .yml or .json file".to_string())),
+        };
+
+        Self::from_config(config).map_err(|e| invalid(e.to_string()))
+    }
+
+    fn from_config(config: DomainMapConfig) -> Result<Self, DnsError> {
+        let mut map = Self {
+            entries: config.domains.len(),
+            ..Self::default()
+        };
+
+        for entry in config.domains {
+            let source = format!("mapping for {}", entry.domain);
+            let address = AppAddress {
+                app_id: entry.app_id,
+                port: entry.port,
+                gateway: entry.gateway.as_deref().map(normalize_gateway),
+                priority: entry.priority,
+                mode: None,
+            }
+            .validate(&source)?;
+            map.addresses.entry(&entry.domain)?.push(address);
+        }
+        map.addresses.values_mut().for_each(|addresses| addresses.sort_by_key(|a| a.priority));
+        map.addresses.finish();
+
+        for entry in config.policies {
+            let policy = map.policies.entry(&entry.domain)?;
+            if policy.is_some() {
+                return Err(DnsError::ParseError(format!("Several policies for '{}'", entry.domain)));
+            }
+            *policy = Some(PolicyOverride {
+                mode: entry.mode,
+                relay_paths: entry.relay_paths,
+            });
+        }
+        map.policies.finish();
+
+        Ok(map)
+    }
+
+    /// The candidate addresses mapped for a domain, ordered by priority
+    pub fn lookup(&self, domain: &str) -> Option<&[AppAddress]> {
+        self.addresses.lookup(domain).map(Vec::as_slice)
+    }
+
+    /// The relay policy configured for a domain
+    pub fn policy(&self, domain: &str) -> Option<&PolicyOverride> {
+        self.policies.lookup(domain).and_then(Option::as_ref)
+    }
+
+    /// Number of relay policies in the file
+    pub fn policy_count(&self) -> usize {
+        self.policies.len()
+    }
+
+    /// Number of entries in the file
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+}
+
+/// Normalize a domain pattern the same way Host headers are normalized
+fn normalize_pattern(name: &str, pattern: &str) -> Result<String, DnsError> {
+    match normalize_host(name) {
+        Ok(RequestHost::Domain(domain)) if !name.contains(':') => Ok(domain),
+        _ => Err(DnsError::ParseError(format!("Invalid domain pattern '{}'", pattern))),
+    }
+}
+
+/// Modification time and size, to notice when the file changes
+type FileStamp = (Option<SystemTime>, u64);
+
+/// A mapping file on disk, reloaded when it changes
+pub struct DomainMapFile {
+    path: PathBuf,
+    reload_interval: Duration,
+    map: RwLock<Arc<DomainMap>>,
+    stamp: Mutex<Option<FileStamp>>,
+}
+
+impl DomainMapFile {
+    /// Load the mapping file; a file that can't be read or parsed is an error
+    pub fn load(path: impl Into<PathBuf>, reload_interval: Duration) -> Result<Self, DnsError> {
+        let file = Self {
+            path: path.into(),
+            reload_interval,
+            map: RwLock::new(Arc::new(DomainMap::default())),
+            stamp: Mutex::new(None),
+        };
+        file.reload_if_changed()?;
+        Ok(file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn reload_interval(&self) -> Duration {
+        self.reload_interval
+    }
+
+    /// The mapping currently in effect
+    pub fn current(&self) -> Arc<DomainMap> {
+        self.map.read().unwrap().clone()
+    }
+
+    /// Reload the file if its modification time or size changed
+    ///
+    /// Returns whether a new mapping was swapped in. On error the previous mapping
+    /// stays in effect, and the same broken file isn't parsed again.
+    pub fn reload_if_changed(&self) -> Result<bool, DnsError> {
+        let read_error = |e: std::io::Error| DnsError::InvalidConfig(format!("{}: {}", self.path.display(), e));
+
+        let metadata = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                // Forget the stamp so the file is loaded as soon as it comes back
+                *self.stamp.lock().unwrap() = None;
+                return Err(read_error(e));
+            }
+        };
+        let stamp = Some((metadata.modified().ok(), metadata.len()));
+        if std::mem::replace(&mut *self.stamp.lock().unwrap(), stamp) == stamp {
+            return Ok(false);
+        }
+
+        let content = std::fs::read_to_string(&self.path).map_err(read_error)?;
+        let map = DomainMap::parse(&self.path, &content)?;
+
+        info!(
+            "Loaded {} domain mappings and {} relay policies from {}",
+            map.len(),
+            map.policy_count(),
+            self.path.display()
+        );
+        metrics::set_domain_map_entries(map.len());
+        *self.map.write().unwrap() = Arc::new(map);
+        Ok(true)
+    }
+
+    /// Check the file for changes every `reload_interval`, calling `on_reload` after
+    /// a new mapping was swapped in
+    /// Watching stops once the file is dropped, e.g. replaced by a configuration reload.
+    pub fn watch(self: Arc<Self>, on_reload: impl Fn() + Send + 'static) {
+        if self.reload_interval.is_zero() {
+            return;
+        }
+
+        let file = Arc::downgrade(&self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.reload_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            drop(self);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(file) = file.upgrade() else {
+                    return;
+                };
+                match file.reload_if_changed() {
+                    Ok(true) => on_reload(),
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to reload domain mappings, keeping the previous ones: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(app_id: &str, port: u16, gateway: Option<&str>, priority: u16) -> AppAddress {
+        AppAddress {
+            app_id: app_id.to_string(),
+            port,
+            gateway: gateway.map(str::to_string),
+            priority,
+            mode: None,
+        }
+    }
+
+    fn app_ids(map: &DomainMap, domain: &str) -> Option<Vec<String>> {
+        map.lookup(domain)
+            .map(|addresses| addresses.iter().map(|a| a.app_id.clone()).collect())
+    }
+
+    const TOML: &str = r#"
+[[domains]]
+domain = "App.Example.com"
+app_id = "my-app"
+port = 8080
+gateway = "prod5.phala.network"
+
+[[domains]]
+domain = "*.example.com"
+app_id = "wild-app"
+
+[[domains]]
+domain = "*.eu.example.com"
+app_id = "eu-app"
+"#;
+
+    #[test]
+    fn test_parse_formats() {
+        let toml = DomainMap::parse(Path::new("map.toml"), TOML).unwrap();
+        assert_eq!(
+            toml.lookup("app.example.com"),
+            Some(&[address("my-app", 8080, Some("prod5.phala.network"), DEFAULT_PRIORITY)][..])
+        );
+
+        let yaml = "domains:\n  - domain: app.example.com\n    app_id: my-app\n    priority: 1\n";
+        let yaml = DomainMap::parse(Path::new("map.yaml"), yaml).unwrap();
+        assert_eq!(yaml.lookup("app.example.com"), Some(&[address("my-app", 80, None, 1)][..]));
+
+        let json = r#"{"domains": [{"domain": "app.example.com", "app_id": "my-app", "gateway": "_.prod5.phala.network."}]}"#;
+        let json = DomainMap::parse(Path::new("map.json"), json).unwrap();
+        assert_eq!(
+            json.lookup("app.example.com."),
+            Some(&[address("my-app", 80, Some("_.prod5.phala.network"), DEFAULT_PRIORITY)][..])
+        );
+    }
+
+    #[test]
+    fn test_exact_beats_longest_wildcard() {
+        let map = DomainMap::parse(Path::new("map.toml"), TOML).unwrap();
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(app_ids(&map, "app.example.com"), Some(vec!["my-app".to_string()]));
+        assert_eq!(app_ids(&map, "other.example.com"), Some(vec!["wild-app".to_string()]));
+        assert_eq!(app_ids(&map, "a.b.example.com"), Some(vec!["wild-app".to_string()]));
+        assert_eq!(app_ids(&map, "x.eu.example.com"), Some(vec!["eu-app".to_string()]));
+        assert_eq!(app_ids(&map, "example.com"), None);
+        assert_eq!(app_ids(&map, "notexample.com"), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_files() {
+        let path = Path::new("map.toml");
+        assert!(DomainMap::parse(Path::new("map.ini"), "").is_err());
+        assert!(DomainMap::parse(path, "[[domains]]\ndomain = \"a.com\"\n").is_err());
+        assert!(DomainMap::parse(path, "[[domains]]\ndomain = \"a.com\"\napp_id = \"x\"\nextra = 1\n").is_err());
+        assert!(DomainMap::parse(path, "[[domains]]\ndomain = \"a.com\"\napp_id = \"bad/app\"\n").is_err());
+        assert!(DomainMap::parse(path, "[[domains]]\ndomain = \"a.com:80\"\napp_id = \"x\"\n").is_err());
+        assert!(DomainMap::parse(path, "[[domains]]\ndomain = \"*\"\napp_id = \"x\"\n").is_err());
+        assert!(DomainMap::parse(path, "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_policies() {
+        let toml = r#"
+[[policies]]
+domain = "App.Example.com"
+mode = "proxy"
+
+[[policies]]
+domain = "*.example.com"
+relay_paths = false
+"#;
+        let map = DomainMap::parse(Path::new("map.toml"), toml).unwrap();
+
+        assert_eq!(map.policy_count(), 2);
+        assert!(map.is_empty());
+        assert_eq!(
+            map.policy("app.example.com"),
+            Some(&PolicyOverride {
+                mode: Some(RelayMode::Proxy),
+                relay_paths: None
+            })
+        );
+        assert_eq!(map.policy("other.example.com").unwrap().relay_paths, Some(false));
+        assert_eq!(map.policy("example.com"), None);
+        assert_eq!(map.lookup("app.example.com"), None);
+
+        let path = Path::new("map.toml");
+        assert!(DomainMap::parse(path, "[[policies]]\ndomain = \"a.com\"\nmode = \"tunnel\"\n").is_err());
+        assert!(DomainMap::parse(path, "[[policies]]\ndomain = \"a.com\"\n[[policies]]\ndomain = \"A.com\"\n").is_err());
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let path = std::env::temp_dir().join(format!("domain-map-{}.toml", std::process::id()));
+        std::fs::write(&path, TOML).unwrap();
+
+        let file = DomainMapFile::load(&path, Duration::ZERO).unwrap();
+        assert_eq!(file.current().len(), 3);
+        assert!(!file.reload_if_changed().unwrap());
+
+        // A broken file keeps the previous mapping in effect
+        std::fs::write(&path, "[[domains]]\n").unwrap();
+        assert!(file.reload_if_changed().is_err());
+        assert_eq!(file.current().len(), 3);
+        assert!(!file.reload_if_changed().unwrap());
+
+        std::fs::write(&path, "[[domains]]\ndomain = \"a.com\"\napp_id = \"x\"\n").unwrap();
+        assert!(file.reload_if_changed().unwrap());
+        assert_eq!(app_ids(&file.current(), "a.com"), Some(vec!["x".to_string()]));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(file.reload_if_changed().is_err());
+        assert_eq!(file.current().len(), 1);
+    }
+}
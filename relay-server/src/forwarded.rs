@@ -0,0 +1,225 @@
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Version};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+use crate::config::{ConfigError, Values};
+
+/// Name this relay uses for itself in `Via`
+const VIA_PSEUDONYM: &str = "dstack-relay";
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+static X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+static X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// Peers whose forwarding headers are believed, such as the front nginx
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    /// Parse a comma-separated list of CIDRs or single addresses
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let networks = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("invalid address or CIDR '{}'", entry))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { networks })
+    }
+
+    /// Read TRUSTED_PROXIES, trusting only loopback when unset
+    pub fn from_values(values: &Values) -> Result<Self, ConfigError> {
+        Ok(values.parse_with("TRUSTED_PROXIES", Self::parse)?.unwrap_or_else(|| Self::parse("127.0.0.0/8,::1").unwrap()))
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+}
+
+/// Add the forwarding headers for a request relayed to the app
+///
+/// Forwarding headers from a trusted peer are kept and this hop is appended;
+/// from anyone else they are replaced, so clients can't spoof their address.
+/// `X-Real-IP` is kept from trusted peers (the front nginx sets it) and set to
+/// the peer address otherwise. `Via` is always appended to.
+pub fn add_forwarding_headers(
+    headers: &mut HeaderMap,
+    peer: Option<IpAddr>,
+    trusted_proxies: &TrustedProxies,
+    version: Version,
+) {
+    let peer = peer.map(|ip| ip.to_canonical());
+    let trusted = peer.is_some_and(|ip| trusted_proxies.contains(ip));
+    let host = headers.get(header::HOST).cloned();
+
+    if !trusted {
+        for name in [&header::FORWARDED, &X_FORWARDED_FOR, &X_FORWARDED_PROTO, &X_FORWARDED_HOST, &X_REAL_IP] {
+            headers.remove(name);
+        }
+    }
+
+    // This relay only serves plain HTTP
+    let proto = "http";
+    if !headers.contains_key(&X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO.clone(), HeaderValue::from_static(proto));
+    }
+    if let Some(ref host) = host {
+        if !headers.contains_key(&X_FORWARDED_HOST) {
+            headers.insert(X_FORWARDED_HOST.clone(), host.clone());
+        }
+    }
+
+    if let Some(peer) = peer {
+        append_list(headers, &X_FORWARDED_FOR, &peer.to_string());
+        if !headers.contains_key(&X_REAL_IP) {
+            headers.insert(X_REAL_IP.clone(), HeaderValue::from_str(&peer.to_string()).unwrap());
+        }
+    }
+
+    let mut element = format!("for={}", forwarded_node(peer));
+    element.push_str(&format!(";proto={}", proto));
+    if let Some(host) = host.as_ref().and_then(|host| host.to_str().ok()) {
+        element.push_str(&format!(";host={}", forwarded_value(host)));
+    }
+    append_list(headers, &header::FORWARDED, &element);
+
+    let via = format!("{} {}", via_protocol(version), VIA_PSEUDONYM);
+    append_list(headers, &header::VIA, &via);
+}
+
+/// Append to a comma-separated list header, merging repeated fields into one
+fn append_list(headers: &mut HeaderMap, name: &HeaderName, value: &str) {
+    let mut values: Vec<&[u8]> = headers.get_all(name).iter().map(HeaderValue::as_bytes).collect();
+    values.push(value.as_bytes());
+    let merged = values.join(&b", "[..]);
+
+    if let Ok(merged) = HeaderValue::from_bytes(&merged) {
+        headers.insert(name.clone(), merged);
+    }
+}
+
+/// Node identifier for `Forwarded: for=` (RFC 7239 section 6)
+fn forwarded_node(peer: Option<IpAddr>) -> String {
+    match peer {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Quote a `Forwarded` parameter value unless it is a plain token
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn via_protocol(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("app.example.com"));
+        for (name, value) in pairs {
+            headers.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn nginx() -> TrustedProxies {
+        TrustedProxies::parse("10.0.0.0/8, 192.168.1.1").unwrap()
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let trusted = nginx();
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("192.168.1.1".parse().unwrap()));
+        assert!(trusted.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!trusted.contains("192.168.1.2".parse().unwrap()));
+        assert!(!TrustedProxies::default().contains("127.0.0.1".parse().unwrap()));
+        assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+        assert!(TrustedProxies::parse("nginx").is_err());
+    }
+
+    #[test]
+    fn test_untrusted_peer_headers_are_replaced() {
+        let mut headers = request_headers(&[
+            ("x-forwarded-for", "6.6.6.6"),
+            ("x-real-ip", "6.6.6.6"),
+            ("x-forwarded-proto", "https"),
+            ("forwarded", "for=6.6.6.6"),
+        ]);
+
+        add_forwarding_headers(&mut headers, Some("203.0.113.7".parse().unwrap()), &nginx(), Version::HTTP_11);
+
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7");
+        assert_eq!(headers["x-real-ip"], "203.0.113.7");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "app.example.com");
+        assert_eq!(headers["forwarded"], "for=203.0.113.7;proto=http;host=app.example.com");
+        assert_eq!(headers["via"], "1.1 dstack-relay");
+    }
+
+    #[test]
+    fn test_trusted_peer_headers_are_appended() {
+        let mut headers = request_headers(&[
+            ("x-forwarded-for", "203.0.113.7"),
+            ("x-real-ip", "203.0.113.7"),
+            ("x-forwarded-proto", "https"),
+            ("forwarded", "for=203.0.113.7"),
+            ("via", "1.1 nginx"),
+        ]);
+
+        add_forwarding_headers(&mut headers, Some("10.0.0.2".parse().unwrap()), &nginx(), Version::HTTP_10);
+
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7, 10.0.0.2");
+        assert_eq!(headers["x-real-ip"], "203.0.113.7");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(
+            headers["forwarded"],
+            "for=203.0.113.7, for=10.0.0.2;proto=http;host=app.example.com"
+        );
+        assert_eq!(headers["via"], "1.1 nginx, 1.0 dstack-relay");
+    }
+
+    #[test]
+    fn test_forwarded_quotes_ipv6_and_unknown_peers() {
+        let mut headers = request_headers(&[]);
+        add_forwarding_headers(&mut headers, Some("2001:db8::1".parse().unwrap()), &nginx(), Version::HTTP_11);
+        assert_eq!(headers["forwarded"], "for=\"[2001:db8::1]\";proto=http;host=app.example.com");
+
+        let mut headers = request_headers(&[("x-forwarded-for", "6.6.6.6")]);
+        add_forwarding_headers(&mut headers, None, &nginx(), Version::HTTP_11);
+        assert!(!headers.contains_key("x-forwarded-for"));
+        assert_eq!(headers["forwarded"], "for=unknown;proto=http;host=app.example.com");
+    }
+}
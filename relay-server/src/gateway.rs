@@ -0,0 +1,602 @@
+use regex::Regex;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::{ConfigError, Values};
+use crate::dns::{DnsError, HostTemplate, ResolvedApp};
+use crate::metrics;
+use crate::proxy::{HyperProxyClient, ProxyClient};
+
+/// Matches "_.prod5.phala.network" and captures "prod5.phala.network"
+pub const DEFAULT_PATTERN: &str = r"^_\.(.+\.phala\.network)$";
+
+/// Consecutive failed requests after which a gateway is considered unhealthy
+pub const DEFAULT_UNHEALTHY_AFTER: u32 = 3;
+
+/// How long an unhealthy gateway is tried only after the healthy ones
+pub const DEFAULT_UNHEALTHY_FOR: Duration = Duration::from_secs(30);
+
+/// A dstack gateway the relay is allowed to send traffic to
+///
+/// A CNAME target (or explicit gateway) belongs to the gateway when it matches
+/// `pattern`; the base domain filled into `{gateway}` of the host template is
+/// taken from capture group `capture_group`. Without a pattern any name
+/// matches, with a leading `_.` stripped.
+pub struct Gateway {
+    name: String,
+    pattern: Option<Regex>,
+    capture_group: usize,
+    host_template: HostTemplate,
+    /// TLS settings for connections to the gateway; `None` trusts the Mozilla roots
+    tls: Option<Arc<rustls::ClientConfig>>,
+    unhealthy_after: u32,
+    unhealthy_for: Duration,
+    consecutive_failures: AtomicU32,
+    unhealthy_until: Mutex<Option<Instant>>,
+    /// Pooled client for gateways with their own TLS settings, built on first use
+    proxy_client: OnceLock<ProxyClient>,
+}
+
+impl Gateway {
+    /// A gateway accepting any name, with the default host template
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            pattern: None,
+            capture_group: 1,
+            host_template: HostTemplate::default(),
+            tls: None,
+            unhealthy_after: DEFAULT_UNHEALTHY_AFTER,
+            unhealthy_for: DEFAULT_UNHEALTHY_FOR,
+            consecutive_failures: AtomicU32::new(0),
+            unhealthy_until: Mutex::new(None),
+            proxy_client: OnceLock::new(),
+        }
+    }
+
+    /// Only accept names matching `pattern`, taking the base domain from `capture_group`
+    pub fn with_pattern(mut self, pattern: Regex, capture_group: usize) -> Self {
+        self.pattern = Some(pattern);
+        self.capture_group = capture_group;
+        self
+    }
+
+    pub fn with_host_template(mut self, host_template: HostTemplate) -> Self {
+        self.host_template = host_template;
+        self
+    }
+
+    /// Connect to the gateway with a custom TLS configuration (e.g. a private CA)
+    pub fn with_tls_config(mut self, tls: rustls::ClientConfig) -> Self {
+        self.tls = Some(Arc::new(tls));
+        self
+    }
+
+    /// Mark the gateway unhealthy for `unhealthy_for` after `unhealthy_after`
+    /// consecutive failures (0 never marks it unhealthy)
+    pub fn with_health(mut self, unhealthy_after: u32, unhealthy_for: Duration) -> Self {
+        self.unhealthy_after = unhealthy_after;
+        self.unhealthy_for = unhealthy_for;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn pattern(&self) -> Option<&str> {
+        self.pattern.as_ref().map(Regex::as_str)
+    }
+
+    /// Extract the base domain from a gateway name, or `None` if the name isn't ours
+    pub fn extract(&self, gateway: &str) -> Option<String> {
+        let Some(ref regex) = self.pattern else {
+            return Some(gateway.strip_prefix("_.").unwrap_or(gateway).to_string());
+        };
+
+        let captures = regex.captures(gateway)?;
+        match captures.get(self.capture_group) {
+            Some(captured) => Some(captured.as_str().to_string()),
+            None => {
+                // Optional group that didn't participate, use the whole match
+                warn!(
+                    "'{}' matches gateway {} but capture group {} not found, using whole match",
+                    gateway, self.name, self.capture_group
+                );
+                Some(gateway.to_string())
+            }
+        }
+    }
+
+    /// Build the target host for an app behind this gateway
+    pub fn render(&self, app: &ResolvedApp) -> String {
+        self.host_template.render(app)
+    }
+
+    /// TLS settings of this gateway, if it doesn't use the default client
+    pub fn tls_config(&self) -> Option<&Arc<rustls::ClientConfig>> {
+        self.tls.as_ref()
+    }
+
+    /// Client for proxied requests to this gateway: `default` unless the gateway has
+    /// its own TLS settings, in which case a client with the pool and timeouts of
+    /// `default` is built
+    pub fn proxy_client<'a>(&'a self, default: &'a ProxyClient) -> &'a ProxyClient {
+        match self.tls {
+            Some(ref tls) => self.proxy_client.get_or_init(|| {
+                ProxyClient::Hyper(HyperProxyClient::with_settings(tls.as_ref().clone(), default.settings()))
+            }),
+            None => default,
+        }
+    }
+
+    /// Whether the gateway answered recently, or its unhealthy period is over
+    pub fn is_healthy(&self) -> bool {
+        match *self.unhealthy_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    /// Record a request that reached the gateway
+    pub fn record_success(&self) {
+        metrics::inc_gateway_requests(&self.name, "success");
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        if self.unhealthy_until.lock().unwrap().take().is_some() {
+            info!("Gateway {} is healthy again", self.name);
+        }
+    }
+
+    /// Record a request that failed to reach the gateway
+    pub fn record_failure(&self) {
+        metrics::inc_gateway_requests(&self.name, "failure");
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.unhealthy_after > 0 && failures >= self.unhealthy_after {
+            warn!(
+                "Gateway {} failed {} times in a row, trying it last for {}s",
+                self.name,
+                failures,
+                self.unhealthy_for.as_secs()
+            );
+            *self.unhealthy_until.lock().unwrap() = Some(Instant::now() + self.unhealthy_for);
+        }
+    }
+}
+
+impl Default for Gateway {
+    /// The phala.network gateways, matched by `DEFAULT_PATTERN`
+    fn default() -> Self {
+        Self::new("default").with_pattern(Regex::new(DEFAULT_PATTERN).unwrap(), 1)
+    }
+}
+
+impl fmt::Debug for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Gateway")
+            .field("name", &self.name)
+            .field("pattern", &self.pattern())
+            .field("capture_group", &self.capture_group)
+            .field("host_template", &self.host_template)
+            .field("custom_tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+/// Gateways are identified by name
+impl PartialEq for Gateway {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+/// A gateway name matched against the registry
+#[derive(Clone, Debug, PartialEq)]
+pub struct GatewayMatch {
+    pub gateway: Arc<Gateway>,
+    /// Base domain extracted from the name, e.g. "prod5.phala.network"
+    pub base_domain: String,
+}
+
+/// The gateways the relay trusts, tried in order
+#[derive(Clone, Debug)]
+pub struct GatewayRegistry {
+    gateways: Vec<Arc<Gateway>>,
+}
+
+impl Default for GatewayRegistry {
+    fn default() -> Self {
+        Self::single(Gateway::default())
+    }
+}
+
+/// TLS settings of a gateway entry
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GatewayTlsConfig {
+    /// Verify the gateway certificate; only disable for test clusters
+    #[serde(default = "default_verify")]
+    verify: bool,
+    /// PEM file with CA certificates trusted for this gateway, in addition to the Mozilla roots
+    ca_file: Option<PathBuf>,
+}
+
+impl Default for GatewayTlsConfig {
+    fn default() -> Self {
+        Self {
+            verify: true,
+            ca_file: None,
+        }
+    }
+}
+
+fn default_verify() -> bool {
+    true
+}
+
+fn default_capture_group() -> usize {
+    1
+}
+
+fn default_port() -> u16 {
+    80
+}
+
+fn default_unhealthy_after() -> u32 {
+    DEFAULT_UNHEALTHY_AFTER
+}
+
+fn default_unhealthy_for() -> u64 {
+    DEFAULT_UNHEALTHY_FOR.as_secs()
+}
+
+/// One `[[gateways]]` entry of the gateway file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GatewayConfig {
+    name: String,
+    /// Regex for CNAME targets and explicit gateways; any name when missing
+    #[serde(rename = "match")]
+    pattern: Option<String>,
+    #[serde(default = "default_capture_group")]
+    capture_group: usize,
+    host_template: Option<String>,
+    /// Port without a suffix in `{port_suffix}`
+    #[serde(default = "default_port")]
+    default_port: u16,
+    #[serde(default)]
+    tls: GatewayTlsConfig,
+    #[serde(default = "default_unhealthy_after")]
+    unhealthy_after: u32,
+    /// Seconds
+    #[serde(default = "default_unhealthy_for")]
+    unhealthy_for: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GatewaysConfig {
+    gateways: Vec<GatewayConfig>,
+}
+
+impl GatewayConfig {
+    fn build(self) -> Result<Gateway, DnsError> {
+        let invalid = |e: String| DnsError::InvalidConfig(format!("gateway '{}': {}", self.name, e));
+
+        let mut gateway = Gateway::new(&self.name)
+            .with_host_template(HostTemplate::new(
+                self.host_template.as_deref().unwrap_or(HostTemplate::DEFAULT),
+                self.default_port,
+            )?)
+            .with_health(self.unhealthy_after, Duration::from_secs(self.unhealthy_for));
+
+        if let Some(ref pattern) = self.pattern {
+            let regex = compile_pattern(pattern, self.capture_group).map_err(invalid)?;
+            gateway = gateway.with_pattern(regex, self.capture_group);
+        }
+
+        if !self.tls.verify || self.tls.ca_file.is_some() {
+            gateway = gateway.with_tls_config(self.tls.client_config().map_err(invalid)?);
+        }
+
+        Ok(gateway)
+    }
+}
+
+/// Compile a gateway pattern, checking that it has the capture group for the base domain
+pub fn compile_pattern(pattern: &str, capture_group: usize) -> Result<Regex, String> {
+    let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+    if capture_group >= regex.captures_len() {
+        return Err(format!(
+            "capture group {} out of range, '{}' has {} groups",
+            capture_group,
+            pattern,
+            regex.captures_len() - 1
+        ));
+    }
+    Ok(regex)
+}
+
+impl GatewayTlsConfig {
+    fn client_config(&self) -> Result<rustls::ClientConfig, String> {
+        let builder = rustls::ClientConfig::builder();
+        if !self.verify {
+            let provider = rustls::crypto::CryptoProvider::get_default()
+                .cloned()
+                .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+            return Ok(builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+                .with_no_client_auth());
+        }
+
+        let mut roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(ref ca_file) = self.ca_file {
+            let certs = CertificateDer::pem_file_iter(ca_file)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("{}: {}", ca_file.display(), e))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(format!("{}: no usable CA certificates", ca_file.display()));
+            }
+        }
+        Ok(builder.with_root_certificates(roots).with_no_client_auth())
+    }
+}
+
+/// Accepts any gateway certificate, still checking handshake signatures
+#[derive(Debug)]
+struct NoVerification(Arc<rustls::crypto::CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl GatewayRegistry {
+    /// A registry of the given gateways, which must have distinct names
+    pub fn new(gateways: Vec<Gateway>) -> Result<Self, DnsError> {
+        if gateways.is_empty() {
+            return Err(DnsError::InvalidConfig("at least one gateway is required".to_string()));
+        }
+        for (i, gateway) in gateways.iter().enumerate() {
+            if gateways[..i].iter().any(|other| other.name == gateway.name) {
+                return Err(DnsError::InvalidConfig(format!("duplicate gateway name '{}'", gateway.name)));
+            }
+        }
+
+        Ok(Self {
+            gateways: gateways.into_iter().map(Arc::new).collect(),
+        })
+    }
+
+    pub fn single(gateway: Gateway) -> Self {
+        Self {
+            gateways: vec![Arc::new(gateway)],
+        }
+    }
+
+    /// Parse a gateway file, picking TOML, YAML or JSON from the file extension
+    pub fn parse(path: &Path, content: &str) -> Result<Self, DnsError> {
+        let invalid = |e: String| DnsError::InvalidConfig(format!("{}: {}", path.display(), e));
+
+        let config: GatewaysConfig = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(content).map_err(|e| invalid(e.to_string()))?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(content).map_err(|e| invalid(e.to_string()))?,
+            Some("json") => serde_json::from_str(content).map_err(|e| invalid(e.to_string()))?,
+            _ => return Err(invalid("expected a .toml, .yaml, .yml or .json file".to_string())),
+        };
+
+        let gateways = config
+            .gateways
+            .into_iter()
+            .map(GatewayConfig::build)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(e.to_string()))?;
+        Self::new(gateways).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Load the gateways from GATEWAYS_FILE, or build a single "default" gateway from
+    /// ALLOWED_DOMAIN_REGEX, GATEWAY_DOMAIN_CAPTURE_GROUP, TARGET_HOST_TEMPLATE and
+    /// TARGET_DEFAULT_PORT when it is unset
+    /// Fails on an invalid regex or a capture group the regex doesn't have
+    pub fn from_values(values: &Values) -> Result<Self, ConfigError> {
+        if let Some(path) = values.var("GATEWAYS_FILE") {
+            for ignored in ["ALLOWED_DOMAIN_REGEX", "GATEWAY_DOMAIN_CAPTURE_GROUP", "TARGET_HOST_TEMPLATE", "TARGET_DEFAULT_PORT"] {
+                if values.var(ignored).is_some() {
+                    warn!("{} is ignored, gateways are configured in {}", ignored, path);
+                }
+            }
+
+            let path = PathBuf::from(path);
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| ConfigError::new("GATEWAYS_FILE", format!("{}: {}", path.display(), e)))?;
+            return Self::parse(&path, &content).map_err(|e| ConfigError::from_dns("GATEWAYS_FILE", e));
+        }
+
+        // ALLOWED_DOMAIN_REGEX should include a capture group to extract the gateway domain
+        // (default: 1, 0 uses the entire match)
+        let pattern = values.var("ALLOWED_DOMAIN_REGEX").unwrap_or_else(|| DEFAULT_PATTERN.to_string());
+        let capture_group = values.parse::<usize>("GATEWAY_DOMAIN_CAPTURE_GROUP")?.unwrap_or(1);
+        let pattern = compile_pattern(&pattern, capture_group).map_err(|e| {
+            let setting = if e.starts_with("capture group") {
+                "GATEWAY_DOMAIN_CAPTURE_GROUP"
+            } else {
+                "ALLOWED_DOMAIN_REGEX"
+            };
+            ConfigError::new(setting, e)
+        })?;
+
+        // Target host template and the port that needs no suffix (see HostTemplate)
+        let target_default_port = values.parse::<u16>("TARGET_DEFAULT_PORT")?.unwrap_or(80);
+        let host_template = HostTemplate::new(
+            &values.var("TARGET_HOST_TEMPLATE").unwrap_or_else(|| HostTemplate::DEFAULT.to_string()),
+            target_default_port,
+        )
+        .map_err(|e| ConfigError::from_dns("TARGET_HOST_TEMPLATE", e))?;
+
+        Ok(Self::single(
+            Gateway::new("default")
+                .with_pattern(pattern, capture_group)
+                .with_host_template(host_template),
+        ))
+    }
+
+    /// Find the first gateway a name (CNAME target) belongs to
+    pub fn match_name(&self, name: &str) -> Option<GatewayMatch> {
+        self.gateways.iter().find_map(|gateway| {
+            gateway.extract(name).map(|base_domain| GatewayMatch {
+                gateway: gateway.clone(),
+                base_domain,
+            })
+        })
+    }
+
+    /// Match an explicit gateway (TXT record, fallback), both as written and in
+    /// CNAME form (`_.{gateway}`)
+    pub fn match_explicit(&self, gateway: &str) -> Option<GatewayMatch> {
+        self.match_name(gateway)
+            .or_else(|| self.match_name(&format!("_.{}", gateway)))
+    }
+
+    /// The first gateway, used for a fallback domain that no gateway matches
+    pub fn first(&self) -> &Arc<Gateway> {
+        &self.gateways[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Gateway>> {
+        self.gateways.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.gateways.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gateways.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GATEWAYS: &str = r#"
+[[gateways]]
+name = "prod5"
+match = '^_\.(prod5\.phala\.network)$'
+
+[[gateways]]
+name = "staging"
+match = '^_\.(.+)\.(staging\.example\.net)$'
+capture_group = 2
+host_template = "{app_id}-{port}.{gateway}"
+default_port = 443
+tls = { verify = false }
+unhealthy_after = 1
+"#;
+
+    #[test]
+    fn test_parse_registry() {
+        let registry = GatewayRegistry::parse(Path::new("gateways.toml"), GATEWAYS).unwrap();
+        assert_eq!(registry.len(), 2);
+
+        let prod5 = registry.match_name("_.prod5.phala.network").unwrap();
+        assert_eq!(prod5.gateway.name(), "prod5");
+        assert_eq!(prod5.base_domain, "prod5.phala.network");
+        assert!(prod5.gateway.tls_config().is_none());
+
+        let staging = registry.match_explicit("eu.staging.example.net").unwrap();
+        assert_eq!(staging.gateway.name(), "staging");
+        assert_eq!(staging.base_domain, "staging.example.net");
+        assert!(staging.gateway.tls_config().is_some());
+
+        assert_eq!(registry.match_name("_.prod7.phala.network"), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_gateways() {
+        let path = Path::new("gateways.toml");
+        let parse = |content: &str| GatewayRegistry::parse(path, content);
+
+        assert!(parse("gateways = []").is_err());
+        assert!(parse("[[gateways]]\nname = \"a\"\nmatch = \"(\"\n").is_err());
+        assert!(parse("[[gateways]]\nname = \"a\"\nmatch = \"^(a)$\"\ncapture_group = 2\n").is_err());
+        assert!(parse("[[gateways]]\nname = \"a\"\n[[gateways]]\nname = \"a\"\n").is_err());
+        assert!(parse("[[gateways]]\nname = \"a\"\nhost_template = \"{gateway}\"\n").is_err());
+        assert!(parse("[[gateways]]\nname = \"a\"\ntls = { ca_file = \"/nonexistent.pem\" }\n").is_err());
+    }
+
+    #[test]
+    fn test_compile_pattern() {
+        assert!(compile_pattern(DEFAULT_PATTERN, 1).is_ok());
+        assert!(compile_pattern(DEFAULT_PATTERN, 0).is_ok());
+
+        let e = compile_pattern(DEFAULT_PATTERN, 2).unwrap_err();
+        assert!(e.contains("capture group 2 out of range"), "{}", e);
+        assert!(compile_pattern(r"^_\.(.+\.phala\.network$", 1).is_err());
+    }
+
+    #[test]
+    fn test_extract_without_pattern() {
+        let gateway = Gateway::new("any");
+        assert_eq!(gateway.extract("_.prod5.phala.network").as_deref(), Some("prod5.phala.network"));
+        assert_eq!(gateway.extract("gateway.test").as_deref(), Some("gateway.test"));
+    }
+
+    #[test]
+    fn test_health() {
+        let gateway = Gateway::new("flaky").with_health(2, Duration::from_secs(60));
+        assert!(gateway.is_healthy());
+
+        gateway.record_failure();
+        assert!(gateway.is_healthy());
+        gateway.record_failure();
+        assert!(!gateway.is_healthy());
+
+        gateway.record_success();
+        assert!(gateway.is_healthy());
+
+        let expired = Gateway::new("flaky").with_health(1, Duration::ZERO);
+        expired.record_failure();
+        assert!(expired.is_healthy());
+    }
+}
@@ -0,0 +1,205 @@
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tracing::warn;
+
+/// Maximum length of a domain name in presentation format, without the trailing dot
+const MAX_DOMAIN_LEN: usize = 253;
+
+/// The normalized target host of a request
+///
+/// Every request passes through [`normalize_host_layer`], which stores this in the
+/// request extensions so all handlers agree on which host was asked for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RequestHost {
+    /// Lowercase ASCII (punycode) domain name without port or trailing dot
+    Domain(String),
+    /// An IP literal, which can never be a dstack custom domain
+    Ip(IpAddr),
+    /// The request carried no Host header (HTTP/1.0)
+    Missing,
+}
+
+impl RequestHost {
+    /// The domain name to look up, if the host is one
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            RequestHost::Domain(domain) => Some(domain),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RequestHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestHost::Domain(domain) => write!(f, "{}", domain),
+            RequestHost::Ip(ip) => write!(f, "{}", ip),
+            RequestHost::Missing => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Error for a Host value that is not a valid `host[:port]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidHost(pub String);
+
+impl fmt::Display for InvalidHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid host: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidHost {}
+
+/// Normalize a Host header value
+///
+/// Strips the port, lowercases, drops a trailing dot and converts internationalized
+/// names to punycode. Labels must be letters, digits and inner hyphens.
+pub fn normalize_host(raw: &str) -> Result<RequestHost, InvalidHost> {
+    let invalid = |reason: &str| InvalidHost(format!("{} ({:?})", reason, raw));
+
+    if let Some(rest) = raw.strip_prefix('[') {
+        let (ip, rest) = rest.split_once(']').ok_or_else(|| invalid("unterminated IPv6 literal"))?;
+        let ip: Ipv6Addr = ip.parse().map_err(|_| invalid("bad IPv6 literal"))?;
+        let port = match rest {
+            "" => None,
+            _ => Some(rest.strip_prefix(':').ok_or_else(|| invalid("unexpected data after IPv6 literal"))?),
+        };
+        validate_port(port).map_err(|_| invalid("bad port"))?;
+        return Ok(RequestHost::Ip(IpAddr::V6(ip)));
+    }
+
+    let (host, port) = match raw.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (raw, None),
+    };
+    validate_port(port).map_err(|_| invalid("bad port"))?;
+
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_empty() {
+        return Err(invalid("empty host"));
+    }
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        return Ok(RequestHost::Ip(IpAddr::V4(ip)));
+    }
+
+    let domain = idna::domain_to_ascii_strict(host).map_err(|_| invalid("bad domain name"))?;
+    if domain.is_empty() || domain.len() > MAX_DOMAIN_LEN || !domain.split('.').all(is_valid_label) {
+        return Err(invalid("bad domain name"));
+    }
+
+    Ok(RequestHost::Domain(domain))
+}
+
+/// Middleware that normalizes the Host of every request before it reaches a handler
+///
+/// Malformed hosts are answered with 400 here, so they never cause DNS lookups.
+pub async fn normalize_host_layer(mut req: Request, next: Next) -> Response {
+    let host = match request_host(&req) {
+        Ok(host) => host,
+        Err(e) => {
+            warn!("Rejecting request: {}", e);
+            return (StatusCode::BAD_REQUEST, "Invalid Host header").into_response();
+        }
+    };
+
+    req.extensions_mut().insert(host);
+    next.run(req).await
+}
+
+fn request_host(req: &Request) -> Result<RequestHost, InvalidHost> {
+    match req.headers().get(header::HOST) {
+        Some(value) => {
+            let raw = std::str::from_utf8(value.as_bytes())
+                .map_err(|_| InvalidHost("Host header is not UTF-8".to_string()))?;
+            normalize_host(raw)
+        }
+        // HTTP/2 requests carry the host in the :authority pseudo-header instead
+        None => match req.uri().authority() {
+            Some(authority) => normalize_host(authority.as_str()),
+            None => Ok(RequestHost::Missing),
+        },
+    }
+}
+
+fn validate_port(port: Option<&str>) -> Result<(), ()> {
+    match port {
+        None => Ok(()),
+        Some(port) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            port.parse::<u16>().map(|_| ()).map_err(|_| ())
+        }
+        Some(_) => Err(()),
+    }
+}
+
+/// A DNS label made of letters, digits and inner hyphens
+pub(crate) fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 63
+        && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain(name: &str) -> Result<RequestHost, InvalidHost> {
+        Ok(RequestHost::Domain(name.to_string()))
+    }
+
+    #[test]
+    fn test_normalize_domain() {
+        assert_eq!(normalize_host("app.example.com"), domain("app.example.com"));
+        assert_eq!(normalize_host("App.Example.COM:8080"), domain("app.example.com"));
+        assert_eq!(normalize_host("app.example.com."), domain("app.example.com"));
+        assert_eq!(normalize_host("app.example.com.:80"), domain("app.example.com"));
+        assert_eq!(normalize_host("localhost"), domain("localhost"));
+    }
+
+    #[test]
+    fn test_normalize_idna() {
+        assert_eq!(normalize_host("Bücher.example"), domain("xn--bcher-kva.example"));
+        assert_eq!(normalize_host("xn--bcher-kva.example"), domain("xn--bcher-kva.example"));
+    }
+
+    #[test]
+    fn test_normalize_ip_literals() {
+        assert_eq!(normalize_host("10.0.0.1:8081"), Ok(RequestHost::Ip("10.0.0.1".parse().unwrap())));
+        assert_eq!(normalize_host("[::1]:8081"), Ok(RequestHost::Ip("::1".parse().unwrap())));
+        assert_eq!(normalize_host("[::1]"), Ok(RequestHost::Ip("::1".parse().unwrap())));
+    }
+
+    #[test]
+    fn test_normalize_rejects_malformed_hosts() {
+        for raw in [
+            "",
+            ".",
+            ":80",
+            "app.example.com:",
+            "app.example.com:http",
+            "app.example.com:70000",
+            "app.example.com:80:80",
+            "::1",
+            "[::1",
+            "[::1]x",
+            "[nope]:80",
+            "app..example.com",
+            "-app.example.com",
+            "app_1.example.com",
+            "app.example.com/path",
+            "user@app.example.com",
+            "app example.com",
+        ] {
+            assert!(normalize_host(raw).is_err(), "{:?} should be rejected", raw);
+        }
+        assert!(normalize_host(&format!("{}.com", "a".repeat(64))).is_err());
+    }
+}
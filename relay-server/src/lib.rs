@@ -0,0 +1,538 @@
+pub mod admin;
+pub mod app_address;
+pub mod challenge;
+pub mod config;
+pub mod dns;
+pub mod domain_map;
+pub mod forwarded;
+pub mod gateway;
+pub mod hostname;
+pub mod listener;
+pub mod metrics;
+pub mod policy;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod reload;
+pub mod shutdown;
+pub mod tls_passthrough;
+pub mod tunnel;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Request, State},
+    Extension,
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Redirect, Response},
+    routing::any,
+    Router,
+};
+use arc_swap::ArcSwap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tower_http::trace::TraceLayer;
+use tracing::{error, info, warn};
+
+use admin::AdminToken;
+use challenge::ChallengeOnly;
+use config::Config;
+use dns::{AppTarget, DnsError, DnsResolver};
+use forwarded::TrustedProxies;
+use hostname::RequestHost;
+use proxy::ProxyClient;
+use shutdown::Shutdown;
+use tunnel::TunnelClient;
+
+pub use policy::{RelayMode, RelayPolicy};
+
+/// Everything a request is handled with
+///
+/// Replaced as a whole when the configuration is reloaded; a request keeps the
+/// snapshot it started with.
+#[derive(Clone)]
+pub struct RelayState {
+    pub dns_resolver: Arc<DnsResolver>,
+    /// Client for proxied requests in proxy mode
+    pub proxy_client: ProxyClient,
+    /// Client for WebSocket and other HTTP Upgrade requests in proxy mode
+    pub tunnel_client: TunnelClient,
+    /// Mode for domains whose policy doesn't set one
+    pub relay_mode: RelayMode,
+    /// Honor the `mode=` attribute of `_dstack-app-address` TXT records
+    pub txt_relay_mode: bool,
+    /// Peers whose forwarding headers are kept in proxy mode
+    pub trusted_proxies: TrustedProxies,
+    /// Relay nothing but ACME challenges, when set
+    pub challenge_only: Option<ChallengeOnly>,
+    /// Bearer token for the admin endpoints, when they require one
+    pub admin_token: Option<AdminToken>,
+}
+
+impl RelayState {
+    /// Build the state for a configuration, with a DNS resolver querying real DNS
+    pub fn new(config: &Config) -> Result<Self, DnsError> {
+        Ok(Self {
+            dns_resolver: Arc::new(DnsResolver::new(config)?),
+            proxy_client: config.proxy_client(),
+            tunnel_client: config.tunnel_client(),
+            relay_mode: config.relay_mode,
+            txt_relay_mode: config.txt_relay_mode,
+            trusted_proxies: config.trusted_proxies.clone(),
+            challenge_only: config.challenge_only.clone(),
+            admin_token: config.admin_token.clone(),
+        })
+    }
+
+    /// Rebuild the relay policy for a reloaded configuration with `dns_resolver`,
+    /// keeping the HTTP clients and their pooled connections
+    pub fn reconfigure(&self, config: &Config, dns_resolver: Arc<DnsResolver>) -> Self {
+        Self {
+            dns_resolver,
+            proxy_client: self.proxy_client.clone(),
+            tunnel_client: self.tunnel_client.clone(),
+            relay_mode: config.relay_mode,
+            txt_relay_mode: config.txt_relay_mode,
+            trusted_proxies: config.trusted_proxies.clone(),
+            challenge_only: config.challenge_only.clone(),
+            admin_token: config.admin_token.clone(),
+        }
+    }
+}
+
+/// Shared application state: the current `RelayState`, swapped atomically on reload
+#[derive(Clone)]
+pub struct AppState {
+    current: Arc<ArcSwap<RelayState>>,
+    /// Draining on shutdown, kept across reloads
+    shutdown: Shutdown,
+}
+
+impl AppState {
+    pub fn new(state: RelayState) -> Self {
+        Self {
+            current: Arc::new(ArcSwap::from_pointee(state)),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// The state new requests are handled with
+    pub fn load(&self) -> Arc<RelayState> {
+        self.current.load_full()
+    }
+
+    /// Handle new requests with `state`; requests in flight finish with the old one
+    pub fn store(&self, state: RelayState) {
+        self.current.store(Arc::new(state));
+    }
+}
+
+impl From<RelayState> for AppState {
+    fn from(state: RelayState) -> Self {
+        Self::new(state)
+    }
+}
+
+/// Build the relay router: the ACME challenge route plus the catch-all relay routes
+/// Nothing else is served; metrics and health are on the admin router
+pub fn build_router(state: impl Into<AppState>) -> Router {
+    let state = state.into();
+    Router::new()
+        .route("/.well-known/acme-challenge/:token", any(acme_challenge_handler))
+        .route("/", any(root_handler))
+        .route("/*path", any(catch_all_handler))
+        .layer(middleware::from_fn(hostname::normalize_host_layer))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(middleware::from_fn_with_state(state.shutdown().clone(), shutdown::track_requests))
+        .with_state(state)
+}
+
+/// Tracing span for a request, with the client address when it is known
+pub(crate) fn request_span(req: &Request) -> tracing::Span {
+    let client = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.to_string());
+
+    tracing::debug_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        client = client.as_deref().unwrap_or("-"),
+    )
+}
+
+/// Handle ACME challenge requests
+/// This is the core function that implements the HTTP-01 challenge relay
+async fn acme_challenge_handler(
+    Extension(host): Extension<RequestHost>,
+    Path(token): Path<String>,
+    State(app): State<AppState>,
+    mut req: Request,
+) -> Response {
+    let state = app.load();
+    let start = Instant::now();
+    let path = format!("/.well-known/acme-challenge/{}", token);
+
+    if let Some(challenge_only) = &state.challenge_only {
+        if let Some(reason) = challenge_only.check(req.method(), &token) {
+            warn!("Refusing challenge request ({}): {} {}", reason, req.method(), path);
+            metrics::inc_challenge_only_rejections(reason);
+            return challenge_only.reject();
+        }
+    }
+
+    let Some(hostname) = host.domain() else {
+        warn!("ACME challenge request for non-domain host: {}", host);
+        return (StatusCode::BAD_REQUEST, "Host must be a domain name").into_response();
+    };
+
+    info!(
+        "Received ACME challenge request for domain: {} token: {}",
+        hostname, token
+    );
+
+    let policy = relay_policy(&state, hostname).await;
+    if policy.mode == RelayMode::Deny {
+        return denied(hostname);
+    }
+    if policy.mode == RelayMode::Proxy {
+        add_forwarding_headers(&state, &mut req);
+    }
+
+    let Some(challenge_only) = &state.challenge_only else {
+        return relay_challenge(&state, hostname, &path, policy, start, req).await;
+    };
+
+    // A challenge is a tiny file, so anything slow is given up on early
+    match tokio::time::timeout(
+        challenge_only.timeout,
+        relay_challenge(&state, hostname, &path, policy, start, req),
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(_) => {
+            error!("Timed out relaying challenge for {} after {:?}", hostname, challenge_only.timeout);
+            metrics::inc_challenge_only_rejections("timeout");
+            metrics::inc_redirects("failure");
+            (StatusCode::GATEWAY_TIMEOUT, "Timed out relaying the challenge").into_response()
+        }
+    }
+}
+
+/// Resolve the app for a challenge and redirect or proxy to it
+async fn relay_challenge(
+    state: &RelayState,
+    hostname: &str,
+    path: &str,
+    policy: RelayPolicy,
+    start: Instant,
+    req: Request,
+) -> Response {
+    // Extract method, headers, and body from request
+    let (parts, body) = req.into_parts();
+    let method = parts.method;
+    let headers = parts.headers;
+
+    // Increment metrics
+    metrics::inc_requests("GET", "/.well-known/acme-challenge/*", 200);
+
+    // Resolve the app URLs using DNS (several when the TXT records list failover candidates)
+    let targets = match state.dns_resolver.resolve_app_targets(hostname, path).await {
+        Ok(targets) => {
+            info!("Successfully resolved app URL: {}", targets[0].url);
+            metrics::inc_dns_lookups("combined", "success");
+            targets
+        }
+        Err(e) => {
+            error!("Failed to resolve app URL for {}: {}", hostname, e);
+            metrics::inc_dns_lookups("combined", e.metric_status());
+            metrics::inc_redirects("failure");
+
+            let error_message = format!("Failed to resolve DNS records for {}: {}", hostname, e);
+            return (StatusCode::BAD_GATEWAY, error_message).into_response();
+        }
+    };
+
+    // Observe request duration for DNS resolution
+    let duration = start.elapsed().as_secs_f64();
+    metrics::observe_request_duration("GET", "/.well-known/acme-challenge/*", duration);
+
+    // Handle based on relay mode; challenges are redirected in https-redirect mode too
+    match policy.mode {
+        RelayMode::Redirect | RelayMode::HttpsRedirect => {
+            let app_url = &targets[0].url;
+            info!("Redirecting to: {}", app_url);
+            metrics::inc_redirects("success");
+
+            // Return a 307 Temporary Redirect to the app URL
+            Redirect::temporary(app_url).into_response()
+        }
+        RelayMode::Deny => unreachable!("denied before resolving"),
+        RelayMode::Proxy => {
+            // Proxy the request to the target URL, preserving the original request (including Host header)
+            match proxy_with_failover(state, &targets, &method, &headers, body).await {
+                Ok(response) => {
+                    metrics::inc_redirects("success");
+                    response
+                }
+                Err(e) => {
+                    error!("Failed to proxy request for {}: {}", hostname, e);
+                    metrics::inc_redirects("failure");
+
+                    let error_message = format!("Failed to proxy request: {}", e);
+                    (StatusCode::BAD_GATEWAY, error_message).into_response()
+                }
+            }
+        }
+    }
+}
+
+/// Pick the relay policy for a custom domain
+///
+/// A policy in the mapping file comes first, then the `mode=` attribute of the
+/// TXT record (when `txt_relay_mode` is set), then the global relay mode.
+async fn relay_policy(state: &RelayState, hostname: &str) -> RelayPolicy {
+    let configured = state
+        .dns_resolver
+        .domain_map()
+        .and_then(|domain_map| domain_map.current().policy(hostname).copied())
+        .unwrap_or_default();
+
+    let txt_mode = match configured.mode {
+        None if state.txt_relay_mode => match state.dns_resolver.resolve_apps(hostname).await {
+            Ok(apps) => apps[0].mode,
+            Err(_) => None,
+        },
+        _ => None,
+    };
+
+    let default = RelayPolicy {
+        mode: state.relay_mode,
+        relay_paths: true,
+    };
+    let policy = configured.resolve(txt_mode, default);
+    if policy != default {
+        info!("Relay policy for {}: {:?}", hostname, policy);
+    }
+    policy
+}
+
+fn denied(hostname: &str) -> Response {
+    info!("Relaying is denied for {}", hostname);
+    (StatusCode::FORBIDDEN, "Relaying is disabled for this domain").into_response()
+}
+
+/// Redirect to the same path and query on `https://{hostname}`
+/// GET and HEAD get a 301; other methods a 308, which keeps the method and body
+fn https_redirect(hostname: &str, req: &Request) -> Response {
+    let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    let location = format!("https://{}{}", hostname, path_and_query);
+    info!("Redirecting to HTTPS: {}", location);
+
+    if req.method() == Method::GET || req.method() == Method::HEAD {
+        (StatusCode::MOVED_PERMANENTLY, [(header::LOCATION, location)]).into_response()
+    } else {
+        Redirect::permanent(&location).into_response()
+    }
+}
+
+/// Tell the app who the client is (Forwarded, X-Forwarded-*, X-Real-IP and Via)
+/// The peer address is only known when the server was started with connect info
+fn add_forwarding_headers(state: &RelayState, req: &mut Request) {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let version = req.version();
+    forwarded::add_forwarding_headers(req.headers_mut(), peer, &state.trusted_proxies, version);
+}
+
+/// Proxy an HTTP request to the first candidate URL that accepts it
+/// GET and HEAD requests fail over to the next candidate when a request fails; other
+/// methods only go to the first candidate, since their body can't be replayed.
+/// Each outcome is recorded in the health of the candidate's gateway.
+async fn proxy_with_failover(
+    state: &RelayState,
+    targets: &[AppTarget],
+    method: &Method,
+    original_headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, String> {
+    let replayable = *method == Method::GET || *method == Method::HEAD;
+    if !replayable || targets.len() == 1 {
+        return proxy_to(state, &targets[0], method, original_headers, body).await;
+    }
+
+    let mut last_error = String::new();
+    for target in targets {
+        match proxy_to(state, target, method, original_headers, Body::empty()).await {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                warn!("Failed to proxy request to {}, trying next candidate: {}", target.url, e);
+                last_error = e;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Proxy an HTTP request to one candidate, with the client for its gateway
+async fn proxy_to(
+    state: &RelayState,
+    target: &AppTarget,
+    method: &Method,
+    original_headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, String> {
+    info!("Proxying request to: {} (gateway {})", target.url, target.gateway.name());
+    let client = target.gateway.proxy_client(&state.proxy_client);
+    match client.request(&target.url, method, original_headers, body).await {
+        Ok(response) => {
+            info!("Successfully proxied request to: {}", target.url);
+            target.gateway.record_success();
+            Ok(response)
+        }
+        Err(e) => {
+            target.gateway.record_failure();
+            Err(e)
+        }
+    }
+}
+
+/// Check if a request is an upgrade request (WebSocket, HTTP/2, etc.)
+fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.get("connection")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_lowercase().contains("upgrade"))
+        .unwrap_or(false)
+}
+
+/// Helper function to relay a request to the backend
+async fn relay_to_backend(
+    state: &RelayState,
+    hostname: &str,
+    path: &str,
+    mut req: Request,
+) -> Response {
+    let policy = relay_policy(state, hostname).await;
+    match policy.mode {
+        RelayMode::Deny => return denied(hostname),
+        _ if !policy.relay_paths => {
+            info!("Not relaying {} for {}: only ACME challenges are relayed", path, hostname);
+            return (StatusCode::NOT_FOUND, "Not Found").into_response();
+        }
+        // Browsers stay on the custom domain, so there's nothing to resolve
+        RelayMode::HttpsRedirect => return https_redirect(hostname, &req),
+        RelayMode::Redirect | RelayMode::Proxy => {}
+    }
+
+    // Resolve the app URLs using DNS
+    let targets = match state.dns_resolver.resolve_app_targets(hostname, path).await {
+        Ok(targets) => {
+            info!("Successfully resolved app URL: {}", targets[0].url);
+            targets
+        }
+        Err(e) => {
+            error!("Failed to resolve app URL for {}: {}", hostname, e);
+            let error_message = format!("Failed to resolve DNS records for {}: {}", hostname, e);
+            return (StatusCode::BAD_GATEWAY, error_message).into_response();
+        }
+    };
+
+    if policy.mode == RelayMode::Proxy {
+        add_forwarding_headers(state, &mut req);
+    }
+
+    // Handle based on relay mode
+    match policy.mode {
+        RelayMode::Redirect => {
+            let app_url = &targets[0].url;
+            info!("Redirecting to: {}", app_url);
+            Redirect::temporary(app_url).into_response()
+        }
+        RelayMode::HttpsRedirect | RelayMode::Deny => unreachable!("answered before resolving"),
+        RelayMode::Proxy if is_upgrade_request(req.headers()) => {
+            // WebSocket and other upgrades get a tunnel instead of a proxied request
+            info!("Upgrade request detected for {}, tunneling to backend", hostname);
+            match state.tunnel_client.tunnel(&targets, req).await {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Failed to tunnel request for {}: {}", hostname, e);
+                    let error_message = format!("Failed to tunnel request: {}", e);
+                    (StatusCode::BAD_GATEWAY, error_message).into_response()
+                }
+            }
+        }
+        RelayMode::Proxy => {
+            // Proxy the request to the target URL, preserving the original request (including Host header)
+            let (parts, body) = req.into_parts();
+            match proxy_with_failover(state, &targets, &parts.method, &parts.headers, body).await {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Failed to proxy request for {}: {}", hostname, e);
+                    let error_message = format!("Failed to proxy request: {}", e);
+                    (StatusCode::BAD_GATEWAY, error_message).into_response()
+                }
+            }
+        }
+    }
+}
+
+/// Root handler for the "/" path
+/// Relays for dstack domains; the info page is on the admin listeners
+async fn root_handler(
+    State(app): State<AppState>,
+    req: Request,
+) -> Response {
+    let state = app.load();
+    relay_path(&state, "/", req).await
+}
+
+/// Catch-all handler for any path not matched by specific routes (except /)
+/// Acts as transparent proxy/redirector for dstack domains, returns 404 for anything else
+async fn catch_all_handler(
+    State(app): State<AppState>,
+    Path(path): Path<String>,
+    req: Request,
+) -> Response {
+    let state = app.load();
+    // Normalize path (add leading slash if needed)
+    let normalized_path = if path.starts_with('/') {
+        path
+    } else {
+        format!("/{}", path)
+    };
+
+    relay_path(&state, &normalized_path, req).await
+}
+
+/// Relay a request for a path other than an ACME challenge
+/// Only dstack custom domains are relayed; nothing is served for other hosts
+async fn relay_path(state: &RelayState, path: &str, req: Request) -> Response {
+    if let Some(challenge_only) = &state.challenge_only {
+        info!("Not relaying {}: only ACME challenges are relayed", path);
+        metrics::inc_challenge_only_rejections("path");
+        return challenge_only.reject();
+    }
+
+    // Host normalized by the middleware; IP literals and missing hosts skip the DNS lookup
+    let host = req.extensions().get::<RequestHost>().cloned().unwrap_or(RequestHost::Missing);
+
+    if let Some(hostname) = host.domain() {
+        if state.dns_resolver.is_dstack_custom_domain(hostname).await {
+            info!("Request for dstack domain {} at path {}, relaying to backend", hostname, path);
+            return relay_to_backend(state, hostname, path, req).await;
+        }
+    }
+
+    info!("Request for non-dstack domain {} at path {}", host, path);
+    (StatusCode::NOT_FOUND, "Not Found").into_response()
+}
@@ -0,0 +1,316 @@
+use axum::{body::Body, extract::ConnectInfo, Router};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tower::ServiceExt;
+use tracing::{debug, error, warn};
+
+use crate::metrics;
+use crate::proxy_protocol::{read_header, HEADER_TIMEOUT};
+use crate::shutdown::Shutdown;
+
+/// Client address given to requests on Unix socket listeners: the proxy in front
+/// is on this host, so it is trusted like a loopback peer
+pub const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Where a listener accepts connections
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Parse `IP:PORT` (`[IPv6]:PORT` for IPv6) or `unix:PATH`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix: needs a socket path".to_string());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        value
+            .parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|_| format!("invalid listen address {:?}, expected IP:PORT, [IPv6]:PORT or unix:PATH", value))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// What a listener serves
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListenerRole {
+    /// ACME challenges and relayed custom domain traffic (LISTEN)
+    Relay,
+    /// HTTPS for custom domains, passed through to the gateway by SNI (TLS_PASSTHROUGH_LISTEN)
+    TlsPassthrough,
+    /// Health, metrics, the info page and management endpoints for operators (ADMIN_LISTEN)
+    Admin,
+}
+
+impl ListenerRole {
+    /// Resolves when listeners of this role stop: relay listeners as soon as
+    /// draining starts, admin listeners once the drain is over, so probes see it
+    async fn stopped(self, shutdown: &Shutdown) {
+        match self {
+            ListenerRole::Relay | ListenerRole::TlsPassthrough => shutdown.draining().await,
+            ListenerRole::Admin => shutdown.finished().await,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub addr: ListenAddr,
+    pub role: ListenerRole,
+}
+
+impl ListenerConfig {
+    pub fn new(addr: ListenAddr, role: ListenerRole) -> Self {
+        Self { addr, role }
+    }
+}
+
+/// Parse a comma-separated list of listen addresses
+pub fn parse_list(value: &str) -> Result<Vec<ListenAddr>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(ListenAddr::parse)
+        .collect()
+}
+
+/// Whether an IPv6 wildcard listener must leave IPv4 to another listener on its port
+///
+/// `[::]` is dual-stack on its own, so it accepts IPv4 clients too; only when an
+/// IPv4 listener shares the port does it bind IPv6 alone.
+fn v6_only(addr: SocketAddr, all: &[ListenerConfig]) -> bool {
+    all.iter().any(|listener| {
+        matches!(listener.addr, ListenAddr::Tcp(other) if other.is_ipv4() && other.port() == addr.port())
+    })
+}
+
+/// The socket a listener accepts on
+enum Bound {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A bound listener, ready to serve its role's router
+pub struct Listener {
+    config: ListenerConfig,
+    bound: Bound,
+}
+
+impl Listener {
+    /// Bind `config`; `all` is every configured listener, to decide on dual-stack
+    ///
+    /// A stale Unix socket file left by a previous run is replaced.
+    pub fn bind(config: &ListenerConfig, all: &[ListenerConfig]) -> io::Result<Self> {
+        let bound = match config.addr {
+            ListenAddr::Tcp(addr) => Bound::Tcp(bind_tcp(addr, v6_only(addr, all))?),
+            ListenAddr::Unix(ref path) => Bound::Unix(bind_unix(path)?),
+        };
+        Ok(Self {
+            config: config.clone(),
+            bound,
+        })
+    }
+
+    pub fn config(&self) -> &ListenerConfig {
+        &self.config
+    }
+
+    /// The bound TCP address, with the actual port when port 0 was asked for
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self.bound {
+            Bound::Tcp(ref listener) => listener.local_addr().ok(),
+            Bound::Unix(_) => None,
+        }
+    }
+
+    /// The TCP socket, for listeners that aren't served as HTTP (TLS passthrough)
+    pub fn into_tcp(self) -> io::Result<TcpListener> {
+        match self.bound {
+            Bound::Tcp(listener) => Ok(listener),
+            Bound::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a TCP listener", self.config.addr),
+            )),
+        }
+    }
+
+    /// Serve `router` until the listener's role stops on `shutdown`
+    ///
+    /// The client address is exposed to handlers as `ConnectInfo<SocketAddr>`:
+    /// the peer address (IPv4-mapped addresses unmapped), [`UNIX_PEER`] on Unix
+    /// sockets, or the source from a PROXY header when `accept_proxy_protocol` is
+    /// set; connections without a valid header are then closed. Once the
+    /// listener stops, open connections are closed after their current request.
+    pub async fn serve(self, router: Router, shutdown: Shutdown, accept_proxy_protocol: bool) -> io::Result<()> {
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.accept(&router, &shutdown, accept_proxy_protocol) => accepted,
+                _ = self.config.role.stopped(&shutdown) => return Ok(()),
+            };
+            if let Err(e) = accepted {
+                // Usually running out of file descriptors; back off instead of spinning
+                error!("Failed to accept connection on {}: {}", self.config.addr, e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    /// Accept one connection and serve it in the background
+    async fn accept(&self, router: &Router, shutdown: &Shutdown, accept_proxy_protocol: bool) -> io::Result<()> {
+        let router = router.clone();
+        let shutdown = shutdown.clone();
+        let role = self.config.role;
+        match self.bound {
+            Bound::Tcp(ref listener) => {
+                let (stream, peer) = listener.accept().await?;
+                let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+                tokio::spawn(serve_connection(stream, peer, router, shutdown, role, accept_proxy_protocol));
+            }
+            Bound::Unix(ref listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(serve_connection(stream, UNIX_PEER, router, shutdown, role, accept_proxy_protocol));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+async fn serve_connection<S>(
+    mut stream: S,
+    peer: SocketAddr,
+    router: Router,
+    shutdown: Shutdown,
+    role: ListenerRole,
+    accept_proxy_protocol: bool,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let client = if accept_proxy_protocol {
+        match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+            Ok(Ok(Some(header))) => {
+                metrics::inc_proxy_protocol_connections("proxied");
+                header.source
+            }
+            Ok(Ok(None)) => {
+                metrics::inc_proxy_protocol_connections("local");
+                peer
+            }
+            Ok(Err(e)) => {
+                metrics::inc_proxy_protocol_connections("invalid");
+                warn!("Closing connection from {}: {}", peer, e);
+                return;
+            }
+            Err(_) => {
+                metrics::inc_proxy_protocol_connections("timeout");
+                warn!("Closing connection from {}: no PROXY header within {:?}", peer, HEADER_TIMEOUT);
+                return;
+            }
+        }
+    } else {
+        peer
+    };
+    debug!("Accepted connection from {} via {}", client, peer);
+
+    let service = tower::service_fn(move |mut req: hyper::Request<Incoming>| {
+        req.extensions_mut().insert(ConnectInfo(client));
+        router.clone().oneshot(req.map(Body::new))
+    });
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = role.stopped(&shutdown) => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        debug!("Connection from {} ended with error: {}", client, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addresses() {
+        assert_eq!(
+            parse_list("0.0.0.0:80, [::]:8081,unix:/run/relay.sock").unwrap(),
+            vec![
+                ListenAddr::Tcp("0.0.0.0:80".parse().unwrap()),
+                ListenAddr::Tcp("[::]:8081".parse().unwrap()),
+                ListenAddr::Unix(PathBuf::from("/run/relay.sock")),
+            ]
+        );
+        assert_eq!(ListenAddr::parse("[::1]:80").unwrap().to_string(), "[::1]:80");
+        assert_eq!(ListenAddr::parse("unix:/tmp/a.sock").unwrap().to_string(), "unix:/tmp/a.sock");
+
+        assert!(ListenAddr::parse("8081").is_err());
+        assert!(ListenAddr::parse("::1:80").is_err());
+        assert!(ListenAddr::parse("localhost:80").is_err());
+        assert!(ListenAddr::parse("unix:").is_err());
+    }
+
+    #[test]
+    fn test_v6_only_with_ipv4_on_same_port() {
+        let listeners = |addrs: &str| -> Vec<ListenerConfig> {
+            parse_list(addrs)
+                .unwrap()
+                .into_iter()
+                .map(|addr| ListenerConfig::new(addr, ListenerRole::Relay))
+                .collect()
+        };
+        let wildcard = "[::]:80".parse().unwrap();
+
+        assert!(!v6_only(wildcard, &listeners("[::]:80")));
+        assert!(!v6_only(wildcard, &listeners("[::]:80,0.0.0.0:8081,unix:/tmp/a.sock")));
+        assert!(v6_only(wildcard, &listeners("[::]:80,0.0.0.0:80")));
+    }
+}
@@ -1,48 +1,21 @@
-mod dns;
-mod metrics;
-
-use axum::{
-    body::Body,
-    extract::{Host, Path, Request, State},
-    http::{HeaderMap, Method, StatusCode},
-    response::{IntoResponse, Redirect, Response},
-    routing::any,
-    Router,
-};
-use futures_util::StreamExt;
+use relay_server::config::{Args, Config, Values};
+use relay_server::admin::build_admin_router;
+use relay_server::listener::{ListenAddr, Listener, ListenerRole};
+use relay_server::reload::Reloader;
+use relay_server::tls_passthrough::TlsPassthrough;
+use relay_server::{build_router, metrics, AppState, RelayState};
+use std::fmt::Display;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tower_http::trace::TraceLayer;
+use tokio::task::JoinSet;
 use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use dns::DnsResolver;
-
-/// Relay mode configuration
-#[derive(Clone, Debug, PartialEq)]
-enum RelayMode {
-    /// Return 307 redirect to the target URL (default)
-    Redirect,
-    /// Proxy/tunnel traffic to the target URL
-    Proxy,
-}
-
-impl RelayMode {
-    fn from_env() -> Self {
-        match std::env::var("RELAY_MODE").as_deref() {
-            Ok("proxy") => RelayMode::Proxy,
-            Ok("redirect") => RelayMode::Redirect,
-            _ => RelayMode::Redirect, // Default
-        }
-    }
-}
-
-/// Shared application state
-#[derive(Clone)]
-struct AppState {
-    dns_resolver: Arc<DnsResolver>,
-    http_client: reqwest::Client,
-    relay_mode: RelayMode,
+/// Stop on an invalid setting instead of running with a weaker configuration
+fn valid<T, E: Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        error!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    })
 }
 
 #[tokio::main]
@@ -59,13 +32,30 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // Settings come from the config file, then the environment, then command line flags
+    let args = valid(Args::parse(std::env::args().skip(1)));
+    if args.help {
+        print!("{}", Args::usage());
+        return;
+    }
+    let values = valid(Values::load(&args));
+    if args.print_config {
+        print!("{}", values.describe());
+    }
+    let config = valid(Config::from_values(&values));
+    if args.print_config {
+        return;
+    }
+
     // Initialize metrics
     metrics::init_metrics();
     info!("Metrics initialized");
 
-    // Create DNS resolver
-    let dns_resolver = match DnsResolver::new() {
-        Ok(resolver) => Arc::new(resolver),
+    // Create the DNS resolver and the HTTP clients for proxy mode (hyper by default,
+    // PROXY_CLIENT=reqwest as fallback); both clients keep pooled connections per
+    // gateway host and use bounded timeouts
+    let relay_state = match RelayState::new(&config) {
+        Ok(relay_state) => relay_state,
         Err(e) => {
             error!("Failed to create DNS resolver: {}", e);
             std::process::exit(1);
@@ -74,436 +64,134 @@ async fn main() {
 
     info!("DNS resolver initialized");
 
-    // Determine relay mode
-    let relay_mode = RelayMode::from_env();
-    info!("Relay mode: {:?}", relay_mode);
+    // Pick up changes to the domain mapping file, if one is configured
+    relay_state.dns_resolver.watch_domain_map();
 
-    // Create HTTP client with connection pooling for proxy mode
-    // This client is optimized for high traffic:
-    // - Connection pooling enabled by default
-    // - Timeouts configured to prevent hanging connections
-    // - TLS configured with rustls for better performance
-    let http_client = reqwest::Client::builder()
-        .pool_max_idle_per_host(100) // Keep up to 100 idle connections per host
-        .pool_idle_timeout(Duration::from_secs(90)) // Keep idle connections for 90 seconds
-        .connect_timeout(Duration::from_secs(10)) // Connection timeout
-        .timeout(Duration::from_secs(30)) // Overall request timeout
-        .build()
-        .expect("Failed to create HTTP client");
+    info!("Relay mode: {:?}", config.relay_mode);
 
-    info!("HTTP client initialized with connection pooling");
-
-    // Create application state
-    let state = AppState {
-        dns_resolver,
-        http_client,
-        relay_mode,
-    };
-
-    // Build the application router
-    let app = Router::new()
-        .route("/.well-known/acme-challenge/:token", any(acme_challenge_handler))
-        .route("/metrics", any(metrics_handler))
-        .route("/health", any(health_handler))
-        .route("/", any(root_handler))
-        .route("/*path", any(catch_all_handler))
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
-
-    // Get port from environment variable or use default 8081
-    let port = std::env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse::<u16>().ok())
-        .unwrap_or(8081);
-
-    let bind_addr = format!("0.0.0.0:{}", port);
-
-    // Bind to the configured port
-    let listener = match tokio::net::TcpListener::bind(&bind_addr).await {
-        Ok(listener) => listener,
-        Err(e) => {
-            error!("Failed to bind to {}: {}", bind_addr, e);
-            if port < 1024 {
-                error!("Port {} requires root/sudo permissions", port);
-            }
-            std::process::exit(1);
-        }
-    };
-
-    info!("Relay server listening on http://{}", bind_addr);
-    info!("Metrics endpoint: http://{}/metrics", bind_addr);
-    info!("Health endpoint: http://{}/health", bind_addr);
-
-    // Start the server
-    if let Err(e) = axum::serve(listener, app).await {
-        error!("Server error: {}", e);
-        std::process::exit(1);
+    // Let domain owners pick the relay mode with a mode= attribute in their TXT record
+    if config.txt_relay_mode {
+        info!("Honoring mode= attributes of TXT records");
     }
-}
-
-/// Handle ACME challenge requests
-/// This is the core function that implements the HTTP-01 challenge relay
-async fn acme_challenge_handler(
-    Host(hostname): Host,
-    Path(token): Path<String>,
-    State(state): State<AppState>,
-    req: Request,
-) -> Response {
-    let start = Instant::now();
-    let path = format!("/.well-known/acme-challenge/{}", token);
 
-    // Extract method, headers, and body from request
-    let (parts, body) = req.into_parts();
-    let method = parts.method;
-    let headers = parts.headers;
+    // Optionally relay nothing but ACME challenges
+    if let Some(ref challenge_only) = config.challenge_only {
+        info!(
+            "Challenge-only mode: other requests get {}, challenges time out after {}s",
+            challenge_only.status,
+            challenge_only.timeout.as_secs()
+        );
+    }
 
     info!(
-        "Received ACME challenge request for domain: {} token: {}",
-        hostname, token
+        "HTTP client initialized with connection pooling ({}, {} idle per host, {}s request timeout)",
+        relay_state.proxy_client.name(),
+        config.http_client.pool_max_idle_per_host,
+        config.http_client.request_timeout.as_secs()
     );
 
-    // Increment metrics
-    metrics::inc_requests("GET", "/.well-known/acme-challenge/*", 200);
-
-    // Resolve the app URL using DNS
-    let app_url = match state.dns_resolver.resolve_app_url(&hostname, &path).await {
-        Ok(url) => {
-            info!("Successfully resolved app URL: {}", url);
-            metrics::inc_dns_lookups("combined", "success");
-            url
-        }
-        Err(e) => {
-            error!("Failed to resolve app URL for {}: {}", hostname, e);
-            metrics::inc_dns_lookups("combined", "failure");
-            metrics::inc_redirects("failure");
-
-            let error_message = format!("Failed to resolve DNS records for {}: {}", hostname, e);
-            return (StatusCode::BAD_GATEWAY, error_message).into_response();
-        }
-    };
-
-    // Observe request duration for DNS resolution
-    let duration = start.elapsed().as_secs_f64();
-    metrics::observe_request_duration("GET", "/.well-known/acme-challenge/*", duration);
-
-    // Handle based on relay mode
-    match state.relay_mode {
-        RelayMode::Redirect => {
-            info!("Redirecting to: {}", app_url);
-            metrics::inc_redirects("success");
-
-            // Return a 307 Temporary Redirect to the app URL
-            Redirect::temporary(&app_url).into_response()
-        }
-        RelayMode::Proxy => {
-            info!("Proxying request to: {}", app_url);
-
-            // Proxy the request to the target URL, preserving the original request (including Host header)
-            match proxy_request(&state.http_client, &app_url, &method, &headers, body).await {
-                Ok(response) => {
-                    info!("Successfully proxied request to: {}", app_url);
-                    metrics::inc_redirects("success");
-                    response
-                }
-                Err(e) => {
-                    error!("Failed to proxy request to {}: {}", app_url, e);
-                    metrics::inc_redirects("failure");
+    // Upgrade requests (WebSocket) are tunneled over their own connections
+    let tunnel_client = &relay_state.tunnel_client;
+    info!("Tunnel idle timeout: {}s", tunnel_client.idle_timeout().as_secs());
+    if let Some(version) = tunnel_client.proxy_protocol() {
+        info!("Sending PROXY protocol {:?} headers to the gateway on tunnels", version);
+    }
 
-                    let error_message = format!("Failed to proxy request: {}", e);
-                    (StatusCode::BAD_GATEWAY, error_message).into_response()
+    // Requests use the current state, which is replaced when the configuration is reloaded
+    let state = AppState::new(relay_state);
+    let reloader = Arc::new(Reloader::new(args, values, state.clone()));
+    reloader.clone().watch(config.reload_interval);
+
+    // Bind every listener before serving any, so a bad address stops startup
+    let mut listeners = Vec::new();
+    for listener_config in &config.listeners {
+        match Listener::bind(listener_config, &config.listeners) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                error!("Failed to bind to {}: {}", listener_config.addr, e);
+                if let ListenAddr::Tcp(addr) = listener_config.addr {
+                    if addr.port() < 1024 {
+                        error!("Port {} requires root/sudo permissions", addr.port());
+                    }
                 }
+                std::process::exit(1);
             }
         }
     }
-}
 
-/// Proxy an HTTP request to the target URL
-/// This function handles the proxying with connection pooling and streaming
-async fn proxy_request(
-    client: &reqwest::Client,
-    target_url: &str,
-    method: &Method,
-    original_headers: &HeaderMap,
-    body: Body,
-) -> Result<Response, String> {
-    // Convert method
-    let req_method = match method.as_str() {
-        "GET" => reqwest::Method::GET,
-        "POST" => reqwest::Method::POST,
-        "PUT" => reqwest::Method::PUT,
-        "DELETE" => reqwest::Method::DELETE,
-        "HEAD" => reqwest::Method::HEAD,
-        "OPTIONS" => reqwest::Method::OPTIONS,
-        "PATCH" => reqwest::Method::PATCH,
-        _ => reqwest::Method::GET,
-    };
+    // Behind an L4 load balancer, the client address comes from each connection's PROXY header
+    let proxy_protocol = config.proxy_protocol;
 
-    // Convert axum body to a stream and wrap for reqwest
-    // This avoids buffering the entire body in memory
-    let body_stream = body.into_data_stream().map(|result| {
-        result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-    });
-    let reqwest_body = reqwest::Body::wrap_stream(body_stream);
+    // HTTPS to custom domains is passed through to the gateway by SNI on TLS passthrough listeners
+    let passthrough = TlsPassthrough::new(state.clone(), config.tunnel_idle_timeout)
+        .with_connect_timeout(config.http_client.connect_timeout)
+        .with_proxy_protocol(proxy_protocol, config.upstream_proxy_protocol);
 
-    // Build request with method and streaming body
-    let mut request_builder = client
-        .request(req_method, target_url)
-        .body(reqwest_body);
+    // SIGTERM/SIGINT stop the listeners; requests and tunnels get SHUTDOWN_TIMEOUT to finish
+    let shutdown = state.shutdown().clone();
+    shutdown.listen_for_signals();
 
-    // Forward all headers, including Host, except hop-by-hop headers
-    for (key, value) in original_headers.iter() {
-        let key_str = key.as_str().to_lowercase();
-        // Skip hop-by-hop headers (but keep host and preserve upgrade/connection for upgrade handling)
-        if key_str != "transfer-encoding"
-            && key_str != "content-length"  // Let reqwest handle content-length
-            && key_str != "te"
-            && key_str != "trailer"
-            && key_str != "proxy-connection"
-            && key_str != "keep-alive" {
-            if let Ok(val) = value.to_str() {
-                request_builder = request_builder.header(key.as_str(), val);
-            }
-        }
+    // Relay listeners only relay; metrics, health and management are on the admin listeners
+    let relay_router = build_router(state.clone());
+    let admin_router = build_admin_router(state.clone(), Some(reloader));
+    if proxy_protocol {
+        info!("Expecting a PROXY protocol header on every relay connection");
     }
-
-    // Send the request
-    let response = request_builder
-        .send()
-        .await
-        .map_err(|e| format!("Request failed: {}", e))?;
-
-    // Extract status code
-    let status = response.status();
-
-    // Extract headers to forward (filtering out connection-specific headers)
-    let mut headers = HeaderMap::new();
-    for (key, value) in response.headers() {
-        let key_str = key.as_str().to_lowercase();
-        // Skip connection-specific headers
-        if key_str != "connection"
-            && key_str != "transfer-encoding"
-            && key_str != "content-encoding"
-            && key_str != "content-length" {
-            if let Ok(val) = value.to_str() {
-                if let Ok(header_value) = val.parse() {
-                    headers.insert(key.clone(), header_value);
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        let addr = &listener.config().addr;
+        let (router, accept_proxy_protocol) = match listener.config().role {
+            ListenerRole::TlsPassthrough => {
+                info!("TLS passthrough listening on {}", addr);
+                let passthrough = passthrough.clone();
+                servers.spawn(async move { passthrough.serve(listener.into_tcp()?).await });
+                continue;
+            }
+            ListenerRole::Relay => {
+                info!("Relay server listening on {}", addr);
+                (relay_router.clone(), proxy_protocol)
+            }
+            ListenerRole::Admin => {
+                info!("Admin endpoints (/metrics, /health, /ready) listening on {}", addr);
+                if config.admin_token.is_none() && matches!(addr, ListenAddr::Tcp(ip) if !ip.ip().is_loopback()) {
+                    warn!("Admin listener {} is reachable from other hosts and ADMIN_TOKEN is not set", addr);
                 }
+                (admin_router.clone(), false)
             }
-        }
+        };
+        servers.spawn(listener.serve(router, shutdown.clone(), accept_proxy_protocol));
     }
 
-    // Convert the response body to a stream
-    // This is important for handling large responses efficiently
-    let body_stream = response.bytes_stream();
-    let body = Body::from_stream(body_stream);
-
-    // Construct the response
-    let mut resp = Response::new(body);
-    *resp.status_mut() = status;
-    *resp.headers_mut() = headers;
-
-    Ok(resp)
-}
-
-/// Check if a request is an upgrade request (WebSocket, HTTP/2, etc.)
-fn is_upgrade_request(headers: &HeaderMap) -> bool {
-    headers.get("connection")
-        .and_then(|v| v.to_str().ok())
-        .map(|v| v.to_lowercase().contains("upgrade"))
-        .unwrap_or(false)
-}
-
-/// Helper function to handle requests with domain checking
-/// If Host is a dstack domain, relays to backend; otherwise calls the provided handler
-async fn handle_request_with_domain_check<F>(
-    state: &AppState,
-    path: &str,
-    req: Request,
-    non_dstack_handler: F,
-) -> Response
-where
-    F: FnOnce() -> Response,
-{
-    let (parts, body) = req.into_parts();
-
-    // Extract Host header
-    let hostname = parts.headers.get("host")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
-
-    // Check if this is a dstack custom domain
-    if state.dns_resolver.is_dstack_custom_domain(&hostname).await {
-        info!("Request for dstack domain {} at path {}, relaying to backend", hostname, path);
-
-        // Relay to the backend with full request
-        return relay_to_backend(state, &hostname, path, &parts.method, &parts.headers, body).await;
-    }
-
-    // Not a dstack domain - call the provided handler
-    info!("Request for non-dstack domain {} at path {}", hostname, path);
-    non_dstack_handler()
-}
-
-/// Helper function to relay a request to the backend
-async fn relay_to_backend(
-    state: &AppState,
-    hostname: &str,
-    path: &str,
-    method: &Method,
-    headers: &HeaderMap,
-    body: Body,
-) -> Response {
-    // Check if this is an upgrade request
-    if is_upgrade_request(headers) {
-        warn!("Upgrade request detected for {} (WebSocket, HTTP/2, etc.)", hostname);
-
-        // For upgrade requests in proxy mode, we currently don't support them
-        // because reqwest doesn't handle protocol upgrades
-        if state.relay_mode == RelayMode::Proxy {
-            warn!("Protocol upgrades are not fully supported in proxy mode yet. Consider using redirect mode (RELAY_MODE=redirect) for WebSocket and other upgrade requests.");
-            return (
-                StatusCode::NOT_IMPLEMENTED,
-                "Protocol upgrades (WebSocket, HTTP/2) are not supported in proxy mode. Please use redirect mode (set RELAY_MODE=redirect) for upgrade requests."
-            ).into_response();
-        }
-    }
-
-    // Resolve the app URL using DNS
-    let app_url = match state.dns_resolver.resolve_app_url(hostname, path).await {
-        Ok(url) => {
-            info!("Successfully resolved app URL: {}", url);
-            url
-        }
-        Err(e) => {
-            error!("Failed to resolve app URL for {}: {}", hostname, e);
-            let error_message = format!("Failed to resolve DNS records for {}: {}", hostname, e);
-            return (StatusCode::BAD_GATEWAY, error_message).into_response();
-        }
-    };
-
-    // Handle based on relay mode
-    match state.relay_mode {
-        RelayMode::Redirect => {
-            info!("Redirecting to: {}", app_url);
-            Redirect::temporary(&app_url).into_response()
-        }
-        RelayMode::Proxy => {
-            info!("Proxying request to: {}", app_url);
-
-            // Proxy the request to the target URL, preserving the original request (including Host header)
-            match proxy_request(&state.http_client, &app_url, method, headers, body).await {
-                Ok(response) => {
-                    info!("Successfully proxied request to: {}", app_url);
-                    response
-                }
-                Err(e) => {
-                    error!("Failed to proxy request to {}: {}", app_url, e);
-                    let error_message = format!("Failed to proxy request: {}", e);
-                    (StatusCode::BAD_GATEWAY, error_message).into_response()
-                }
+    // A listener that fails or panics takes the whole server down, rather than
+    // leaving it running without that listener
+    tokio::select! {
+        _ = shutdown.draining() => {}
+        Some(finished) = servers.join_next() => match finished {
+            // Listeners only return on their own once draining starts
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                error!("Server error: {}", e);
+                std::process::exit(1);
             }
-        }
+            Err(e) => {
+                error!("Server task failed: {}", e);
+                std::process::exit(1);
+            }
+        },
     }
-}
 
-/// Metrics endpoint for Prometheus scraping
-/// Serves relay server metrics if Host is not a dstack domain, otherwise relays to backend
-async fn metrics_handler(
-    State(state): State<AppState>,
-    req: Request,
-) -> Response {
-    handle_request_with_domain_check(&state, "/metrics", req, || {
-        let metrics = metrics::gather_metrics();
-        (
-            StatusCode::OK,
-            [("content-type", "text/plain; version=0.0.4")],
-            metrics,
-        )
-            .into_response()
-    })
-    .await
-}
-
-/// Health check endpoint
-/// Serves relay server health if Host is not a dstack domain, otherwise relays to backend
-async fn health_handler(
-    State(state): State<AppState>,
-    req: Request,
-) -> Response {
-    handle_request_with_domain_check(&state, "/health", req, || {
-        (StatusCode::OK, "OK").into_response()
-    })
-    .await
-}
-
-/// Root handler for the "/" path
-/// Acts as transparent proxy/redirector for dstack domains, serves info page for relay server
-async fn root_handler(
-    State(state): State<AppState>,
-    req: Request,
-) -> Response {
-    let relay_mode = state.relay_mode.clone();
-    handle_request_with_domain_check(&state, "/", req, move || {
-        let mode_description = match relay_mode {
-        RelayMode::Redirect => "307 redirect (default)",
-        RelayMode::Proxy => "HTTP proxy/tunnel",
-    };
-
-        let info = format!(
-            r#"
-dstack HTTP-01 ACME Challenge Relay Server
-
-This server relays ACME HTTP-01 challenges to dstack applications.
-
-Current Mode: {}
-
-Endpoints:
-- /.well-known/acme-challenge/:token - ACME challenge endpoint
-- /metrics - Prometheus metrics
-- /health - Health check
-
-How it works:
-1. Let's Encrypt requests http://{{custom-domain}}/.well-known/acme-challenge/{{token}}
-2. This server looks up DNS records:
-   - TXT _dstack-app-address.{{custom-domain}} -> {{app-id}}:port
-   - CNAME {{custom-domain}} -> _.{{gateway-base-domain}}
-3. In redirect mode: Returns 307 redirect to https://{{app-id}}.{{gateway-base-domain}}/.well-known/acme-challenge/{{token}}
-   In proxy mode: Proxies the request directly to the target HTTPS endpoint
-4. The ACME client in dstack responds with the challenge
-
-Proxy Mode Features:
-- Connection pooling (up to 100 idle connections per host)
-- Request streaming for efficient memory usage
-- Configured timeouts for reliability
-- Optimized for high traffic scenarios
-
-Status: Running
-"#,
-            mode_description
-        );
-
-        (StatusCode::OK, info).into_response()
-    })
-    .await
-}
-
-/// Catch-all handler for any path not matched by specific routes (except /)
-/// Acts as transparent proxy/redirector for dstack domains, returns 404 for relay server
-async fn catch_all_handler(
-    State(state): State<AppState>,
-    Path(path): Path<String>,
-    req: Request,
-) -> Response {
-    // Normalize path (add leading slash if needed)
-    let normalized_path = if path.starts_with('/') {
-        path
+    info!(
+        "Stopped accepting connections, waiting up to {}s for {} requests and tunnels",
+        config.shutdown_timeout.as_secs(),
+        shutdown.active()
+    );
+    let cut_off = shutdown.drain(config.shutdown_timeout).await;
+    if cut_off.is_empty() {
+        info!("All connections drained, shutting down");
     } else {
-        format!("/{}", path)
-    };
-
-    handle_request_with_domain_check(&state, &normalized_path, req, || {
-        (StatusCode::NOT_FOUND, "Not Found").into_response()
-    })
-    .await
+        warn!("Shutdown timeout reached, cutting off {} requests and tunnels", cut_off.len());
+        for activity in &cut_off {
+            warn!("Cut off {}", activity);
+        }
+    }
 }
@@ -1,5 +1,5 @@
 use prometheus::{
-    register_int_counter_vec, register_histogram_vec, IntCounterVec, HistogramVec, Encoder, TextEncoder,
+    register_int_counter, register_int_counter_vec, register_int_gauge, register_histogram_vec, IntCounter, IntCounterVec, IntGauge, HistogramVec, Encoder, TextEncoder,
 };
 use std::sync::OnceLock;
 
@@ -7,6 +7,19 @@ static REQUESTS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
 static REQUEST_DURATION: OnceLock<HistogramVec> = OnceLock::new();
 static DNS_LOOKUPS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
 static REDIRECTS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static DNS_CACHE_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static DNS_CACHE_ENTRIES: OnceLock<IntGauge> = OnceLock::new();
+static APP_RESOLUTIONS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static DOMAIN_MAP_ENTRIES: OnceLock<IntGauge> = OnceLock::new();
+static TUNNELS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static TUNNELS_ACTIVE: OnceLock<IntGauge> = OnceLock::new();
+static TUNNEL_BYTES_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static PROXY_PROTOCOL_CONNECTIONS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static TLS_PASSTHROUGH_CONNECTIONS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static CHALLENGE_ONLY_REJECTIONS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static GATEWAY_REQUESTS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static CONFIG_RELOADS_TOTAL: OnceLock<IntCounterVec> = OnceLock::new();
+static ADMIN_AUTH_FAILURES_TOTAL: OnceLock<IntCounter> = OnceLock::new();
 
 /// Initialize Prometheus metrics
 pub fn init_metrics() {
@@ -45,6 +58,119 @@ pub fn init_metrics() {
         )
         .unwrap()
     });
+
+    DNS_CACHE_TOTAL.get_or_init(|| {
+        register_int_counter_vec!(
+            "dns_cache_requests_total",
+            "Total number of DNS cache lookups",
+            &["result"]
+        )
+        .unwrap()
+    });
+
+    DNS_CACHE_ENTRIES.get_or_init(|| {
+        register_int_gauge!(
+            "dns_cache_entries",
+            "Number of entries in the DNS cache"
+        )
+        .unwrap()
+    });
+
+    APP_RESOLUTIONS_TOTAL.get_or_init(|| {
+        register_int_counter_vec!(
+            "app_resolutions_total",
+            "Total number of relayed requests by the source that resolved the app",
+            &["source"]
+        )
+        .unwrap()
+    });
+
+    DOMAIN_MAP_ENTRIES.get_or_init(|| {
+        register_int_gauge!(
+            "domain_map_entries",
+            "Number of entries in the domain mapping file"
+        )
+        .unwrap()
+    });
+
+    TUNNELS_TOTAL.get_or_init(|| {
+        register_int_counter_vec!(
+            "tunnels_total",
+            "Total number of finished upgrade tunnels by how they ended",
+            &["status"]
+        )
+        .unwrap()
+    });
+
+    TUNNELS_ACTIVE.get_or_init(|| {
+        register_int_gauge!(
+            "tunnels_active",
+            "Number of open upgrade tunnels"
+        )
+        .unwrap()
+    });
+
+    PROXY_PROTOCOL_CONNECTIONS_TOTAL.get_or_init(|| {
+        register_int_counter_vec!(
+            "proxy_protocol_connections_total",
+            "Total number of connections accepted by the PROXY protocol listener by header outcome",
+            &["status"]
+        )
+        .unwrap()
+    });
+
+    TLS_PASSTHROUGH_CONNECTIONS_TOTAL.get_or_init(|| {
+        register_int_counter_vec!(
+            "tls_passthrough_connections_total",
+            "Total number of connections on the TLS passthrough listener by outcome",
+            &["status"]
+        )
+        .unwrap()
+    });
+
+    CHALLENGE_ONLY_REJECTIONS_TOTAL.get_or_init(|| {
+        register_int_counter_vec!(
+            "challenge_only_rejections_total",
+            "Total number of requests refused in challenge-only mode",
+            &["reason"]
+        )
+        .unwrap()
+    });
+
+    GATEWAY_REQUESTS_TOTAL.get_or_init(|| {
+        register_int_counter_vec!(
+            "gateway_requests_total",
+            "Total number of proxied requests and tunnels by gateway and outcome",
+            &["gateway", "status"]
+        )
+        .unwrap()
+    });
+
+    CONFIG_RELOADS_TOTAL.get_or_init(|| {
+        register_int_counter_vec!(
+            "config_reloads_total",
+            "Total number of configuration reloads by outcome",
+            &["status"]
+        )
+        .unwrap()
+    });
+
+    ADMIN_AUTH_FAILURES_TOTAL.get_or_init(|| {
+        register_int_counter!(
+            "admin_auth_failures_total",
+            "Total number of admin requests rejected for a missing or wrong bearer token"
+        )
+        .unwrap()
+    });
+
+    TUNNEL_BYTES_TOTAL.get_or_init(|| {
+        register_int_counter_vec!(
+            "tunnel_bytes_total",
+            "Total bytes copied through upgrade tunnels",
+            &["direction"]
+        )
+        .unwrap()
+    });
 }
 
 /// Increment HTTP request counter
@@ -79,6 +205,111 @@ pub fn inc_redirects(status: &str) {
     }
 }
 
+/// Increment DNS cache counter ("hit" or "miss")
+pub fn inc_dns_cache(result: &str) {
+    if let Some(counter) = DNS_CACHE_TOTAL.get() {
+        counter.with_label_values(&[result]).inc();
+    }
+}
+
+/// Set the current number of DNS cache entries
+pub fn set_dns_cache_entries(entries: usize) {
+    if let Some(gauge) = DNS_CACHE_ENTRIES.get() {
+        gauge.set(entries as i64);
+    }
+}
+
+/// Increment the resolution counter for a source ("domain_map" or "dns")
+pub fn inc_app_resolutions(source: &str) {
+    if let Some(counter) = APP_RESOLUTIONS_TOTAL.get() {
+        counter.with_label_values(&[source]).inc();
+    }
+}
+
+/// Number of requests resolved by a source so far
+pub fn app_resolutions(source: &str) -> u64 {
+    APP_RESOLUTIONS_TOTAL
+        .get()
+        .map_or(0, |counter| counter.with_label_values(&[source]).get())
+}
+
+/// Set the current number of domain mapping entries
+pub fn set_domain_map_entries(entries: usize) {
+    if let Some(gauge) = DOMAIN_MAP_ENTRIES.get() {
+        gauge.set(entries as i64);
+    }
+}
+
+/// Increment the finished tunnel counter ("closed", "idle_timeout" or "error")
+pub fn inc_tunnels(status: &str) {
+    if let Some(counter) = TUNNELS_TOTAL.get() {
+        counter.with_label_values(&[status]).inc();
+    }
+}
+
+/// Count a newly opened tunnel
+pub fn inc_tunnels_active() {
+    if let Some(gauge) = TUNNELS_ACTIVE.get() {
+        gauge.inc();
+    }
+}
+
+/// Count a closed tunnel
+pub fn dec_tunnels_active() {
+    if let Some(gauge) = TUNNELS_ACTIVE.get() {
+        gauge.dec();
+    }
+}
+
+/// Add bytes copied through a tunnel ("client_to_upstream" or "upstream_to_client")
+pub fn inc_tunnel_bytes(direction: &str, bytes: u64) {
+    if let Some(counter) = TUNNEL_BYTES_TOTAL.get() {
+        counter.with_label_values(&[direction]).inc_by(bytes);
+    }
+}
+
+/// Count a connection by PROXY header outcome ("proxied", "local", "invalid" or "timeout")
+pub fn inc_proxy_protocol_connections(status: &str) {
+    if let Some(counter) = PROXY_PROTOCOL_CONNECTIONS_TOTAL.get() {
+        counter.with_label_values(&[status]).inc();
+    }
+}
+
+/// Count a TLS passthrough connection by outcome ("relayed", "no_sni", "unknown_domain", ...)
+pub fn inc_tls_passthrough_connections(status: &str) {
+    if let Some(counter) = TLS_PASSTHROUGH_CONNECTIONS_TOTAL.get() {
+        counter.with_label_values(&[status]).inc();
+    }
+}
+
+/// Count a request refused in challenge-only mode ("method", "token", "path" or "timeout")
+pub fn inc_challenge_only_rejections(reason: &str) {
+    if let Some(counter) = CHALLENGE_ONLY_REJECTIONS_TOTAL.get() {
+        counter.with_label_values(&[reason]).inc();
+    }
+}
+
+/// Count a configuration reload ("success" or "failure")
+pub fn inc_config_reloads(status: &str) {
+    if let Some(counter) = CONFIG_RELOADS_TOTAL.get() {
+        counter.with_label_values(&[status]).inc();
+    }
+}
+
+/// Count an admin request rejected for its bearer token
+pub fn inc_admin_auth_failures() {
+    if let Some(counter) = ADMIN_AUTH_FAILURES_TOTAL.get() {
+        counter.inc();
+    }
+}
+
+/// Count a request to a gateway ("success" or "failure")
+pub fn inc_gateway_requests(gateway: &str, status: &str) {
+    if let Some(counter) = GATEWAY_REQUESTS_TOTAL.get() {
+        counter.with_label_values(&[gateway, status]).inc();
+    }
+}
+
 /// Gather and encode all metrics for Prometheus scraping
 pub fn gather_metrics() -> Vec<u8> {
     let encoder = TextEncoder::new();
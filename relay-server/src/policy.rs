@@ -0,0 +1,113 @@
+use serde::Deserialize;
+
+use crate::config::{ConfigError, Values};
+
+/// Relay mode configuration
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RelayMode {
+    /// Return 307 redirect to the target URL (default)
+    Redirect,
+    /// Proxy/tunnel traffic to the target URL
+    Proxy,
+    /// Redirect ACME challenges like `Redirect`, and send everything else to
+    /// `https://{custom-domain}{path}` with a permanent redirect
+    HttpsRedirect,
+    /// Refuse to relay anything for the domain
+    Deny,
+}
+
+impl RelayMode {
+    /// Parse "redirect", "proxy", "https-redirect" or "deny"
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "redirect" => Some(RelayMode::Redirect),
+            "proxy" => Some(RelayMode::Proxy),
+            "https-redirect" => Some(RelayMode::HttpsRedirect),
+            "deny" => Some(RelayMode::Deny),
+            _ => None,
+        }
+    }
+
+    /// Read RELAY_MODE (default redirect)
+    pub fn from_values(values: &Values) -> Result<Self, ConfigError> {
+        let mode = values.parse_with("RELAY_MODE", |s| {
+            Self::parse(s).ok_or_else(|| "expected redirect, proxy, https-redirect or deny".to_string())
+        })?;
+        Ok(mode.unwrap_or(RelayMode::Redirect))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelayMode::Redirect => "redirect",
+            RelayMode::Proxy => "proxy",
+            RelayMode::HttpsRedirect => "https-redirect",
+            RelayMode::Deny => "deny",
+        }
+    }
+}
+
+/// How requests for one custom domain are relayed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RelayPolicy {
+    pub mode: RelayMode,
+    /// Whether paths other than ACME challenges are relayed at all
+    pub relay_paths: bool,
+}
+
+/// Per-domain settings from the mapping file; unset fields fall through to the
+/// TXT record's `mode` attribute (when enabled) and then to the global defaults
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub struct PolicyOverride {
+    pub mode: Option<RelayMode>,
+    pub relay_paths: Option<bool>,
+}
+
+impl PolicyOverride {
+    /// Fill in unset fields: the mode from the TXT record, then the defaults
+    pub fn resolve(&self, txt_mode: Option<RelayMode>, default: RelayPolicy) -> RelayPolicy {
+        RelayPolicy {
+            mode: self.mode.or(txt_mode).unwrap_or(default.mode),
+            relay_paths: self.relay_paths.unwrap_or(default.relay_paths),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_modes() {
+        for mode in [RelayMode::Redirect, RelayMode::Proxy, RelayMode::HttpsRedirect, RelayMode::Deny] {
+            assert_eq!(RelayMode::parse(mode.as_str()), Some(mode));
+        }
+        assert_eq!(RelayMode::parse("Proxy"), None);
+    }
+
+    #[test]
+    fn test_override_precedence() {
+        let default = RelayPolicy {
+            mode: RelayMode::Redirect,
+            relay_paths: true,
+        };
+
+        assert_eq!(PolicyOverride::default().resolve(None, default), default);
+        assert_eq!(
+            PolicyOverride::default().resolve(Some(RelayMode::Proxy), default).mode,
+            RelayMode::Proxy
+        );
+
+        let configured = PolicyOverride {
+            mode: Some(RelayMode::Deny),
+            relay_paths: Some(false),
+        };
+        assert_eq!(
+            configured.resolve(Some(RelayMode::Proxy), default),
+            RelayPolicy {
+                mode: RelayMode::Deny,
+                relay_paths: false
+            }
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method},
+    response::Response,
+};
+use futures_util::StreamExt;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{ConfigError, Values};
+
+/// Connection pool and timeouts for connections to the gateway
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HttpClientSettings {
+    /// Keep up to this many idle connections per gateway host
+    pub pool_max_idle_per_host: usize,
+    /// Close pooled connections idle for longer than this
+    pub pool_idle_timeout: Duration,
+    /// How long to wait for the TCP connection to the gateway
+    pub connect_timeout: Duration,
+    /// How long the gateway may take to answer a proxied request
+    pub request_timeout: Duration,
+}
+
+impl Default for HttpClientSettings {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: 100,
+            pool_idle_timeout: Duration::from_secs(90),
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl HttpClientSettings {
+    /// Read GATEWAY_POOL_MAX_IDLE, GATEWAY_POOL_IDLE_TIMEOUT, GATEWAY_CONNECT_TIMEOUT
+    /// and GATEWAY_REQUEST_TIMEOUT (seconds); the timeouts must not be zero
+    pub fn from_values(values: &Values) -> Result<Self, ConfigError> {
+        let defaults = Self::default();
+
+        let timeout = |name: &str, default: Duration| match values.seconds(name)? {
+            Some(timeout) if timeout.is_zero() => Err(ConfigError::new(name, "must be at least 1 second")),
+            timeout => Ok(timeout.unwrap_or(default)),
+        };
+
+        Ok(Self {
+            pool_max_idle_per_host: values
+                .parse::<usize>("GATEWAY_POOL_MAX_IDLE")?
+                .unwrap_or(defaults.pool_max_idle_per_host),
+            pool_idle_timeout: timeout("GATEWAY_POOL_IDLE_TIMEOUT", defaults.pool_idle_timeout)?,
+            connect_timeout: timeout("GATEWAY_CONNECT_TIMEOUT", defaults.connect_timeout)?,
+            request_timeout: timeout("GATEWAY_REQUEST_TIMEOUT", defaults.request_timeout)?,
+        })
+    }
+}
+
+/// Hop-by-hop headers (RFC 9110 section 7.6.1), plus the proxy credentials meant
+/// for this hop only
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+    "proxy-authenticate",
+    "proxy-authorization",
+];
+
+/// Remove hop-by-hop headers, including every header named in `Connection`
+///
+/// `TE: trailers` survives, since trailers are relayed end to end.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    let te_trailers = headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"));
+
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+
+    if te_trailers {
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+/// Trust the Mozilla root certificates, like reqwest's rustls backend
+pub(crate) fn webpki_tls_config() -> rustls::ClientConfig {
+    let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// TCP connector for the gateway, with the connect timeout
+pub(crate) fn http_connector(connect_timeout: Duration) -> HttpConnector {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(connect_timeout));
+    http
+}
+
+/// HTTPS-only HTTP/1.1 connector for the gateway over `http`
+pub(crate) fn https_connector<H>(tls: rustls::ClientConfig, http: H) -> HttpsConnector<H> {
+    HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_only()
+        .enable_http1()
+        .wrap_connector(http)
+}
+
+/// Proxy client built directly on hyper
+///
+/// Requests and responses are relayed as-is apart from hop-by-hop headers: any
+/// method, header values byte for byte (repeated headers included), bodies
+/// streamed in both directions and trailers.
+#[derive(Clone)]
+pub struct HyperProxyClient {
+    client: Arc<Client<HttpsConnector<HttpConnector>, Body>>,
+    settings: HttpClientSettings,
+}
+
+impl HyperProxyClient {
+    /// Create a client trusting the Mozilla root certificates
+    pub fn new() -> Self {
+        Self::with_settings(webpki_tls_config(), HttpClientSettings::default())
+    }
+
+    /// Create a client with a custom TLS configuration (e.g. a private CA)
+    /// `timeout` bounds the wait for the response headers
+    pub fn with_tls_config(tls: rustls::ClientConfig, timeout: Duration) -> Self {
+        Self::with_settings(
+            tls,
+            HttpClientSettings {
+                request_timeout: timeout,
+                ..HttpClientSettings::default()
+            },
+        )
+    }
+
+    /// Create a client with a custom TLS configuration, pool and timeouts
+    pub fn with_settings(tls: rustls::ClientConfig, settings: HttpClientSettings) -> Self {
+        let client = Client::builder(TokioExecutor::new())
+            .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+            .pool_idle_timeout(settings.pool_idle_timeout)
+            .build(https_connector(tls, http_connector(settings.connect_timeout)));
+
+        Self {
+            client: Arc::new(client),
+            settings,
+        }
+    }
+
+    pub fn settings(&self) -> HttpClientSettings {
+        self.settings
+    }
+
+    /// Proxy a request to the target URL
+    pub async fn request(
+        &self,
+        target_url: &str,
+        method: &Method,
+        original_headers: &HeaderMap,
+        body: Body,
+    ) -> Result<Response, String> {
+        let mut request = Request::new(body);
+        *request.method_mut() = method.clone();
+        *request.uri_mut() = target_url
+            .parse()
+            .map_err(|e| format!("Invalid target URL {}: {}", target_url, e))?;
+
+        // Forward all headers, including Host, except hop-by-hop headers
+        let mut headers = original_headers.clone();
+        strip_hop_by_hop(&mut headers);
+        *request.headers_mut() = headers;
+
+        let response = tokio::time::timeout(self.settings.request_timeout, self.client.request(request))
+            .await
+            .map_err(|_| "Request timed out".to_string())?
+            .map_err(|e| format!("Request failed: {}", e))?;
+
+        let (mut parts, body) = response.into_parts();
+        strip_hop_by_hop(&mut parts.headers);
+        Ok(Response::from_parts(parts, Body::new(body)))
+    }
+}
+
+impl Default for HyperProxyClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Client used to proxy requests to the gateway in proxy mode
+#[derive(Clone)]
+pub enum ProxyClient {
+    /// hyper-based client with full method and header fidelity (default)
+    Hyper(HyperProxyClient),
+    /// reqwest-based client, kept as a fallback
+    Reqwest(reqwest::Client, HttpClientSettings),
+}
+
+impl ProxyClient {
+    /// Create the client named `name` ("hyper" or "reqwest", see PROXY_CLIENT)
+    pub fn new(name: &str, settings: HttpClientSettings) -> Self {
+        match name {
+            "reqwest" => ProxyClient::Reqwest(
+                reqwest::Client::builder()
+                    .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+                    .pool_idle_timeout(settings.pool_idle_timeout)
+                    .connect_timeout(settings.connect_timeout)
+                    .timeout(settings.request_timeout)
+                    .build()
+                    .expect("Failed to create HTTP client"),
+                settings,
+            ),
+            _ => ProxyClient::Hyper(HyperProxyClient::with_settings(webpki_tls_config(), settings)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProxyClient::Hyper(_) => "hyper",
+            ProxyClient::Reqwest(..) => "reqwest",
+        }
+    }
+
+    pub fn settings(&self) -> HttpClientSettings {
+        match self {
+            ProxyClient::Hyper(client) => client.settings(),
+            ProxyClient::Reqwest(_, settings) => *settings,
+        }
+    }
+
+    /// Proxy an HTTP request to the target URL
+    pub async fn request(
+        &self,
+        target_url: &str,
+        method: &Method,
+        original_headers: &HeaderMap,
+        body: Body,
+    ) -> Result<Response, String> {
+        match self {
+            ProxyClient::Hyper(client) => client.request(target_url, method, original_headers, body).await,
+            ProxyClient::Reqwest(client, _) => reqwest_request(client, target_url, method, original_headers, body).await,
+        }
+    }
+}
+
+/// Proxy an HTTP request to the target URL with reqwest
+/// This function handles the proxying with connection pooling and streaming
+async fn reqwest_request(
+    client: &reqwest::Client,
+    target_url: &str,
+    method: &Method,
+    original_headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, String> {
+    // Convert method
+    let req_method = match method.as_str() {
+        "GET" => reqwest::Method::GET,
+        "POST" => reqwest::Method::POST,
+        "PUT" => reqwest::Method::PUT,
+        "DELETE" => reqwest::Method::DELETE,
+        "HEAD" => reqwest::Method::HEAD,
+        "OPTIONS" => reqwest::Method::OPTIONS,
+        "PATCH" => reqwest::Method::PATCH,
+        _ => reqwest::Method::GET,
+    };
+
+    // Convert axum body to a stream and wrap for reqwest
+    // This avoids buffering the entire body in memory
+    let body_stream = body.into_data_stream().map(|result| {
+        result.map_err(std::io::Error::other)
+    });
+    let reqwest_body = reqwest::Body::wrap_stream(body_stream);
+
+    // Build request with method and streaming body
+    let mut request_builder = client
+        .request(req_method, target_url)
+        .body(reqwest_body);
+
+    // Forward all headers, including Host, except hop-by-hop headers
+    for (key, value) in original_headers.iter() {
+        let key_str = key.as_str().to_lowercase();
+        // Skip hop-by-hop headers (but keep host and preserve upgrade/connection for upgrade handling)
+        if key_str != "transfer-encoding"
+            && key_str != "content-length"  // Let reqwest handle content-length
+            && key_str != "te"
+            && key_str != "trailer"
+            && key_str != "proxy-connection"
+            && key_str != "keep-alive" {
+            if let Ok(val) = value.to_str() {
+                request_builder = request_builder.header(key.as_str(), val);
+            }
+        }
+    }
+
+    // Send the request
+    let response = request_builder
+        .send()
+        .await
+        .map_err(|e| format!("Request failed: {}", e))?;
+
+    // Extract status code
+    let status = response.status();
+
+    // Extract headers to forward (filtering out connection-specific headers)
+    let mut headers = HeaderMap::new();
+    for (key, value) in response.headers() {
+        let key_str = key.as_str().to_lowercase();
+        // Skip connection-specific headers
+        if key_str != "connection"
+            && key_str != "transfer-encoding"
+            && key_str != "content-encoding"
+            && key_str != "content-length" {
+            if let Ok(val) = value.to_str() {
+                if let Ok(header_value) = val.parse() {
+                    headers.insert(key.clone(), header_value);
+                }
+            }
+        }
+    }
+
+    // Convert the response body to a stream
+    // This is important for handling large responses efficiently
+    let body_stream = response.bytes_stream();
+    let body = Body::from_stream(body_stream);
+
+    // Construct the response
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    *resp.headers_mut() = headers;
+
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("app.example.com"));
+        headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive, X-Hop"));
+        headers.append(header::CONNECTION, HeaderValue::from_static("x-other-hop"));
+        headers.insert("x-hop", HeaderValue::from_static("1"));
+        headers.insert("x-other-hop", HeaderValue::from_static("1"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert(header::TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert(header::PROXY_AUTHORIZATION, HeaderValue::from_static("Basic eA=="));
+        headers.insert(header::TRAILER, HeaderValue::from_static("x-checksum"));
+        headers.append(header::SET_COOKIE, HeaderValue::from_static("a=1"));
+        headers.append(header::SET_COOKIE, HeaderValue::from_static("b=2"));
+
+        strip_hop_by_hop(&mut headers);
+
+        let mut names: Vec<&str> = headers.keys().map(HeaderName::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["host", "set-cookie", "trailer"]);
+        assert_eq!(headers.get_all(header::SET_COOKIE).iter().count(), 2);
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop_keeps_te_trailers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::TE, HeaderValue::from_static("gzip, trailers"));
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers[header::TE], "trailers");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::TE, HeaderValue::from_static("gzip"));
+        strip_hop_by_hop(&mut headers);
+        assert!(!headers.contains_key(header::TE));
+    }
+}
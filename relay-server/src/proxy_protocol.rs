@@ -0,0 +1,351 @@
+use hyper::Uri;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tower::Service;
+
+/// Signature starting every PROXY protocol v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible v1 header, CRLF included
+const V1_MAX_LENGTH: usize = 107;
+
+/// How long a new connection may take to send its PROXY header
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// PROXY protocol version used when sending headers upstream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyVersion {
+    /// Human-readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+impl ProxyVersion {
+    /// Parse "v1" or "v2"
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "v1" => Some(ProxyVersion::V1),
+            "v2" => Some(ProxyVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+/// Addresses carried by a PROXY header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProxyHeader {
+    /// The original client
+    pub source: SocketAddr,
+    /// The address the client connected to
+    pub destination: SocketAddr,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid PROXY header: {}", message))
+}
+
+/// Read a v1 or v2 PROXY header from the start of a connection
+///
+/// Only the header is consumed, so the stream is left at the first byte of the
+/// proxied connection. Returns `None` when the header carries no client address
+/// (v1 `UNKNOWN`, or a v2 `LOCAL` command such as a load balancer health check).
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<ProxyHeader>> {
+    // Both versions are at least this long, so this never reads past the header
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let mut payload = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut payload).await?;
+        parse_v2(fixed[0], fixed[1], &payload)
+    } else if prefix.starts_with(b"PROXY ") {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid("v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else {
+        Err(invalid("missing PROXY protocol signature"))
+    }
+}
+
+/// Parse a v1 header line without its CRLF
+fn parse_v1(line: &[u8]) -> io::Result<Option<ProxyHeader>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let address = |ip: &str| -> io::Result<IpAddr> {
+                let ip = if *protocol == "TCP4" {
+                    ip.parse::<Ipv4Addr>().map(IpAddr::V4)
+                } else {
+                    ip.parse::<Ipv6Addr>().map(IpAddr::V6)
+                };
+                ip.map_err(|_| invalid("bad address"))
+            };
+            let port = |port: &str| port.parse::<u16>().map_err(|_| invalid("bad port"));
+
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(address(source)?, port(source_port)?),
+                destination: SocketAddr::new(address(destination)?, port(destination_port)?),
+            }))
+        }
+        _ => Err(invalid("malformed v1 header")),
+    }
+}
+
+/// Parse the v2 version/command and family bytes and the address block
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> io::Result<Option<ProxyHeader>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection, e.g. a health check
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+
+    // The high nibble is the address family; TCP and UDP carry the same addresses
+    match family >> 4 {
+        0x1 => {
+            let block = payload.get(..12).ok_or_else(|| invalid("truncated IPv4 addresses"))?;
+            let ip = |at: usize| IpAddr::V4(Ipv4Addr::new(block[at], block[at + 1], block[at + 2], block[at + 3]));
+            let port = |at: usize| u16::from_be_bytes([block[at], block[at + 1]]);
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            }))
+        }
+        0x2 => {
+            let block = payload.get(..36).ok_or_else(|| invalid("truncated IPv6 addresses"))?;
+            let ip = |at: usize| IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&block[at..at + 16]).unwrap()));
+            let port = |at: usize| u16::from_be_bytes([block[at], block[at + 1]]);
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            }))
+        }
+        // Unspecified or Unix socket addresses: nothing usable
+        _ => Ok(None),
+    }
+}
+
+/// Encode a PROXY header; `None` announces a connection without client address
+pub fn encode_header(header: Option<&ProxyHeader>, version: ProxyVersion) -> Vec<u8> {
+    // Both addresses must share a family, so mixed pairs are sent as IPv6
+    let addresses = header.map(|header| match (header.source.ip(), header.destination.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => (header.source, header.destination),
+        (source, destination) => (
+            SocketAddr::new(IpAddr::V6(to_ipv6(source)), header.source.port()),
+            SocketAddr::new(IpAddr::V6(to_ipv6(destination)), header.destination.port()),
+        ),
+    });
+
+    match version {
+        ProxyVersion::V1 => match addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyVersion::V2 => {
+            let mut encoded = V2_SIGNATURE.to_vec();
+            match addresses {
+                Some((source, destination)) => {
+                    let mut block = Vec::with_capacity(36);
+                    match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                            encoded.extend_from_slice(&[0x21, 0x11]);
+                            block.extend_from_slice(&source_ip.octets());
+                            block.extend_from_slice(&destination_ip.octets());
+                        }
+                        (source_ip, destination_ip) => {
+                            encoded.extend_from_slice(&[0x21, 0x21]);
+                            block.extend_from_slice(&to_ipv6(source_ip).octets());
+                            block.extend_from_slice(&to_ipv6(destination_ip).octets());
+                        }
+                    }
+                    block.extend_from_slice(&source.port().to_be_bytes());
+                    block.extend_from_slice(&destination.port().to_be_bytes());
+                    encoded.extend_from_slice(&(block.len() as u16).to_be_bytes());
+                    encoded.extend_from_slice(&block);
+                }
+                None => encoded.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]),
+            }
+            encoded
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Connector writing a PROXY header on each new upstream connection, before TLS
+#[derive(Clone)]
+pub(crate) struct SendProxyHeader {
+    http: HttpConnector,
+    source: Option<SocketAddr>,
+    version: ProxyVersion,
+}
+
+impl SendProxyHeader {
+    /// Announce `source` as the client; the destination is the gateway address connected to
+    pub(crate) fn new(http: HttpConnector, source: Option<SocketAddr>, version: ProxyVersion) -> Self {
+        Self { http, source, version }
+    }
+}
+
+impl Service<Uri> for SendProxyHeader {
+    type Response = TokioIo<TcpStream>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.http.call(uri);
+        let source = self.source;
+        let version = self.version;
+
+        Box::pin(async move {
+            let mut stream = connecting.await?.into_inner();
+            let header = source.map(|source| stream.peer_addr().map(|destination| ProxyHeader { source, destination }));
+            let header = header.transpose()?;
+            stream.write_all(&encode_header(header.as_ref(), version)).await?;
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(bytes: &[u8]) -> (io::Result<Option<ProxyHeader>>, Vec<u8>) {
+        let mut stream = bytes;
+        let header = read_header(&mut stream).await;
+        (header, stream.to_vec())
+    }
+
+    fn header(source: &str, destination: &str) -> ProxyHeader {
+        ProxyHeader {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_header() {
+        let (parsed, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 80\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(parsed.unwrap(), Some(header("203.0.113.7:51000", "10.0.0.1:80")));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (parsed, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51000 80\r\n").await;
+        assert_eq!(parsed.unwrap(), Some(header("[2001:db8::7]:51000", "[2001:db8::1]:80")));
+
+        let (parsed, rest) = read(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(parsed.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn test_read_rejects_invalid_headers() {
+        for bytes in [
+            &b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"[..],
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51000\r\n",
+            b"PROXY TCP4 2001:db8::7 10.0.0.1 51000 80\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 99999\r\n",
+        ] {
+            let (parsed, _) = read(bytes).await;
+            assert_eq!(parsed.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut too_long = b"PROXY TCP6 ".to_vec();
+        too_long.extend_from_slice(&[b'1'; 120]);
+        let (parsed, _) = read(&too_long).await;
+        assert_eq!(parsed.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_v2_round_trip() {
+        for (source, destination) in [
+            ("203.0.113.7:51000", "10.0.0.1:80"),
+            ("[2001:db8::7]:51000", "[2001:db8::1]:80"),
+        ] {
+            let sent = header(source, destination);
+            let mut bytes = encode_header(Some(&sent), ProxyVersion::V2);
+            bytes.extend_from_slice(b"GET");
+
+            let (parsed, rest) = read(&bytes).await;
+            assert_eq!(parsed.unwrap(), Some(sent));
+            assert_eq!(rest, b"GET");
+        }
+
+        let (parsed, rest) = read(&encode_header(None, ProxyVersion::V2)).await;
+        assert_eq!(parsed.unwrap(), None);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_v2_skips_tlvs_and_ignores_unix_addresses() {
+        let mut bytes = V2_SIGNATURE.to_vec();
+        bytes.extend_from_slice(&[0x21, 0x11, 0x00, 0x10]);
+        bytes.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1, 0xc7, 0x38, 0x00, 0x50]);
+        bytes.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]); // NOOP TLV
+        bytes.extend_from_slice(b"GET");
+        let (parsed, rest) = read(&bytes).await;
+        assert_eq!(parsed.unwrap(), Some(header("203.0.113.7:51000", "10.0.0.1:80")));
+        assert_eq!(rest, b"GET");
+
+        let mut bytes = V2_SIGNATURE.to_vec();
+        bytes.extend_from_slice(&[0x21, 0x31, 0x00, 0x00]);
+        let (parsed, _) = read(&bytes).await;
+        assert_eq!(parsed.unwrap(), None);
+    }
+
+    #[test]
+    fn test_encode_v1_and_mixed_families() {
+        let sent = header("203.0.113.7:51000", "10.0.0.1:80");
+        assert_eq!(
+            encode_header(Some(&sent), ProxyVersion::V1),
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 80\r\n"
+        );
+        assert_eq!(encode_header(None, ProxyVersion::V1), b"PROXY UNKNOWN\r\n");
+
+        let mixed = header("203.0.113.7:51000", "[2001:db8::1]:443");
+        assert_eq!(
+            encode_header(Some(&mixed), ProxyVersion::V1),
+            b"PROXY TCP6 ::ffff:203.0.113.7 2001:db8::1 51000 443\r\n"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+use crate::config::{Args, Config, Values, SETTINGS};
+use crate::dns::DnsResolver;
+use crate::{metrics, AppState};
+
+/// Settings the gateway registry is built from (see `GatewayRegistry::from_values`)
+const GATEWAY_SETTINGS: &[&str] = &[
+    "GATEWAYS_FILE",
+    "ALLOWED_DOMAIN_REGEX",
+    "GATEWAY_DOMAIN_CAPTURE_GROUP",
+    "TARGET_HOST_TEMPLATE",
+    "TARGET_DEFAULT_PORT",
+];
+
+/// The other settings the DNS resolver is built from
+const RESOLVER_SETTINGS: &[&str] = &[
+    "FALLBACK_GATEWAY_DOMAIN",
+    "CNAME_MAX_DEPTH",
+    "DNS_SERVERS",
+    "DNS_USE_SYSTEM_CONFIG",
+    "DNS_TIMEOUT",
+    "DNS_ATTEMPTS",
+    "DNSSEC_REQUIRED_ZONES",
+    "DNS_CACHE_MIN_TTL",
+    "DNS_CACHE_MAX_TTL",
+    "DNS_CACHE_MAX_ENTRIES",
+    "DNS_NEGATIVE_CACHE_TTL",
+    "DOMAIN_MAP_FILE",
+    "DOMAIN_MAP_RELOAD_INTERVAL",
+];
+
+/// Modification time and size, to notice when the config file changes
+type FileStamp = (Option<SystemTime>, u64);
+
+fn stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok(), metadata.len()))
+}
+
+fn gateways_file_stamp(values: &Values) -> Option<FileStamp> {
+    values.var("GATEWAYS_FILE").and_then(|path| stamp(Path::new(&path)))
+}
+
+/// Rebuilds the relay state on SIGHUP and when the config file changes
+///
+/// The config file, environment and command line flags are read again, then the
+/// relay policy is rebuilt and swapped in. The DNS resolver (DNS settings,
+/// domain mapping file) is only rebuilt when one of its settings changed, and
+/// the gateways only when their settings or file did, so the DNS cache and
+/// gateway health survive unrelated reloads. A configuration that doesn't load
+/// is logged and ignored: the previous one keeps running. Listener and HTTP
+/// client settings only change on restart.
+pub struct Reloader {
+    args: Args,
+    config_file: Option<PathBuf>,
+    /// Settings the process was started with, to spot changes needing a restart
+    started_with: Values,
+    /// Settings and gateways file the current resolver was built from
+    applied: Mutex<(Values, Option<FileStamp>)>,
+    state: AppState,
+    stamp: Mutex<Option<FileStamp>>,
+}
+
+impl Reloader {
+    /// `values` are the settings `state` was built from
+    pub fn new(args: Args, values: Values, state: AppState) -> Self {
+        let config_file = args.config_file();
+        let stamp = config_file.as_deref().and_then(stamp);
+        let gateways_file = gateways_file_stamp(&values);
+        Self {
+            args,
+            config_file,
+            started_with: values.clone(),
+            applied: Mutex::new((values, gateways_file)),
+            state,
+            stamp: Mutex::new(stamp),
+        }
+    }
+
+    /// Reload the configuration now; on error the current one stays in effect
+    pub fn reload(&self) -> Result<(), String> {
+        // The file as read now counts as seen, so the watcher doesn't reload it again
+        self.config_file_changed();
+
+        let result = self.rebuild();
+        match result {
+            Ok(()) => {
+                info!("Configuration reloaded");
+                metrics::inc_config_reloads("success");
+            }
+            Err(ref e) => {
+                error!("Failed to reload the configuration, keeping the previous one: {}", e);
+                metrics::inc_config_reloads("failure");
+            }
+        }
+        result
+    }
+
+    fn rebuild(&self) -> Result<(), String> {
+        let values = Values::load(&self.args).map_err(|e| e.to_string())?;
+        let mut config = Config::from_values(&values).map_err(|e| e.to_string())?;
+        // Held until the new state is stored, so overlapping reloads don't undo each other
+        let mut applied = self.applied.lock().unwrap();
+        let current = self.state.load();
+        let changed = |names: &[&str]| names.iter().any(|name| values.var(name) != applied.0.var(name));
+        let gateways_file = gateways_file_stamp(&values);
+        let gateways_changed = changed(GATEWAY_SETTINGS) || gateways_file != applied.1;
+
+        let dns_resolver = if gateways_changed || changed(RESOLVER_SETTINGS) {
+            if !gateways_changed {
+                // Keep the gateways, with their health
+                config.dns.gateways = current.dns_resolver.gateways().clone();
+            }
+            let dns_resolver = Arc::new(DnsResolver::new(&config).map_err(|e| e.to_string())?);
+            dns_resolver.watch_domain_map();
+            dns_resolver
+        } else {
+            // The mapping file is watched already; a reload picks up changes right away
+            if let Some(domain_map) = current.dns_resolver.domain_map() {
+                if domain_map.reload_if_changed().map_err(|e| e.to_string())? {
+                    current.dns_resolver.clear_cache();
+                }
+            }
+            current.dns_resolver.clone()
+        };
+
+        for setting in SETTINGS.iter().filter(|setting| !setting.reloadable) {
+            if values.var(setting.name) != self.started_with.var(setting.name) {
+                warn!("{} changed, it only takes effect after a restart", setting.name);
+            }
+        }
+
+        self.state.store(current.reconfigure(&config, dns_resolver));
+        *applied = (values, gateways_file);
+        Ok(())
+    }
+
+    /// Whether the config file changed since it was last looked at
+    fn config_file_changed(&self) -> bool {
+        let Some(ref path) = self.config_file else {
+            return false;
+        };
+        let stamp = stamp(path);
+        std::mem::replace(&mut *self.stamp.lock().unwrap(), stamp) != stamp
+    }
+
+    /// Reload on SIGHUP, and when the config file changes (checked every `interval`, zero disables)
+    pub fn watch(self: Arc<Self>, interval: Duration) {
+        let reloader = self.clone();
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    error!("Failed to listen for SIGHUP, reloading is disabled: {}", e);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading the configuration");
+                let _ = reloader.reload();
+            }
+        });
+
+        let Some(path) = self.config_file.clone() else {
+            return;
+        };
+        if interval.is_zero() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                if self.config_file_changed() {
+                    info!("{} changed, reloading the configuration", path.display());
+                    let _ = self.reload();
+                }
+            }
+        });
+    }
+}
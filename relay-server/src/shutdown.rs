@@ -0,0 +1,278 @@
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use http_body::{Frame, SizeHint};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+/// What a drain waits for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActivityKind {
+    /// An HTTP request, until its response body is sent
+    Request,
+    /// A WebSocket or TLS passthrough tunnel
+    Tunnel,
+}
+
+impl fmt::Display for ActivityKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActivityKind::Request => write!(f, "request"),
+            ActivityKind::Tunnel => write!(f, "tunnel"),
+        }
+    }
+}
+
+struct Activity {
+    kind: ActivityKind,
+    description: String,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    draining: AtomicBool,
+    /// Set once `drain` has returned
+    finished: AtomicBool,
+    next_id: AtomicU64,
+    active: Mutex<BTreeMap<u64, Activity>>,
+    /// Woken when draining starts or finishes and when an activity ends
+    changed: Notify,
+}
+
+/// Graceful shutdown: stop accepting, then let requests and tunnels finish
+///
+/// Relay listeners stop accepting once draining starts; admin listeners keep
+/// answering until `drain` returns, so `/health` can report it. `drain` waits
+/// for the tracked requests and tunnels up to a deadline and returns those
+/// still running, which are cut off when the process exits.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting connections; returns false if draining had already started
+    pub fn start_draining(&self) -> bool {
+        let first = !self.inner.draining.swap(true, Ordering::SeqCst);
+        self.inner.changed.notify_waiters();
+        first
+    }
+
+    /// Resolves once draining starts
+    pub async fn draining(&self) {
+        self.wait_until(|inner| inner.draining.load(Ordering::SeqCst)).await
+    }
+
+    /// Resolves once `drain` has returned
+    pub async fn finished(&self) {
+        self.wait_until(|inner| inner.finished.load(Ordering::SeqCst)).await
+    }
+
+    /// Count a request or tunnel as active until the returned guard is dropped
+    pub fn track(&self, kind: ActivityKind, description: impl Into<String>) -> ActivityGuard {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let activity = Activity {
+            kind,
+            description: description.into(),
+            started: Instant::now(),
+        };
+        self.inner.active.lock().unwrap().insert(id, activity);
+        ActivityGuard {
+            shutdown: self.clone(),
+            id,
+        }
+    }
+
+    /// Number of requests and tunnels still running
+    pub fn active(&self) -> usize {
+        self.inner.active.lock().unwrap().len()
+    }
+
+    /// Wait up to `timeout` for every request and tunnel to finish
+    ///
+    /// Returns a description of each one still running at the deadline.
+    pub async fn drain(&self, timeout: Duration) -> Vec<String> {
+        let idle = self.wait_until(|inner| inner.active.lock().unwrap().is_empty());
+        let _ = tokio::time::timeout(timeout, idle).await;
+
+        let cut_off = self
+            .inner
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|activity| {
+                format!(
+                    "{} {} (running for {:.1}s)",
+                    activity.kind,
+                    activity.description,
+                    activity.started.elapsed().as_secs_f64()
+                )
+            })
+            .collect();
+        self.inner.finished.store(true, Ordering::SeqCst);
+        self.inner.changed.notify_waiters();
+        cut_off
+    }
+
+    /// Start draining on SIGTERM or SIGINT; a second signal exits right away
+    pub fn listen_for_signals(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            match wait_for_signal().await {
+                Ok(name) => info!("Received {}, draining connections", name),
+                Err(e) => {
+                    error!("Failed to listen for SIGTERM/SIGINT, graceful shutdown is disabled: {}", e);
+                    return;
+                }
+            }
+            shutdown.start_draining();
+
+            if let Ok(name) = wait_for_signal().await {
+                warn!(
+                    "Received {} while draining, exiting with {} connections still active",
+                    name,
+                    shutdown.active()
+                );
+                std::process::exit(1);
+            }
+        });
+    }
+
+    async fn wait_until(&self, done: impl Fn(&Inner) -> bool) {
+        loop {
+            let changed = self.inner.changed.notified();
+            tokio::pin!(changed);
+            // Register before checking, so a change in between isn't missed
+            changed.as_mut().enable();
+            if done(&self.inner) {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+/// Keeps a request or tunnel counted as active; see `Shutdown::track`
+pub struct ActivityGuard {
+    shutdown: Shutdown,
+    id: u64,
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        let inner = &self.shutdown.inner;
+        inner.active.lock().unwrap().remove(&self.id);
+        inner.changed.notify_waiters();
+    }
+}
+
+async fn wait_for_signal() -> io::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    })
+}
+
+/// Middleware counting each request as active until its response body is sent
+///
+/// The `Shutdown` is also added to the request extensions, so tunnels started
+/// by the request can be tracked too.
+pub async fn track_requests(State(shutdown): State<Shutdown>, mut req: Request, next: Next) -> Response {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("-");
+    let description = format!("{} {}{}", req.method(), host, req.uri().path());
+    let activity = shutdown.track(ActivityKind::Request, description);
+    req.extensions_mut().insert(shutdown);
+
+    next.run(req)
+        .await
+        .map(|body| Body::new(TrackedBody { body, _activity: activity }))
+}
+
+/// Response body holding its request's activity guard until it's dropped
+struct TrackedBody {
+    body: Body,
+    _activity: ActivityGuard,
+}
+
+impl http_body::Body for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_activities() {
+        let shutdown = Shutdown::new();
+        let request = shutdown.track(ActivityKind::Request, "GET example.com/");
+        assert_eq!(shutdown.active(), 1);
+
+        assert!(shutdown.start_draining());
+        assert!(!shutdown.start_draining());
+        shutdown.draining().await;
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(request);
+        });
+        let cut_off = shutdown.drain(Duration::from_secs(10)).await;
+        assert!(cut_off.is_empty());
+        assert_eq!(shutdown.active(), 0);
+        shutdown.finished().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_reports_what_is_cut_off() {
+        let shutdown = Shutdown::new();
+        let _tunnel = shutdown.track(ActivityKind::Tunnel, "wss://app.example.com/ws");
+        drop(shutdown.track(ActivityKind::Request, "GET example.com/"));
+
+        let cut_off = shutdown.drain(Duration::from_secs(5)).await;
+        assert_eq!(cut_off.len(), 1);
+        assert!(cut_off[0].starts_with("tunnel wss://app.example.com/ws (running for"), "{}", cut_off[0]);
+    }
+}
@@ -0,0 +1,410 @@
+use axum::http::uri::Authority;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, warn};
+
+use crate::hostname::{normalize_host, RequestHost};
+use crate::metrics;
+use crate::policy::RelayMode;
+use crate::proxy::HttpClientSettings;
+use crate::proxy_protocol::{self, encode_header, ProxyHeader, ProxyVersion};
+use crate::shutdown::ActivityKind;
+use crate::tunnel::pipe;
+use crate::AppState;
+
+/// How long a client may take to send its ClientHello
+pub const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Port the gateway accepts TLS on when the target host doesn't name one
+const DEFAULT_TLS_PORT: u16 = 443;
+
+/// Largest ClientHello accepted, like the TLS limit on a handshake record (2^14)
+const MAX_CLIENT_HELLO: usize = 16 * 1024;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_HOST_NAME: u8 = 0x00;
+
+/// Outcome of parsing the first bytes of a TLS connection
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientHello {
+    /// More bytes are needed
+    Incomplete,
+    /// A ClientHello with this server name
+    ServerName(String),
+    /// A ClientHello without the server name extension
+    NoServerName,
+    /// Not a TLS ClientHello
+    Invalid,
+}
+
+/// Find the SNI host name in the ClientHello at the start of `data`
+///
+/// The ClientHello may span several handshake records; only the handshake
+/// message itself is parsed, the bytes are relayed untouched.
+pub fn parse_client_hello(data: &[u8]) -> ClientHello {
+    // Reassemble the handshake message from its records
+    let mut message = Vec::new();
+    let mut records = data;
+    loop {
+        if message.len() >= 4 {
+            let length = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if message[0] != HANDSHAKE_CLIENT_HELLO || length > MAX_CLIENT_HELLO {
+                return ClientHello::Invalid;
+            }
+            if message.len() >= 4 + length {
+                return parse_client_hello_body(&message[4..4 + length]).unwrap_or(ClientHello::Invalid);
+            }
+        }
+
+        if records.len() < 5 {
+            return ClientHello::Incomplete;
+        }
+        if records[0] != CONTENT_TYPE_HANDSHAKE || records[1] != 0x03 {
+            return ClientHello::Invalid;
+        }
+        let length = u16::from_be_bytes([records[3], records[4]]) as usize;
+        if length == 0 || length > MAX_CLIENT_HELLO {
+            return ClientHello::Invalid;
+        }
+        if records.len() < 5 + length {
+            return ClientHello::Incomplete;
+        }
+        message.extend_from_slice(&records[5..5 + length]);
+        records = &records[5 + length..];
+    }
+}
+
+/// Cursor over a length-prefixed TLS structure; `None` means truncated
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()? as usize;
+        self.take(n)
+    }
+
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let n = self.u16()? as usize;
+        self.take(n).map(Reader)
+    }
+}
+
+fn parse_client_hello_body(body: &[u8]) -> Option<ClientHello> {
+    let mut hello = Reader(body);
+    hello.take(2 + 32)?; // legacy_version, random
+    hello.vec8()?; // legacy_session_id
+    hello.vec16()?; // cipher_suites
+    hello.vec8()?; // legacy_compression_methods
+
+    // No extensions at all (very old clients)
+    if hello.0.is_empty() {
+        return Some(ClientHello::NoServerName);
+    }
+
+    let mut extensions = hello.vec16()?;
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut extension = extensions.vec16()?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = extension.vec16()?;
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == SERVER_NAME_HOST_NAME {
+                let name = std::str::from_utf8(name.0).ok()?;
+                return Some(ClientHello::ServerName(name.to_string()));
+            }
+        }
+        return Some(ClientHello::NoServerName);
+    }
+
+    Some(ClientHello::NoServerName)
+}
+
+/// Relays TLS connections to the dstack gateway by SNI, without terminating TLS
+///
+/// The ClientHello is read to learn the custom domain, which is resolved like
+/// HTTP requests are (domain mapping file, then TXT and CNAME records). The
+/// bytes read so far are replayed to the gateway and the connection is spliced
+/// through, so the gateway still sees the client's own handshake.
+#[derive(Clone)]
+pub struct TlsPassthrough {
+    /// Domains are resolved with the current state, so reloads apply here too
+    state: AppState,
+    idle_timeout: Duration,
+    connect_timeout: Duration,
+    accept_proxy_protocol: bool,
+    send_proxy_protocol: Option<ProxyVersion>,
+}
+
+impl TlsPassthrough {
+    /// `idle_timeout` closes connections without traffic (zero disables it)
+    pub fn new(state: impl Into<AppState>, idle_timeout: Duration) -> Self {
+        Self {
+            state: state.into(),
+            idle_timeout,
+            connect_timeout: HttpClientSettings::default().connect_timeout,
+            accept_proxy_protocol: false,
+            send_proxy_protocol: None,
+        }
+    }
+
+    /// How long to wait for the TCP connection to each candidate gateway host
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Expect a PROXY header on each accepted connection and/or send one to the gateway
+    pub fn with_proxy_protocol(mut self, accept: bool, send: Option<ProxyVersion>) -> Self {
+        self.accept_proxy_protocol = accept;
+        self.send_proxy_protocol = send;
+        self
+    }
+
+    /// Accept and relay connections until draining starts on shutdown
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let passthrough = Arc::new(self);
+        let shutdown = passthrough.state.shutdown().clone();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.draining() => return Ok(()),
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept TLS connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let passthrough = passthrough.clone();
+            tokio::spawn(async move {
+                let status = passthrough.relay(stream, peer).await;
+                metrics::inc_tls_passthrough_connections(status);
+            });
+        }
+    }
+
+    /// Relay one connection, returning its metric status
+    async fn relay(&self, mut stream: TcpStream, peer: SocketAddr) -> &'static str {
+        let deadline = tokio::time::sleep(CLIENT_HELLO_TIMEOUT);
+        tokio::pin!(deadline);
+
+        // Dual-stack listeners see IPv4 clients as IPv4-mapped IPv6 addresses
+        let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        let mut client = canonical(peer);
+        let mut destination = stream.local_addr().ok().map(canonical);
+        if self.accept_proxy_protocol {
+            tokio::select! {
+                header = proxy_protocol::read_header(&mut stream) => match header {
+                    Ok(Some(header)) => {
+                        client = header.source;
+                        destination = Some(header.destination);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("Closing TLS connection from {}: {}", peer, e);
+                        return "invalid";
+                    }
+                },
+                _ = &mut deadline => return "timeout",
+            }
+        }
+
+        // Read until the whole ClientHello is in
+        let mut hello = Vec::with_capacity(1024);
+        let server_name = loop {
+            match parse_client_hello(&hello) {
+                ClientHello::Incomplete => {}
+                ClientHello::ServerName(name) => break name,
+                ClientHello::NoServerName => {
+                    debug!("Closing TLS connection from {}: no SNI", client);
+                    return "no_sni";
+                }
+                ClientHello::Invalid => {
+                    debug!("Closing connection from {}: not a TLS ClientHello", client);
+                    return "invalid";
+                }
+            }
+
+            let read = tokio::select! {
+                read = stream.read_buf(&mut hello) => read,
+                _ = &mut deadline => return "timeout",
+            };
+            match read {
+                Ok(0) | Err(_) => return "invalid",
+                Ok(_) => {}
+            }
+        };
+
+        let domain = match normalize_host(&server_name) {
+            Ok(RequestHost::Domain(domain)) => domain,
+            _ => {
+                debug!("Closing TLS connection from {}: bad SNI {:?}", client, server_name);
+                return "invalid";
+            }
+        };
+
+        let state = self.state.load();
+        // Passthrough would relay more than challenges for any resolvable domain
+        if state.challenge_only.is_some() {
+            info!("Closing TLS connection from {} for {}: only ACME challenges are relayed", client, domain);
+            return "challenge_only";
+        }
+
+        // Same per-domain policy as HTTP: a denied domain gets no HTTPS either
+        let policy = crate::relay_policy(&state, &domain).await;
+        if policy.mode == RelayMode::Deny || !policy.relay_paths {
+            info!("Closing TLS connection from {} for {}: relaying is disabled for this domain", client, domain);
+            return "denied";
+        }
+
+        let hosts = match state.dns_resolver.resolve_app_hosts(&domain).await {
+            Ok(hosts) => hosts,
+            Err(e) => {
+                info!("Closing TLS connection from {} for {}: {}", client, domain, e);
+                return "unknown_domain";
+            }
+        };
+
+        let Some((target, mut upstream)) = connect_first(&hosts, self.connect_timeout).await else {
+            return "connect_failed";
+        };
+
+        if let Some(version) = self.send_proxy_protocol {
+            let header = destination.map(|destination| ProxyHeader { source: client, destination });
+            if upstream.write_all(&encode_header(header.as_ref(), version)).await.is_err() {
+                return "connect_failed";
+            }
+        }
+        if upstream.write_all(&hello).await.is_err() {
+            return "connect_failed";
+        }
+
+        info!("Passing TLS for {} from {} through to {}", domain, client, target);
+        let start = Instant::now();
+        let _activity = self
+            .state
+            .shutdown()
+            .track(ActivityKind::Tunnel, format!("TLS passthrough for {} from {}", domain, client));
+        metrics::inc_tunnels_active();
+        let (stats, end) = pipe(stream, upstream, self.idle_timeout).await;
+        metrics::dec_tunnels_active();
+        info!(
+            "TLS passthrough for {} closed ({:?}) after {:.1}s: {} bytes sent, {} bytes received",
+            domain,
+            end,
+            start.elapsed().as_secs_f64(),
+            stats.client_to_upstream + hello.len() as u64,
+            stats.upstream_to_client
+        );
+        "relayed"
+    }
+}
+
+/// Connect to the first candidate host that accepts a TCP connection
+async fn connect_first(hosts: &[String], connect_timeout: Duration) -> Option<(String, TcpStream)> {
+    for host in hosts {
+        let Ok(authority) = host.parse::<Authority>() else {
+            warn!("Invalid gateway host {}", host);
+            continue;
+        };
+        let target = (authority.host(), authority.port_u16().unwrap_or(DEFAULT_TLS_PORT));
+
+        match tokio::time::timeout(connect_timeout, TcpStream::connect(target)).await {
+            Ok(Ok(stream)) => return Some((format!("{}:{}", target.0, target.1), stream)),
+            Ok(Err(e)) => warn!("Failed to connect to {}, trying next candidate: {}", host, e),
+            Err(_) => warn!("Timed out connecting to {}, trying next candidate", host),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first flight a rustls client sends for `server_name`
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let config = crate::proxy::webpki_tls_config();
+        let name = rustls::pki_types::ServerName::try_from(server_name.to_string()).unwrap();
+        let mut connection = rustls::ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut hello = Vec::new();
+        connection.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn test_parse_client_hello_server_name() {
+        let hello = client_hello("app.example.com");
+        assert_eq!(
+            parse_client_hello(&hello),
+            ClientHello::ServerName("app.example.com".to_string())
+        );
+
+        // Any prefix is incomplete, so reads can stop as soon as the name is known
+        for end in [0, 3, 5, 40, hello.len() - 1] {
+            assert_eq!(parse_client_hello(&hello[..end]), ClientHello::Incomplete, "{}", end);
+        }
+    }
+
+    #[test]
+    fn test_parse_client_hello_without_server_name() {
+        // rustls sends no SNI for IP addresses
+        let hello = client_hello("203.0.113.7");
+        assert_eq!(parse_client_hello(&hello), ClientHello::NoServerName);
+    }
+
+    #[test]
+    fn test_parse_client_hello_split_across_records() {
+        let hello = client_hello("app.example.com");
+        let message = &hello[5..];
+        let (first, second) = message.split_at(100);
+
+        let mut split = Vec::new();
+        for fragment in [first, second] {
+            split.extend_from_slice(&[0x16, 0x03, 0x01]);
+            split.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            split.extend_from_slice(fragment);
+        }
+        assert_eq!(
+            parse_client_hello(&split),
+            ClientHello::ServerName("app.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_client_hello_rejects_other_protocols() {
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n\r\n"), ClientHello::Invalid);
+        assert_eq!(parse_client_hello(b"\x16\x03\x01\x00\x05\x02\x00\x00\x01\x00"), ClientHello::Invalid);
+    }
+}
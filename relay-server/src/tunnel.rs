@@ -0,0 +1,410 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use hyper::upgrade::OnUpgrade;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::dns::AppTarget;
+use crate::metrics;
+use crate::proxy::{http_connector, https_connector, strip_hop_by_hop, webpki_tls_config, HttpClientSettings};
+use crate::proxy_protocol::{ProxyVersion, SendProxyHeader};
+use crate::shutdown::{ActivityKind, Shutdown};
+
+/// Size of the copy buffer for each direction
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Relays HTTP Upgrade requests (WebSocket and friends) to the dstack gateway
+///
+/// The upgrade request is sent over a dedicated HTTP/1.1 connection; once the
+/// gateway answers `101 Switching Protocols`, the inbound and upstream
+/// connections are upgraded and bytes are piped between them until either side
+/// closes or the tunnel is idle for `idle_timeout`.
+#[derive(Clone)]
+pub struct TunnelClient {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    tls: Arc<rustls::ClientConfig>,
+    idle_timeout: Duration,
+    http: HttpClientSettings,
+    proxy_protocol: Option<ProxyVersion>,
+}
+
+impl TunnelClient {
+    /// Create a tunnel client trusting the Mozilla root certificates, like the proxy client
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self::with_tls_config(webpki_tls_config(), idle_timeout)
+    }
+
+    /// Create a tunnel client with a custom TLS configuration (e.g. a private CA)
+    pub fn with_tls_config(tls: rustls::ClientConfig, idle_timeout: Duration) -> Self {
+        let http = HttpClientSettings::default();
+        Self {
+            client: Client::builder(TokioExecutor::new())
+                .build(https_connector(tls.clone(), http_connector(http.connect_timeout))),
+            tls: Arc::new(tls),
+            idle_timeout,
+            http,
+            proxy_protocol: None,
+        }
+    }
+
+    /// Use the connect and request timeouts of `http` (tunnels aren't pooled)
+    pub fn with_http_settings(mut self, http: HttpClientSettings) -> Self {
+        self.client = Client::builder(TokioExecutor::new())
+            .build(https_connector(self.tls.as_ref().clone(), http_connector(http.connect_timeout)));
+        self.http = http;
+        self
+    }
+
+    /// Send a PROXY header with the client address on each upstream connection
+    pub fn with_proxy_protocol(mut self, version: Option<ProxyVersion>) -> Self {
+        self.proxy_protocol = version;
+        self
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn proxy_protocol(&self) -> Option<ProxyVersion> {
+        self.proxy_protocol
+    }
+
+    /// A client whose one connection starts with a PROXY header for `source`
+    ///
+    /// The header describes a single client, so these connections aren't pooled.
+    fn proxied_client(
+        &self,
+        source: Option<SocketAddr>,
+        version: ProxyVersion,
+        tls: &rustls::ClientConfig,
+    ) -> Client<HttpsConnector<SendProxyHeader>, Body> {
+        let connector = SendProxyHeader::new(http_connector(self.http.connect_timeout), source, version);
+        Client::builder(TokioExecutor::new())
+            .pool_max_idle_per_host(0)
+            .build(https_connector(tls.clone(), connector))
+    }
+
+    /// Send an upgrade request to the first candidate URL that answers
+    ///
+    /// Returns the gateway's response: on `101 Switching Protocols` a tunnel task is
+    /// started in the background, any other response is passed through as-is.
+    /// Gateways with their own TLS settings get a dedicated connection using them.
+    pub async fn tunnel(&self, targets: &[AppTarget], mut req: Request) -> Result<Response, String> {
+        let inbound = req
+            .extensions_mut()
+            .remove::<OnUpgrade>()
+            .ok_or_else(|| "Inbound connection can't be upgraded".to_string())?;
+        let source = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        let shutdown = req.extensions().get::<Shutdown>().cloned();
+        let (parts, _body) = req.into_parts();
+        let headers = upstream_headers(&parts.headers);
+
+        let mut last_error = String::new();
+        for target in targets {
+            let target_url = &target.url;
+            info!("Tunneling upgrade request to: {}", target_url);
+
+            let mut request = Request::new(Body::empty());
+            *request.method_mut() = parts.method.clone();
+            *request.uri_mut() = target_url.parse().map_err(|e| format!("Invalid target URL {}: {}", target_url, e))?;
+            *request.headers_mut() = headers.clone();
+
+            let tls = target.gateway.tls_config().unwrap_or(&self.tls);
+            let pending = match (self.proxy_protocol, target.gateway.tls_config()) {
+                (Some(version), _) => self.proxied_client(source, version, tls).request(request),
+                (None, Some(tls)) => Client::builder(TokioExecutor::new())
+                    .build(https_connector(tls.as_ref().clone(), http_connector(self.http.connect_timeout)))
+                    .request(request),
+                (None, None) => self.client.request(request),
+            };
+
+            let mut response = match tokio::time::timeout(self.http.request_timeout, pending).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(e)) => {
+                    warn!("Failed to tunnel to {}, trying next candidate: {}", target_url, e);
+                    target.gateway.record_failure();
+                    last_error = format!("Request failed: {}", e);
+                    continue;
+                }
+                Err(_) => {
+                    warn!("Timed out tunneling to {}, trying next candidate", target_url);
+                    target.gateway.record_failure();
+                    last_error = "Request timed out".to_string();
+                    continue;
+                }
+            };
+            target.gateway.record_success();
+
+            if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+                info!("Gateway declined upgrade for {} with {}", target_url, response.status());
+                let (mut parts, body) = response.into_parts();
+                strip_hop_by_hop(&mut parts.headers);
+                return Ok(Response::from_parts(parts, Body::new(body)));
+            }
+
+            let upstream = hyper::upgrade::on(&mut response);
+            let target_url = target_url.clone();
+            let idle_timeout = self.idle_timeout;
+            // A drain on shutdown waits for the tunnel too
+            let activity = shutdown.map(|shutdown| shutdown.track(ActivityKind::Tunnel, target_url.clone()));
+            tokio::spawn(async move {
+                let _activity = activity;
+                match tokio::try_join!(inbound, upstream) {
+                    Ok((inbound, upstream)) => {
+                        run_tunnel(&target_url, TokioIo::new(inbound), TokioIo::new(upstream), idle_timeout).await
+                    }
+                    Err(e) => warn!("Failed to upgrade connections for {}: {}", target_url, e),
+                }
+            });
+
+            let (response_parts, _) = response.into_parts();
+            return Ok(Response::from_parts(response_parts, Body::empty()));
+        }
+
+        Err(last_error)
+    }
+}
+
+/// Copy the client's headers for the upstream request, keeping the upgrade intent
+fn upstream_headers(original: &HeaderMap) -> HeaderMap {
+    let mut headers = original.clone();
+    strip_hop_by_hop(&mut headers);
+
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    for protocol in original.get_all(header::UPGRADE) {
+        headers.append(header::UPGRADE, protocol.clone());
+    }
+    headers
+}
+
+/// Pipe an established tunnel and record its outcome
+async fn run_tunnel<C, U>(target_url: &str, client: C, upstream: U, idle_timeout: Duration)
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = Instant::now();
+    metrics::inc_tunnels_active();
+    let (stats, end) = pipe(client, upstream, idle_timeout).await;
+    metrics::dec_tunnels_active();
+    metrics::inc_tunnels(end.metric_status());
+
+    info!(
+        "Tunnel to {} closed ({:?}) after {:.1}s: {} bytes sent, {} bytes received",
+        target_url,
+        end,
+        start.elapsed().as_secs_f64(),
+        stats.client_to_upstream,
+        stats.upstream_to_client
+    );
+}
+
+/// Bytes copied through a tunnel in each direction
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TunnelStats {
+    pub client_to_upstream: u64,
+    pub upstream_to_client: u64,
+}
+
+/// Why a tunnel ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunnelEnd {
+    /// Both sides closed their half of the connection
+    Closed,
+    /// No bytes moved in either direction for the idle timeout
+    IdleTimeout,
+    /// Reading or writing failed
+    Error(io::ErrorKind),
+}
+
+impl TunnelEnd {
+    fn metric_status(&self) -> &'static str {
+        match self {
+            TunnelEnd::Closed => "closed",
+            TunnelEnd::IdleTimeout => "idle_timeout",
+            TunnelEnd::Error(_) => "error",
+        }
+    }
+}
+
+/// Copy bytes in both directions until both sides are closed
+///
+/// A side reaching EOF shuts down writing on the other, so half-closed
+/// connections keep working. `idle_timeout` applies to waiting for data and to
+/// handing it to a side that stops reading; zero disables it.
+pub async fn pipe<C, U>(client: C, upstream: U, idle_timeout: Duration) -> (TunnelStats, TunnelEnd)
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    let mut client_buf = vec![0u8; BUFFER_SIZE];
+    let mut upstream_buf = vec![0u8; BUFFER_SIZE];
+    let mut client_open = true;
+    let mut upstream_open = true;
+    let mut stats = TunnelStats::default();
+
+    while client_open || upstream_open {
+        let idle = async {
+            if idle_timeout.is_zero() {
+                std::future::pending::<()>().await
+            } else {
+                tokio::time::sleep(idle_timeout).await
+            }
+        };
+
+        // Reads are cancel-safe, so losing the race to the other direction drops nothing
+        let result = tokio::select! {
+            read = client_read.read(&mut client_buf), if client_open => {
+                copy_chunk(read, &client_buf, &mut upstream_write, &mut client_open, idle_timeout).await.map(|n| {
+                    stats.client_to_upstream += n;
+                    metrics::inc_tunnel_bytes("client_to_upstream", n);
+                })
+            }
+            read = upstream_read.read(&mut upstream_buf), if upstream_open => {
+                copy_chunk(read, &upstream_buf, &mut client_write, &mut upstream_open, idle_timeout).await.map(|n| {
+                    stats.upstream_to_client += n;
+                    metrics::inc_tunnel_bytes("upstream_to_client", n);
+                })
+            }
+            _ = idle => return (stats, TunnelEnd::IdleTimeout),
+        };
+
+        if let Err(end) = result {
+            return (stats, end);
+        }
+    }
+
+    (stats, TunnelEnd::Closed)
+}
+
+/// Forward one read result to the other side, shutting it down on EOF
+async fn copy_chunk<W: AsyncWrite + Unpin>(
+    read: io::Result<usize>,
+    buf: &[u8],
+    writer: &mut W,
+    open: &mut bool,
+    idle_timeout: Duration,
+) -> Result<u64, TunnelEnd> {
+    let n = read.map_err(|e| TunnelEnd::Error(e.kind()))?;
+    let write = async {
+        if n == 0 {
+            *open = false;
+            writer.shutdown().await
+        } else {
+            writer.write_all(&buf[..n]).await?;
+            writer.flush().await
+        }
+    };
+    let written = if idle_timeout.is_zero() {
+        write.await
+    } else {
+        tokio::time::timeout(idle_timeout, write).await.map_err(|_| TunnelEnd::IdleTimeout)?
+    };
+    written.map_err(|e| TunnelEnd::Error(e.kind()))?;
+    Ok(n as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pipe_copies_both_directions_and_counts_bytes() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (upstream, mut upstream_peer) = tokio::io::duplex(64);
+        let tunnel = tokio::spawn(pipe(client, upstream, Duration::from_secs(5)));
+
+        client_peer.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        upstream_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        upstream_peer.write_all(b"world!").await.unwrap();
+        let mut buf = [0u8; 6];
+        client_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world!");
+
+        // Half-close from the client still lets the upstream answer
+        client_peer.shutdown().await.unwrap();
+        assert_eq!(upstream_peer.read(&mut buf).await.unwrap(), 0);
+        upstream_peer.write_all(b"bye").await.unwrap();
+        drop(upstream_peer);
+        let mut rest = Vec::new();
+        client_peer.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"bye");
+
+        let (stats, end) = tunnel.await.unwrap();
+        assert_eq!(end, TunnelEnd::Closed);
+        assert_eq!(
+            stats,
+            TunnelStats {
+                client_to_upstream: 5,
+                upstream_to_client: 9
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipe_closes_idle_tunnels() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (upstream, _upstream_peer) = tokio::io::duplex(64);
+        let tunnel = tokio::spawn(pipe(client, upstream, Duration::from_secs(60)));
+
+        client_peer.write_all(b"ping").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(!tunnel.is_finished());
+
+        let (stats, end) = tunnel.await.unwrap();
+        assert_eq!(end, TunnelEnd::IdleTimeout);
+        assert_eq!(stats.client_to_upstream, 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipe_closes_tunnels_to_a_peer_that_stops_reading() {
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        // The upstream peer never reads, so writes to it block once its buffer is full
+        let (upstream, _upstream_peer) = tokio::io::duplex(8);
+        let tunnel = tokio::spawn(pipe(client, upstream, Duration::from_secs(60)));
+
+        client_peer.write_all(&[0u8; 32]).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(!tunnel.is_finished());
+
+        let (_, end) = tunnel.await.unwrap();
+        assert_eq!(end, TunnelEnd::IdleTimeout);
+    }
+
+    #[test]
+    fn test_upstream_headers_keep_upgrade() {
+        let mut original = HeaderMap::new();
+        original.insert(header::HOST, HeaderValue::from_static("app.example.com"));
+        original.insert(header::CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+        original.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        original.insert(header::TE, HeaderValue::from_static("gzip"));
+        original.insert("sec-websocket-key", HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="));
+
+        let headers = upstream_headers(&original);
+
+        assert_eq!(headers[header::HOST], "app.example.com");
+        assert_eq!(headers[header::CONNECTION], "upgrade");
+        assert_eq!(headers[header::UPGRADE], "websocket");
+        assert_eq!(headers["sec-websocket-key"], "dGhlIHNhbXBsZSBub25jZQ==");
+        assert!(!headers.contains_key(header::TE));
+    }
+}
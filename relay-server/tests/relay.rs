@@ -0,0 +1,1682 @@
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{header, Request, StatusCode};
+use axum::response::Response;
+use http_body_util::BodyExt;
+use regex::Regex;
+use relay_server::challenge::ChallengeOnly;
+use relay_server::config::{Args, Config, Values};
+use relay_server::dns::{DnsResolver, DnsSettings, HostTemplate, StaticResolver};
+use relay_server::admin::{build_admin_router, AdminToken};
+use relay_server::gateway::{Gateway, GatewayRegistry};
+use relay_server::listener::{ListenAddr, Listener, ListenerConfig, ListenerRole};
+use relay_server::domain_map::DomainMapFile;
+use relay_server::forwarded::TrustedProxies;
+use relay_server::proxy::{HyperProxyClient, ProxyClient};
+use relay_server::proxy_protocol::{self, ProxyVersion};
+use relay_server::reload::Reloader;
+use relay_server::shutdown::Shutdown;
+use relay_server::tls_passthrough::TlsPassthrough;
+use relay_server::tunnel::TunnelClient;
+use relay_server::{build_router, AppState, RelayMode, RelayState};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tower::ServiceExt;
+
+const DOMAIN: &str = "app.example.com";
+
+/// A dstack custom domain pointing at app "my-app" behind prod5
+fn dstack_records() -> StaticResolver {
+    StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_cname("app.example.com", "_.prod5.phala.network.")
+}
+
+fn state(resolver: StaticResolver, settings: DnsSettings, relay_mode: RelayMode) -> RelayState {
+    RelayState {
+        dns_resolver: Arc::new(DnsResolver::with_resolver(Arc::new(resolver), settings)),
+        proxy_client: ProxyClient::Hyper(HyperProxyClient::new()),
+        tunnel_client: TunnelClient::new(Duration::from_secs(60)),
+        relay_mode,
+        txt_relay_mode: false,
+        trusted_proxies: TrustedProxies::default(),
+        challenge_only: None,
+        admin_token: None,
+    }
+}
+
+async fn get(state: RelayState, host: &str, path: &str) -> Response {
+    let request = Request::builder()
+        .uri(path)
+        .header(header::HOST, host)
+        .body(Body::empty())
+        .unwrap();
+
+    build_router(state).oneshot(request).await.unwrap()
+}
+
+async fn admin_get(state: impl Into<AppState>, path: &str) -> Response {
+    let request = Request::builder()
+        .uri(path)
+        .header(header::HOST, "localhost")
+        .body(Body::empty())
+        .unwrap();
+
+    build_admin_router(state, None).oneshot(request).await.unwrap()
+}
+
+fn location(response: &Response) -> &str {
+    response.headers()[header::LOCATION].to_str().unwrap()
+}
+
+async fn body_text(response: Response) -> String {
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn acme_challenge_redirects_to_gateway() {
+    let state = state(dstack_records(), DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn txt_port_selects_gateway_host() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:8080")
+        .with_cname("app.example.com", "_.prod5.phala.network");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app-8080.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn highest_priority_txt_record_wins() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "old-app:80")
+        .with_txt("_dstack-app-address.app.example.com", "v=dstack1; app=new-app; port=8080; prio=1")
+        .with_cname("app.example.com", "_.prod5.phala.network");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://new-app-8080.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn explicit_gateway_skips_cname() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80@prod7.phala.network");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod7.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn explicit_gateway_outside_allowed_regex_is_skipped() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "v=dstack1; app=evil; gw=evil.example.net; prio=1")
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_cname("app.example.com", "_.prod5.phala.network");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+/// prod5 with the default host template, and a staging cluster with its own
+fn gateway_registry() -> GatewayRegistry {
+    let prod5 = Gateway::new("prod5").with_pattern(Regex::new(r"^_\.(prod5\.phala\.network)$").unwrap(), 1);
+    let staging = Gateway::new("staging")
+        .with_pattern(Regex::new(r"^_\.(staging\.example\.net)$").unwrap(), 1)
+        .with_host_template(HostTemplate::new("{app_id}-{port}.apps.{gateway}", 0).unwrap());
+    GatewayRegistry::new(vec![prod5, staging]).unwrap()
+}
+
+#[tokio::test]
+async fn gateway_registry_selects_gateway_by_cname() {
+    let records = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_cname("app.example.com", "_.prod5.phala.network.")
+        .with_txt("_dstack-app-address.staging.example.com", "my-app:8080")
+        .with_cname("staging.example.com", "_.staging.example.net.")
+        .with_txt("_dstack-app-address.other.example.com", "my-app:80")
+        .with_cname("other.example.com", "_.prod7.phala.network.");
+    let settings = DnsSettings {
+        gateways: gateway_registry(),
+        ..DnsSettings::default()
+    };
+    let state = state(records, settings, RelayMode::Redirect);
+
+    let response = get(state.clone(), DOMAIN, "/.well-known/acme-challenge/token-123").await;
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+
+    let response = get(state.clone(), "staging.example.com", "/.well-known/acme-challenge/token-123").await;
+    assert_eq!(
+        location(&response),
+        "https://my-app-8080.apps.staging.example.net/.well-known/acme-challenge/token-123"
+    );
+
+    let response = get(state, "other.example.com", "/.well-known/acme-challenge/token-123").await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn custom_host_template() {
+    let gateway = Gateway::default().with_host_template(HostTemplate::new("{app_id}-{port}.{gateway}", 0).unwrap());
+    let settings = DnsSettings {
+        gateways: GatewayRegistry::single(gateway),
+        ..DnsSettings::default()
+    };
+    let state = state(dstack_records(), settings, RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app-80.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn acme_challenge_without_txt_record_is_bad_gateway() {
+    let state = state(StaticResolver::new(), DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn malformed_txt_record_is_bad_gateway() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "not-an-address")
+        .with_cname("app.example.com", "_.prod5.phala.network");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn cname_outside_allowed_regex_uses_fallback() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_cname("app.example.com", "evil.example.net");
+    let settings = DnsSettings {
+        fallback_gateway_domain: Some("prod7.phala.network".to_string()),
+        ..DnsSettings::default()
+    };
+    let state = state(resolver, settings, RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod7.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn cname_outside_allowed_regex_without_fallback_is_bad_gateway() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_cname("app.example.com", "evil.example.net");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn missing_cname_uses_fallback() {
+    let resolver = StaticResolver::new().with_txt("_dstack-app-address.app.example.com", "my-app:80");
+    let settings = DnsSettings {
+        fallback_gateway_domain: Some("prod7.phala.network".to_string()),
+        ..DnsSettings::default()
+    };
+    let state = state(resolver, settings, RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod7.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn cname_chain_is_followed_to_allowed_hop() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_cname("app.example.com", "app.cdn.example.net")
+        .with_cname("app.cdn.example.net", "edge.example.org")
+        .with_cname("edge.example.org", "_.prod7.phala.network");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod7.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn cname_chain_respects_max_depth() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_cname("app.example.com", "app.cdn.example.net")
+        .with_cname("app.cdn.example.net", "_.prod7.phala.network");
+    let settings = DnsSettings {
+        cname_max_depth: 1,
+        ..DnsSettings::default()
+    };
+    let state = state(resolver, settings, RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn cname_loop_is_bad_gateway() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_cname("app.example.com", "a.example.net")
+        .with_cname("a.example.net", "app.example.com");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn gateway_txt_record_replaces_flattened_cname() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_txt("_dstack-gateway.app.example.com", "_.prod7.phala.network");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod7.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn gateway_txt_record_must_be_allowed() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.app.example.com", "my-app:80")
+        .with_txt("_dstack-gateway.app.example.com", "evil.example.net");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn capture_group_selects_gateway_domain() {
+    let gateway = Gateway::new("default").with_pattern(Regex::new(r"^_\.(.+?)\.(.+)$").unwrap(), 2);
+    let settings = DnsSettings {
+        gateways: GatewayRegistry::single(gateway),
+        ..DnsSettings::default()
+    };
+    let state = state(dstack_records(), settings, RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn no_regex_strips_wildcard_prefix() {
+    let settings = DnsSettings {
+        gateways: GatewayRegistry::single(Gateway::new("any")),
+        ..DnsSettings::default()
+    };
+    let state = state(dstack_records(), settings, RelayMode::Redirect);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn dstack_domain_paths_are_relayed() {
+    let state = state(dstack_records(), DnsSettings::default(), RelayMode::Redirect);
+
+    for path in ["/", "/health", "/metrics", "/some/page"] {
+        let response = get(state.clone(), DOMAIN, path).await;
+
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT, "path {}", path);
+        assert_eq!(location(&response), format!("https://my-app.prod5.phala.network{}", path));
+    }
+}
+
+#[tokio::test]
+async fn https_redirect_mode_keeps_browsers_on_custom_domain() {
+    let state = state(dstack_records(), DnsSettings::default(), RelayMode::HttpsRedirect);
+
+    for path in ["/", "/health", "/some/page?q=1&r=2"] {
+        let response = get(state.clone(), "app.example.com:80", path).await;
+
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY, "path {}", path);
+        assert_eq!(location(&response), format!("https://app.example.com{}", path));
+    }
+
+    let request = Request::builder()
+        .method("POST")
+        .uri("/api/submit")
+        .header(header::HOST, DOMAIN)
+        .body(Body::from("form"))
+        .unwrap();
+    let response = build_router(state.clone()).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(location(&response), "https://app.example.com/api/submit");
+
+    // Challenges still go to the gateway
+    let response = get(state.clone(), DOMAIN, "/.well-known/acme-challenge/token-123").await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+
+    // Hosts that aren't dstack custom domains are not redirected
+    let response = get(state, "relay.example.org", "/some/page").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn health_reports_draining_and_requests_are_tracked_until_sent() {
+    let app = AppState::new(state(dstack_records(), DnsSettings::default(), RelayMode::Redirect));
+    let health = || {
+        Request::builder()
+            .uri("/health")
+            .header(header::HOST, "relay.example.org")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // A request counts as active until its response body is done
+    let response = build_admin_router(app.clone(), None).oneshot(health()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(app.shutdown().active(), 1);
+    assert_eq!(body_text(response).await, "OK");
+    assert_eq!(app.shutdown().active(), 0);
+
+    app.shutdown().start_draining();
+    let response = build_admin_router(app.clone(), None).oneshot(health()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body_text(response).await, "Draining");
+
+    // Custom domains are still relayed while draining
+    let response = build_router(app).oneshot(
+        Request::builder()
+            .uri("/health")
+            .header(header::HOST, DOMAIN)
+            .body(Body::empty())
+            .unwrap(),
+    ).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+}
+
+#[tokio::test]
+async fn other_hosts_get_nothing_from_the_relay_listener() {
+    let state = state(dstack_records(), DnsSettings::default(), RelayMode::Redirect);
+
+    // Health, metrics and the info page are only on the admin listener
+    for path in ["/", "/health", "/metrics", "/some/page"] {
+        let response = get(state.clone(), "relay.example.org", path).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "path {}", path);
+    }
+}
+
+/// A TLS server for "localhost" and app.example.com handling one connection with `handler`;
+/// returns its port and a client config trusting it
+async fn tls_gateway<F, Fut>(handler: F) -> (u16, rustls::ClientConfig)
+where
+    F: FnOnce(tokio_rustls::server::TlsStream<TcpStream>) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    tcp_tls_gateway(|stream, acceptor| async move { handler(acceptor.accept(stream).await.unwrap()).await }).await
+}
+
+/// Like `tls_gateway`, but the handler gets the TCP connection before the TLS handshake
+async fn tcp_tls_gateway<F, Fut>(handler: F) -> (u16, rustls::ClientConfig)
+where
+    F: FnOnce(TcpStream, tokio_rustls::TlsAcceptor) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    let names = vec!["localhost".to_string(), DOMAIN.to_string()];
+    let certified = rcgen::generate_simple_self_signed(names).unwrap();
+    let cert = certified.cert.der().clone();
+    let key = rustls::pki_types::PrivateKeyDer::Pkcs8(certified.signing_key.serialize_der().into());
+
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key)
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handler(stream, acceptor).await;
+    });
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    (port, client_config)
+}
+
+/// A gateway that accepts one WebSocket-style upgrade and echoes everything after it
+async fn upgrade_echo_gateway() -> (u16, rustls::ClientConfig) {
+    tls_gateway(|mut stream| async move {
+        let head = read_head(&mut stream).await.to_lowercase();
+        assert!(head.contains("host: app.example.com\r\n"), "{}", head);
+        assert!(head.contains("upgrade: websocket\r\n"), "{}", head);
+        stream
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buf[..n]).await.unwrap();
+        }
+    })
+    .await
+}
+
+/// Records resolving app.example.com to the local gateway on `port`
+fn local_gateway_state(port: u16, relay_mode: RelayMode) -> RelayState {
+    let resolver = StaticResolver::new().with_txt(
+        "_dstack-app-address.app.example.com",
+        &format!("v=dstack1; app=localhost; port={}; gw=gateway.test", port),
+    );
+    let gateway = Gateway::new("local").with_host_template(HostTemplate::new("{app_id}:{port}", 0).unwrap());
+    let settings = DnsSettings {
+        gateways: GatewayRegistry::single(gateway),
+        ..DnsSettings::default()
+    };
+    state(resolver, settings, relay_mode)
+}
+
+/// Read an HTTP message head, up to and including the empty line
+async fn read_head_raw<S: AsyncReadExt + Unpin>(stream: &mut S) -> Vec<u8> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8; 1];
+        assert_eq!(stream.read(&mut byte).await.unwrap(), 1, "connection closed in message head");
+        head.push(byte[0]);
+    }
+    head
+}
+
+async fn read_head<S: AsyncReadExt + Unpin>(stream: &mut S) -> String {
+    String::from_utf8_lossy(&read_head_raw(stream).await).into_owned()
+}
+
+#[tokio::test]
+async fn proxy_mode_tunnels_upgrade_requests() {
+    let (gateway_port, tls_config) = upgrade_echo_gateway().await;
+    let state = RelayState {
+        tunnel_client: TunnelClient::with_tls_config(tls_config, Duration::from_secs(60)),
+        ..local_gateway_state(gateway_port, RelayMode::Proxy)
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, build_router(state)).await.unwrap() });
+
+    let mut client = TcpStream::connect(relay_addr).await.unwrap();
+    client
+        .write_all(b"GET /socket HTTP/1.1\r\nHost: app.example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+        .await
+        .unwrap();
+
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+
+    client.write_all(b"ping").await.unwrap();
+    let mut echo = [0u8; 4];
+    client.read_exact(&mut echo).await.unwrap();
+    assert_eq!(&echo, b"ping");
+}
+
+#[tokio::test]
+async fn declined_upgrade_is_relayed_without_hop_by_hop_headers() {
+    let (gateway_port, tls_config) = tls_gateway(|mut stream| async move {
+        read_head(&mut stream).await;
+        stream
+            .write_all(
+                b"HTTP/1.1 426 Upgrade Required\r\n\
+                  Connection: upgrade, keep-alive, X-Upstream-Hop\r\n\
+                  Upgrade: websocket\r\n\
+                  Keep-Alive: timeout=5\r\n\
+                  X-Upstream-Hop: 1\r\n\
+                  Content-Length: 2\r\n\
+                  \r\n\
+                  no",
+            )
+            .await
+            .unwrap();
+    })
+    .await;
+    let state = RelayState {
+        tunnel_client: TunnelClient::with_tls_config(tls_config, Duration::from_secs(60)),
+        ..local_gateway_state(gateway_port, RelayMode::Proxy)
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, build_router(state)).await.unwrap() });
+
+    let mut client = TcpStream::connect(relay_addr).await.unwrap();
+    client
+        .write_all(b"GET /socket HTTP/1.1\r\nHost: app.example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+        .await
+        .unwrap();
+
+    let head = read_head(&mut client).await.to_lowercase();
+    assert!(head.starts_with("http/1.1 426"), "{}", head);
+    for name in ["upgrade:", "keep-alive:", "x-upstream-hop:"] {
+        assert!(!head.contains(name), "{} in {}", name, head);
+    }
+}
+
+#[tokio::test]
+async fn proxy_mode_relays_requests_verbatim() {
+    let (request_tx, request_rx) = tokio::sync::oneshot::channel();
+    let (gateway_port, tls_config) = tls_gateway(|mut stream| async move {
+        let head = read_head_raw(&mut stream).await;
+        let mut body = [0u8; 4];
+        stream.read_exact(&mut body).await.unwrap();
+        stream
+            .write_all(
+                b"HTTP/1.1 207 Multi-Status\r\n\
+                  Connection: close, X-Upstream-Hop\r\n\
+                  X-Upstream-Hop: 1\r\n\
+                  Set-Cookie: a=1\r\n\
+                  Set-Cookie: b=2\r\n\
+                  X-Raw: caf\xe9\r\n\
+                  Transfer-Encoding: chunked\r\n\
+                  Trailer: X-Checksum\r\n\
+                  \r\n\
+                  2\r\nok\r\n0\r\nX-Checksum: 42\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+        request_tx.send((head, body)).unwrap();
+    })
+    .await;
+    let state = RelayState {
+        proxy_client: ProxyClient::Hyper(HyperProxyClient::with_tls_config(tls_config, Duration::from_secs(10))),
+        ..local_gateway_state(gateway_port, RelayMode::Proxy)
+    };
+
+    let request = Request::builder()
+        .method("PROPFIND")
+        .uri("/dav/file")
+        .header(header::HOST, DOMAIN)
+        .header(header::CONNECTION, "X-Client-Hop")
+        .header("x-client-hop", "1")
+        .header("keep-alive", "timeout=5")
+        .header("x-raw", header::HeaderValue::from_bytes(b"caf\xe9").unwrap())
+        .header(header::CONTENT_LENGTH, "4")
+        .body(Body::from("body"))
+        .unwrap();
+    let response = build_router(state).oneshot(request).await.unwrap();
+
+    let (head_raw, body) = request_rx.await.unwrap();
+    let raw_header = b"x-raw: caf\xe9\r\n";
+    assert!(head_raw.windows(raw_header.len()).any(|w| w == raw_header));
+    let head = String::from_utf8_lossy(&head_raw).to_lowercase();
+    assert!(head.starts_with("propfind /dav/file http/1.1\r\n"), "{}", head);
+    assert!(head.contains("host: app.example.com\r\n"), "{}", head);
+    assert!(!head.contains("x-client-hop"), "{}", head);
+    assert!(!head.contains("keep-alive"), "{}", head);
+    assert_eq!(&body, b"body");
+
+    assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+    let headers = response.headers().clone();
+    let cookies: Vec<&[u8]> = headers.get_all(header::SET_COOKIE).iter().map(|v| v.as_bytes()).collect();
+    assert_eq!(cookies, vec![b"a=1".as_ref(), b"b=2".as_ref()]);
+    assert_eq!(headers["x-raw"].as_bytes(), b"caf\xe9");
+    assert!(!headers.contains_key("x-upstream-hop"));
+    assert!(!headers.contains_key(header::CONNECTION));
+
+    let collected = response.into_body().collect().await.unwrap();
+    assert_eq!(collected.trailers().unwrap()["x-checksum"], "42");
+    assert_eq!(collected.to_bytes(), "ok");
+}
+
+/// The request head the gateway sees for a proxied request from `peer`
+async fn forwarded_request_head(trusted_proxies: &str, peer: &str, headers: &[(&str, &str)]) -> String {
+    let (head_tx, head_rx) = tokio::sync::oneshot::channel();
+    let (gateway_port, tls_config) = tls_gateway(|mut stream| async move {
+        let head = read_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        head_tx.send(head).unwrap();
+    })
+    .await;
+    let state = RelayState {
+        proxy_client: ProxyClient::Hyper(HyperProxyClient::with_tls_config(tls_config, Duration::from_secs(10))),
+        trusted_proxies: TrustedProxies::parse(trusted_proxies).unwrap(),
+        ..local_gateway_state(gateway_port, RelayMode::Proxy)
+    };
+
+    // What into_make_service_with_connect_info records for each connection
+    let peer: SocketAddr = peer.parse().unwrap();
+    let mut request = Request::builder()
+        .uri("/audit")
+        .header(header::HOST, DOMAIN)
+        .extension(ConnectInfo(peer));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = build_router(state).oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    head_rx.await.unwrap().to_lowercase()
+}
+
+#[tokio::test]
+async fn proxy_mode_overwrites_forwarding_headers_from_untrusted_peers() {
+    let spoofed = [
+        ("x-forwarded-for", "10.9.9.9"),
+        ("x-real-ip", "10.9.9.9"),
+        ("forwarded", "for=10.9.9.9"),
+    ];
+    let head = forwarded_request_head("10.0.0.0/8", "203.0.113.7:51000", &spoofed).await;
+
+    assert!(!head.contains("10.9.9.9"), "{}", head);
+    assert!(head.contains("x-forwarded-for: 203.0.113.7\r\n"), "{}", head);
+    assert!(head.contains("x-real-ip: 203.0.113.7\r\n"), "{}", head);
+    assert!(head.contains("x-forwarded-proto: http\r\n"), "{}", head);
+    assert!(head.contains("x-forwarded-host: app.example.com\r\n"), "{}", head);
+    assert!(
+        head.contains("forwarded: for=203.0.113.7;proto=http;host=app.example.com\r\n"),
+        "{}",
+        head
+    );
+    assert!(head.contains("via: 1.1 dstack-relay\r\n"), "{}", head);
+}
+
+#[tokio::test]
+async fn proxy_mode_appends_to_forwarding_headers_from_trusted_proxies() {
+    // What the front nginx sends
+    let from_nginx = [
+        ("x-real-ip", "203.0.113.7"),
+        ("x-forwarded-for", "203.0.113.7"),
+        ("x-forwarded-proto", "https"),
+    ];
+    let head = forwarded_request_head("10.0.0.0/8", "10.0.0.2:51000", &from_nginx).await;
+
+    assert!(head.contains("x-real-ip: 203.0.113.7\r\n"), "{}", head);
+    assert!(head.contains("x-forwarded-for: 203.0.113.7, 10.0.0.2\r\n"), "{}", head);
+    assert!(head.contains("x-forwarded-proto: https\r\n"), "{}", head);
+    assert!(head.contains("forwarded: for=10.0.0.2;proto=http;host=app.example.com\r\n"), "{}", head);
+}
+
+#[tokio::test]
+async fn proxy_protocol_listener_uses_client_address_from_header() {
+    let (head_tx, head_rx) = tokio::sync::oneshot::channel();
+    let (gateway_port, tls_config) = tls_gateway(|mut stream| async move {
+        let head = read_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        head_tx.send(head.to_lowercase()).unwrap();
+    })
+    .await;
+    let state = RelayState {
+        proxy_client: ProxyClient::Hyper(HyperProxyClient::with_tls_config(tls_config, Duration::from_secs(10))),
+        ..local_gateway_state(gateway_port, RelayMode::Proxy)
+    };
+
+    let listener = bind(ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()), ListenerRole::Relay);
+    let relay_addr = listener.local_addr().unwrap();
+    tokio::spawn(listener.serve(build_router(state), Shutdown::new(), true));
+
+    let mut client = TcpStream::connect(relay_addr).await.unwrap();
+    client
+        .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 80\r\nGET /audit HTTP/1.1\r\nHost: app.example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+
+    let upstream_head = head_rx.await.unwrap();
+    assert!(upstream_head.contains("x-forwarded-for: 203.0.113.7\r\n"), "{}", upstream_head);
+
+    // Without a PROXY header the connection is closed unanswered
+    let mut client = TcpStream::connect(relay_addr).await.unwrap();
+    client
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    // Closed with unread data, so the read may see a reset instead of EOF
+    let mut buf = [0u8; 64];
+    assert_eq!(client.read(&mut buf).await.unwrap_or(0), 0);
+}
+
+fn bind(addr: ListenAddr, role: ListenerRole) -> Listener {
+    let config = ListenerConfig::new(addr, role);
+    Listener::bind(&config, std::slice::from_ref(&config)).unwrap()
+}
+
+#[tokio::test]
+async fn dual_stack_listener_accepts_ipv4_and_ipv6() {
+    let state = state(dstack_records(), DnsSettings::default(), RelayMode::Redirect);
+    let listener = bind(ListenAddr::Tcp("[::]:0".parse().unwrap()), ListenerRole::Admin);
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(listener.serve(build_admin_router(state, None), Shutdown::new(), false));
+
+    for addr in [format!("127.0.0.1:{}", port), format!("[::1]:{}", port)] {
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}: {}", addr, head);
+    }
+}
+
+#[tokio::test]
+async fn admin_listener_reports_draining_until_the_drain_is_over() {
+    let app = AppState::new(state(dstack_records(), DnsSettings::default(), RelayMode::Redirect));
+    let relay = bind(ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()), ListenerRole::Relay);
+    let admin = bind(ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()), ListenerRole::Admin);
+    let admin_addr = admin.local_addr().unwrap();
+    let relay = tokio::spawn(relay.serve(build_router(app.clone()), app.shutdown().clone(), false));
+    let admin = tokio::spawn(admin.serve(build_admin_router(app.clone(), None), app.shutdown().clone(), false));
+
+    app.shutdown().start_draining();
+    relay.await.unwrap().unwrap();
+
+    // New probes still get an answer while requests and tunnels drain
+    let mut client = TcpStream::connect(admin_addr).await.unwrap();
+    client
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.1 503"), "{}", head);
+    drop(client);
+
+    app.shutdown().drain(Duration::from_secs(1)).await;
+    tokio::time::timeout(Duration::from_secs(1), admin).await.unwrap().unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn unix_socket_listener_trusts_the_local_proxy() {
+    let (head_tx, head_rx) = tokio::sync::oneshot::channel();
+    let (gateway_port, tls_config) = tls_gateway(|mut stream| async move {
+        let head = read_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        head_tx.send(head.to_lowercase()).unwrap();
+    })
+    .await;
+    let state = RelayState {
+        proxy_client: ProxyClient::Hyper(HyperProxyClient::with_tls_config(tls_config, Duration::from_secs(10))),
+        trusted_proxies: TrustedProxies::parse("127.0.0.0/8").unwrap(),
+        ..local_gateway_state(gateway_port, RelayMode::Proxy)
+    };
+
+    let path = std::env::temp_dir().join(format!("relay-{}-listener.sock", std::process::id()));
+    // A stale socket file from an earlier run is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path));
+    let listener = bind(ListenAddr::Unix(path.clone()), ListenerRole::Relay);
+    tokio::spawn(listener.serve(build_router(state), Shutdown::new(), false));
+
+    let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+    client
+        .write_all(b"GET /audit HTTP/1.1\r\nHost: app.example.com\r\nX-Forwarded-For: 203.0.113.7\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+
+    let upstream_head = head_rx.await.unwrap();
+    assert!(upstream_head.contains("x-forwarded-for: 203.0.113.7, 127.0.0.1\r\n"), "{}", upstream_head);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn admin_router_serves_operators_for_any_host() {
+    let app = AppState::new(state(dstack_records(), DnsSettings::default(), RelayMode::Redirect));
+    let request = |path: &str| {
+        Request::builder()
+            .uri(path)
+            .header(header::HOST, DOMAIN)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // Even for a custom domain, nothing is relayed
+    let response = build_admin_router(app.clone(), None).oneshot(request("/health")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_text(response).await, "OK");
+
+    let response = build_admin_router(app.clone(), None).oneshot(request("/metrics")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = build_admin_router(app.clone(), None).oneshot(request("/")).await.unwrap();
+    let info = body_text(response).await;
+    assert!(info.contains("HTTP-01 ACME Challenge Relay Server"), "{}", info);
+    assert!(info.contains("hyper client, up to 100 idle connections per host"), "{}", info);
+
+    let response = build_admin_router(app, None).oneshot(request("/.well-known/acme-challenge/token-123")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn admin_token_is_required_except_for_probes() {
+    let state = RelayState {
+        admin_token: Some(AdminToken::new("0123456789abcdef-admin")),
+        ..state(dstack_records(), DnsSettings::default(), RelayMode::Redirect)
+    };
+    let request = |method: &str, path: &str, authorization: Option<&str>| {
+        let mut request = Request::builder().method(method).uri(path).header(header::HOST, "localhost");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+    let router = build_admin_router(state, None);
+
+    for path in ["/health", "/ready"] {
+        let response = router.clone().oneshot(request("GET", path, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "path {}", path);
+    }
+
+    for authorization in [None, Some("Bearer wrong-token-of-some-length"), Some("Basic 0123456789abcdef-admin")] {
+        let response = router.clone().oneshot(request("GET", "/metrics", authorization)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{:?}", authorization);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+    let response = router.clone().oneshot(request("POST", "/dns-cache/clear", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = router
+        .clone()
+        .oneshot(request("GET", "/metrics", Some("bearer 0123456789abcdef-admin")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Without a reloader there is nothing to reload
+    let response = router
+        .oneshot(request("POST", "/reload", Some("Bearer 0123456789abcdef-admin")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn admin_endpoints_report_gateways_and_resolve_domains() {
+    let resolver = Arc::new(dstack_records());
+    let gateway = Gateway::default().with_health(1, Duration::from_secs(60));
+    let settings = DnsSettings {
+        gateways: GatewayRegistry::single(gateway),
+        ..DnsSettings::default()
+    };
+    let state = RelayState {
+        dns_resolver: Arc::new(DnsResolver::with_resolver(resolver.clone(), settings)),
+        ..state(StaticResolver::new(), DnsSettings::default(), RelayMode::Redirect)
+    };
+
+    let response = admin_get(state.clone(), "/resolve/App.Example.com").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let resolved: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+    assert_eq!(resolved["domain"], DOMAIN);
+    assert_eq!(resolved["targets"][0]["url"], "https://my-app.prod5.phala.network/");
+    assert_eq!(resolved["targets"][0]["gateway"], "default");
+
+    let response = admin_get(state.clone(), "/resolve/10.0.0.1").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = admin_get(state.clone(), "/resolve/unknown.example.com").await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+    // Answers are cached until the cache is cleared
+    let lookups = resolver.lookup_count();
+    admin_get(state.clone(), "/resolve/app.example.com").await;
+    assert_eq!(resolver.lookup_count(), lookups);
+    let clear = Request::builder()
+        .method("POST")
+        .uri("/dns-cache/clear")
+        .body(Body::empty())
+        .unwrap();
+    let response = build_admin_router(state.clone(), None).oneshot(clear).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    admin_get(state.clone(), "/resolve/app.example.com").await;
+    assert!(resolver.lookup_count() > lookups);
+
+    let response = admin_get(state.clone(), "/gateways").await;
+    let gateways: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+    assert_eq!(gateways[0]["name"], "default");
+    assert_eq!(gateways[0]["healthy"], true);
+
+    // Not ready once no gateway is healthy
+    state.dns_resolver.gateways().first().record_failure();
+    let response = admin_get(state.clone(), "/ready").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body_text(response).await, "No healthy gateway");
+    let response = admin_get(state.clone(), "/gateways").await;
+    let gateways: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+    assert_eq!(gateways[0]["healthy"], false);
+
+    // Liveness doesn't depend on the gateways
+    let response = admin_get(state, "/health").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn tunnel_sends_proxy_header_to_gateway() {
+    let (header_tx, header_rx) = tokio::sync::oneshot::channel();
+    let (gateway_port, tls_config) = tcp_tls_gateway(|mut stream, acceptor| async move {
+        let header = proxy_protocol::read_header(&mut stream).await.unwrap();
+        header_tx.send(header).unwrap();
+
+        let mut stream = acceptor.accept(stream).await.unwrap();
+        read_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+    })
+    .await;
+    let state = RelayState {
+        tunnel_client: TunnelClient::with_tls_config(tls_config, Duration::from_secs(60))
+            .with_proxy_protocol(Some(ProxyVersion::V2)),
+        ..local_gateway_state(gateway_port, RelayMode::Proxy)
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = listener.local_addr().unwrap();
+    let app = build_router(state).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let mut client = TcpStream::connect(relay_addr).await.unwrap();
+    client
+        .write_all(b"GET /socket HTTP/1.1\r\nHost: app.example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+
+    let header = header_rx.await.unwrap().unwrap();
+    assert_eq!(header.source, client.local_addr().unwrap());
+    assert_eq!(header.destination.port(), gateway_port);
+}
+
+#[tokio::test]
+async fn tls_passthrough_splices_to_gateway_by_sni() {
+    let (gateway_port, tls_config) = tls_gateway(|mut stream| async move {
+        assert_eq!(stream.get_ref().1.server_name(), Some(DOMAIN));
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("GET /secure HTTP/1.1\r\n"), "{}", head);
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\nsecure")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+    })
+    .await;
+    let state = local_gateway_state(gateway_port, RelayMode::Redirect);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = listener.local_addr().unwrap();
+    let passthrough = TlsPassthrough::new(state, Duration::from_secs(60));
+    tokio::spawn(passthrough.serve(listener));
+
+    // The client's TLS session, with its own SNI, ends at the gateway
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
+    let server_name = rustls::pki_types::ServerName::try_from(DOMAIN).unwrap();
+    let tcp = TcpStream::connect(relay_addr).await.unwrap();
+    let mut stream = connector.connect(server_name, tcp).await.unwrap();
+    stream
+        .write_all(b"GET /secure HTTP/1.1\r\nHost: app.example.com\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("secure"), "{}", response);
+}
+
+#[tokio::test]
+async fn tls_passthrough_closes_connections_for_unknown_domains() {
+    let resolver = Arc::new(StaticResolver::new());
+    let state = RelayState {
+        dns_resolver: Arc::new(DnsResolver::with_resolver(resolver.clone(), DnsSettings::default())),
+        ..state(StaticResolver::new(), DnsSettings::default(), RelayMode::Redirect)
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = listener.local_addr().unwrap();
+    tokio::spawn(TlsPassthrough::new(state, Duration::from_secs(60)).serve(listener));
+
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth(),
+    ));
+    let server_name = rustls::pki_types::ServerName::try_from("unknown.example.com").unwrap();
+    let tcp = TcpStream::connect(relay_addr).await.unwrap();
+    assert!(connector.connect(server_name, tcp).await.is_err());
+    assert_eq!(resolver.lookup_count(), 1);
+}
+
+#[tokio::test]
+async fn tls_passthrough_listener_is_dual_stack() {
+    let resolver = Arc::new(StaticResolver::new());
+    let state = RelayState {
+        dns_resolver: Arc::new(DnsResolver::with_resolver(resolver.clone(), DnsSettings::default())),
+        ..state(StaticResolver::new(), DnsSettings::default(), RelayMode::Redirect)
+    };
+    let listener = bind(ListenAddr::Tcp("[::]:0".parse().unwrap()), ListenerRole::TlsPassthrough);
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(TlsPassthrough::new(state, Duration::from_secs(60)).serve(listener.into_tcp().unwrap()));
+
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth(),
+    ));
+    for (addr, name) in [(format!("127.0.0.1:{}", port), "v4.example.com"), (format!("[::1]:{}", port), "v6.example.com")] {
+        let server_name = rustls::pki_types::ServerName::try_from(name).unwrap();
+        let tcp = TcpStream::connect(&addr).await.unwrap();
+        assert!(connector.connect(server_name, tcp).await.is_err(), "{}", addr);
+    }
+    // Both clients got as far as the lookup
+    assert_eq!(resolver.lookup_count(), 2);
+}
+
+#[tokio::test]
+async fn tls_passthrough_honors_deny_policy() {
+    let gateway = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let resolver = StaticResolver::new().with_txt(
+        "_dstack-app-address.app.example.com",
+        &format!("v=dstack1; app=localhost; port={}; gw=gateway.test; mode=deny", gateway.local_addr().unwrap().port()),
+    );
+    let settings = DnsSettings {
+        gateways: GatewayRegistry::single(
+            Gateway::new("local").with_host_template(HostTemplate::new("{app_id}:{port}", 0).unwrap()),
+        ),
+        ..DnsSettings::default()
+    };
+    let state = RelayState {
+        txt_relay_mode: true,
+        ..state(resolver, settings, RelayMode::Proxy)
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = listener.local_addr().unwrap();
+    tokio::spawn(TlsPassthrough::new(state, Duration::from_secs(60)).serve(listener));
+
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth(),
+    ));
+    let server_name = rustls::pki_types::ServerName::try_from(DOMAIN).unwrap();
+    let tcp = TcpStream::connect(relay_addr).await.unwrap();
+    assert!(connector.connect(server_name, tcp).await.is_err());
+    assert!(tokio::time::timeout(Duration::from_millis(100), gateway.accept()).await.is_err());
+}
+
+#[tokio::test]
+async fn tls_passthrough_relays_nothing_in_challenge_only_mode() {
+    let resolver = Arc::new(dstack_records());
+    let state = RelayState {
+        dns_resolver: Arc::new(DnsResolver::with_resolver(resolver.clone(), DnsSettings::default())),
+        challenge_only: Some(ChallengeOnly::default()),
+        ..state(StaticResolver::new(), DnsSettings::default(), RelayMode::Redirect)
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = listener.local_addr().unwrap();
+    tokio::spawn(TlsPassthrough::new(state, Duration::from_secs(60)).serve(listener));
+
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth(),
+    ));
+    let server_name = rustls::pki_types::ServerName::try_from(DOMAIN).unwrap();
+    let tcp = TcpStream::connect(relay_addr).await.unwrap();
+    assert!(connector.connect(server_name, tcp).await.is_err());
+    assert_eq!(resolver.lookup_count(), 0);
+}
+
+const TOKEN: &str = "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0";
+
+#[tokio::test]
+async fn challenge_only_mode_relays_only_valid_challenges() {
+    let resolver = Arc::new(dstack_records());
+    let state = RelayState {
+        dns_resolver: Arc::new(DnsResolver::with_resolver(resolver.clone(), DnsSettings::default())),
+        challenge_only: Some(ChallengeOnly::default()),
+        ..state(StaticResolver::new(), DnsSettings::default(), RelayMode::Redirect)
+    };
+
+    let path = format!("/.well-known/acme-challenge/{}", TOKEN);
+    let response = get(state.clone(), DOMAIN, &path).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(location(&response), format!("https://my-app.prod5.phala.network{}", path));
+    let lookups = resolver.lookup_count();
+
+    let post = Request::builder()
+        .method("POST")
+        .uri(&path)
+        .header(header::HOST, DOMAIN)
+        .body(Body::empty())
+        .unwrap();
+    let response = build_router(state.clone()).oneshot(post).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    for path in ["/.well-known/acme-challenge/token-123", "/some/page", "/.well-known/other"] {
+        let response = get(state.clone(), DOMAIN, path).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "path {}", path);
+    }
+
+    // Nothing else is relayed, so the host isn't looked up
+    for path in ["/", "/health"] {
+        let response = get(state.clone(), DOMAIN, path).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "path {}", path);
+    }
+    assert_eq!(resolver.lookup_count(), lookups);
+
+    let response = admin_get(state, "/").await;
+    assert!(body_text(response).await.contains("Challenge-Only: enabled"));
+}
+
+#[tokio::test]
+async fn challenge_only_response_is_configurable() {
+    let state = RelayState {
+        challenge_only: Some(ChallengeOnly {
+            status: StatusCode::GONE,
+            body: "Only ACME challenges are relayed here".to_string(),
+            ..ChallengeOnly::default()
+        }),
+        ..state(dstack_records(), DnsSettings::default(), RelayMode::Redirect)
+    };
+
+    for path in ["/some/page", "/.well-known/acme-challenge/not+a+token"] {
+        let response = get(state.clone(), DOMAIN, path).await;
+        assert_eq!(response.status(), StatusCode::GONE, "path {}", path);
+        assert_eq!(body_text(response).await, "Only ACME challenges are relayed here");
+    }
+}
+
+#[tokio::test]
+async fn challenge_only_mode_gives_up_on_slow_gateways() {
+    let (port, client_config) = tls_gateway(|mut stream| async move {
+        read_head(&mut stream).await;
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    })
+    .await;
+    let state = RelayState {
+        proxy_client: ProxyClient::Hyper(HyperProxyClient::with_tls_config(client_config, Duration::from_secs(30))),
+        challenge_only: Some(ChallengeOnly {
+            timeout: Duration::from_millis(500),
+            ..ChallengeOnly::default()
+        }),
+        ..local_gateway_state(port, RelayMode::Proxy)
+    };
+
+    let started = std::time::Instant::now();
+    let response = get(state, DOMAIN, &format!("/.well-known/acme-challenge/{}", TOKEN)).await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn gateway_tls_settings_are_used_for_proxied_requests() {
+    let (port, client_config) = tls_gateway(|mut stream| async move {
+        read_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+            .await
+            .unwrap();
+    })
+    .await;
+
+    // The relay-wide client only trusts the Mozilla roots; the gateway trusts the test CA
+    let gateway = Gateway::new("local")
+        .with_host_template(HostTemplate::new("{app_id}:{port}", 0).unwrap())
+        .with_tls_config(client_config);
+    let resolver = StaticResolver::new().with_txt(
+        "_dstack-app-address.app.example.com",
+        &format!("v=dstack1; app=localhost; port={}; gw=gateway.test", port),
+    );
+    let settings = DnsSettings {
+        gateways: GatewayRegistry::single(gateway),
+        ..DnsSettings::default()
+    };
+    let state = state(resolver, settings, RelayMode::Proxy);
+
+    let response = get(state, DOMAIN, "/some/page").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_text(response).await, "ok");
+}
+
+#[tokio::test]
+async fn unhealthy_gateway_is_tried_last() {
+    // Answers both requests on one kept-alive connection
+    let (port, client_config) = tls_gateway(|mut stream| async move {
+        for _ in 0..2 {
+            read_head(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+        }
+    })
+    .await;
+    let closed_port = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    };
+
+    let resolver = StaticResolver::new()
+        .with_txt(
+            "_dstack-app-address.app.example.com",
+            &format!("v=dstack1; app=localhost; port={}; gw=down.test; prio=1", closed_port),
+        )
+        .with_txt(
+            "_dstack-app-address.app.example.com",
+            &format!("v=dstack1; app=localhost; port={}; gw=up.test; prio=2", port),
+        );
+    let template = || HostTemplate::new("{app_id}:{port}", 0).unwrap();
+    let down = Gateway::new("down")
+        .with_pattern(Regex::new(r"^(down\.test)$").unwrap(), 1)
+        .with_host_template(template())
+        .with_health(1, Duration::from_secs(60));
+    let up = Gateway::new("up")
+        .with_pattern(Regex::new(r"^(up\.test)$").unwrap(), 1)
+        .with_host_template(template());
+    let settings = DnsSettings {
+        gateways: GatewayRegistry::new(vec![down, up]).unwrap(),
+        ..DnsSettings::default()
+    };
+    let state = RelayState {
+        proxy_client: ProxyClient::Hyper(HyperProxyClient::with_tls_config(client_config, Duration::from_secs(10))),
+        ..state(resolver, settings, RelayMode::Proxy)
+    };
+
+    // GET fails over from the down gateway, which is then marked unhealthy
+    let response = get(state.clone(), DOMAIN, "/some/page").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // POST only goes to the first candidate, which is now the healthy gateway
+    let post = Request::builder()
+        .method("POST")
+        .uri("/some/page")
+        .header(header::HOST, DOMAIN)
+        .body(Body::empty())
+        .unwrap();
+    let response = build_router(state.clone()).oneshot(post).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = admin_get(state, "/").await;
+    let info = body_text(response).await;
+    assert!(info.contains("- down (^(down\\.test)$): unhealthy"), "{}", info);
+    assert!(info.contains("- up (^(up\\.test)$): healthy"), "{}", info);
+}
+
+#[tokio::test]
+async fn upgrade_without_upgradable_connection_is_bad_gateway() {
+    let state = state(dstack_records(), DnsSettings::default(), RelayMode::Proxy);
+    let request = Request::builder()
+        .uri("/socket")
+        .header(header::HOST, DOMAIN)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = build_router(state).oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn host_is_normalized_before_lookup() {
+    let state = state(dstack_records(), DnsSettings::default(), RelayMode::Redirect);
+
+    let response = get(state, "App.Example.COM.:80", "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn internationalized_host_is_looked_up_as_punycode() {
+    let resolver = StaticResolver::new()
+        .with_txt("_dstack-app-address.xn--bcher-kva.example", "my-app:80")
+        .with_cname("xn--bcher-kva.example", "_.prod5.phala.network");
+    let state = state(resolver, DnsSettings::default(), RelayMode::Redirect);
+    let request = Request::builder()
+        .uri("/.well-known/acme-challenge/token-123")
+        .header(header::HOST, header::HeaderValue::from_bytes("bücher.example".as_bytes()).unwrap())
+        .body(Body::empty())
+        .unwrap();
+
+    let response = build_router(state).oneshot(request).await.unwrap();
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn malformed_host_is_rejected_without_dns_lookup() {
+    let resolver = Arc::new(dstack_records());
+    let state = RelayState {
+        dns_resolver: Arc::new(DnsResolver::with_resolver(resolver.clone(), DnsSettings::default())),
+        proxy_client: ProxyClient::Hyper(HyperProxyClient::new()),
+        tunnel_client: TunnelClient::new(Duration::from_secs(60)),
+        relay_mode: RelayMode::Redirect,
+        txt_relay_mode: false,
+        trusted_proxies: TrustedProxies::default(),
+        challenge_only: None,
+        admin_token: None,
+    };
+
+    for host in ["app.example.com:http", "app..example.com", "app_x.example.com", "[::1"] {
+        let response = get(state.clone(), host, "/.well-known/acme-challenge/token-123").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", host);
+        let response = get(state.clone(), host, "/health").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", host);
+    }
+
+    // IP literals are the relay itself, which serves nothing on the public router
+    let response = get(state.clone(), "127.0.0.1:8081", "/some/page").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    assert_eq!(resolver.lookup_count(), 0);
+}
+
+/// A resolver consulting a mapping file with `content` before `resolver`
+fn state_with_domain_map(resolver: StaticResolver, name: &str, content: &str) -> RelayState {
+    let path = std::env::temp_dir().join(format!("relay-{}-{}", std::process::id(), name));
+    std::fs::write(&path, content).unwrap();
+    let domain_map = DomainMapFile::load(&path, Duration::ZERO).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let dns_resolver = DnsResolver::with_resolver(Arc::new(resolver), DnsSettings::default())
+        .with_domain_map(Arc::new(domain_map));
+    RelayState {
+        dns_resolver: Arc::new(dns_resolver),
+        proxy_client: ProxyClient::Hyper(HyperProxyClient::new()),
+        tunnel_client: TunnelClient::new(Duration::from_secs(60)),
+        relay_mode: RelayMode::Redirect,
+        txt_relay_mode: false,
+        trusted_proxies: TrustedProxies::default(),
+        challenge_only: None,
+        admin_token: None,
+    }
+}
+
+const DOMAIN_MAP: &str = r#"
+[[domains]]
+domain = "mapped.example.com"
+app_id = "mapped-app"
+port = 8080
+gateway = "prod7.phala.network"
+
+[[domains]]
+domain = "*.tenant.example.com"
+app_id = "tenant-app"
+"#;
+
+#[tokio::test]
+async fn domain_map_answers_without_txt_record() {
+    let state = state_with_domain_map(StaticResolver::new(), "map.toml", DOMAIN_MAP);
+
+    let response = get(state, "mapped.example.com", "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://mapped-app-8080.prod7.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn domain_map_wildcard_uses_cname_gateway() {
+    let resolver = StaticResolver::new().with_cname("a.tenant.example.com", "_.prod5.phala.network");
+    let state = state_with_domain_map(resolver, "wildcard.json", r#"{"domains": [
+        {"domain": "*.tenant.example.com", "app_id": "tenant-app"}
+    ]}"#);
+
+    let response = get(state, "a.tenant.example.com", "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://tenant-app.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+#[tokio::test]
+async fn unmapped_domain_falls_through_to_dns() {
+    let state = state_with_domain_map(dstack_records(), "fallthrough.toml", DOMAIN_MAP);
+
+    let response = get(state, DOMAIN, "/.well-known/acme-challenge/token-123").await;
+
+    assert_eq!(
+        location(&response),
+        "https://my-app.prod5.phala.network/.well-known/acme-challenge/token-123"
+    );
+}
+
+const POLICIES: &str = r#"
+[[policies]]
+domain = "app.example.com"
+relay_paths = false
+
+[[policies]]
+domain = "*.denied.example.com"
+mode = "deny"
+
+[[domains]]
+domain = "*.denied.example.com"
+app_id = "denied-app"
+gateway = "prod5.phala.network"
+"#;
+
+#[tokio::test]
+async fn policy_can_limit_relaying_to_challenges() {
+    let state = state_with_domain_map(dstack_records(), "paths.toml", POLICIES);
+
+    let response = get(state.clone(), DOMAIN, "/.well-known/acme-challenge/token-123").await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+
+    for path in ["/", "/some/page", "/health"] {
+        let response = get(state.clone(), DOMAIN, path).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "path {}", path);
+    }
+}
+
+#[tokio::test]
+async fn policy_can_deny_wildcard_domains() {
+    let state = state_with_domain_map(StaticResolver::new(), "deny.toml", POLICIES);
+
+    for path in ["/.well-known/acme-challenge/token-123", "/some/page"] {
+        let response = get(state.clone(), "a.denied.example.com", path).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "path {}", path);
+    }
+}
+
+#[tokio::test]
+async fn txt_mode_attribute_is_honored_only_when_enabled() {
+    let records = || {
+        StaticResolver::new()
+            .with_txt("_dstack-app-address.app.example.com", "v=dstack1; app=my-app; mode=https-redirect")
+            .with_cname("app.example.com", "_.prod5.phala.network.")
+    };
+
+    let ignored = state(records(), DnsSettings::default(), RelayMode::Redirect);
+    let response = get(ignored, DOMAIN, "/some/page").await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(location(&response), "https://my-app.prod5.phala.network/some/page");
+
+    let honored = RelayState {
+        txt_relay_mode: true,
+        ..state(records(), DnsSettings::default(), RelayMode::Redirect)
+    };
+    let response = get(honored, DOMAIN, "/some/page").await;
+    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(location(&response), "https://app.example.com/some/page");
+}
+
+#[tokio::test]
+async fn info_page_lists_domain_map_source() {
+    let state = state_with_domain_map(StaticResolver::new(), "info.toml", DOMAIN_MAP);
+
+    let response = admin_get(state, "/").await;
+
+    let body = body_text(response).await;
+    assert!(body.contains("Domain mapping file"), "{}", body);
+    assert!(body.contains("info.toml (2 entries)"), "{}", body);
+}
+
+#[tokio::test]
+async fn reload_swaps_in_new_config_and_keeps_old_one_on_errors() {
+    let path = std::env::temp_dir().join(format!("relay-{}-reload.toml", std::process::id()));
+    std::fs::write(&path, "relay_mode = \"redirect\"\n").unwrap();
+    let args = Args {
+        config_file: Some(path.clone()),
+        ..Args::default()
+    };
+    let values = Values::load(&args).unwrap();
+    let state = AppState::new(RelayState::new(&Config::from_values(&values).unwrap()).unwrap());
+    let reloader = Reloader::new(args, values, state.clone());
+
+    let in_flight = state.load();
+    std::fs::write(&path, "relay_mode = \"proxy\"\ntxt_relay_mode = true\n").unwrap();
+    reloader.reload().unwrap();
+    assert_eq!(state.load().relay_mode, RelayMode::Proxy);
+    assert!(state.load().txt_relay_mode);
+    // Requests already running keep the state they started with
+    assert_eq!(in_flight.relay_mode, RelayMode::Redirect);
+
+    let current = state.load();
+    std::fs::write(&path, "relay_mode = \"redirect\"\nallowed_domain_regex = \"(\"\n").unwrap();
+    let error = reloader.reload().unwrap_err();
+    assert!(error.contains("ALLOWED_DOMAIN_REGEX"), "{}", error);
+    assert!(Arc::ptr_eq(&current, &state.load()));
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(reloader.reload().is_err());
+    assert_eq!(state.load().relay_mode, RelayMode::Proxy);
+}
+
+#[tokio::test]
+async fn reload_keeps_dns_resolver_and_gateways_when_their_settings_are_unchanged() {
+    let path = std::env::temp_dir().join(format!("relay-{}-reload-dns.toml", std::process::id()));
+    std::fs::write(&path, "relay_mode = \"redirect\"\n").unwrap();
+    let args = Args {
+        config_file: Some(path.clone()),
+        ..Args::default()
+    };
+    let values = Values::load(&args).unwrap();
+    let state = AppState::new(RelayState::new(&Config::from_values(&values).unwrap()).unwrap());
+    let reloader = Reloader::new(args, values, state.clone());
+
+    // Only the relay policy changed: same resolver, cache and all
+    let before = state.load();
+    std::fs::write(&path, "relay_mode = \"proxy\"\n").unwrap();
+    reloader.reload().unwrap();
+    assert_eq!(state.load().relay_mode, RelayMode::Proxy);
+    assert!(Arc::ptr_eq(&before.dns_resolver, &state.load().dns_resolver));
+
+    // A DNS setting changed: new resolver, same gateways
+    let before = state.load();
+    std::fs::write(&path, "relay_mode = \"proxy\"\nfallback_gateway_domain = \"gw.example.com\"\n").unwrap();
+    reloader.reload().unwrap();
+    let after = state.load();
+    assert!(!Arc::ptr_eq(&before.dns_resolver, &after.dns_resolver));
+    assert!(Arc::ptr_eq(before.dns_resolver.gateways().first(), after.dns_resolver.gateways().first()));
+
+    // A gateway setting changed: new gateways
+    let before = state.load();
+    std::fs::write(
+        &path,
+        "relay_mode = \"proxy\"\nfallback_gateway_domain = \"gw.example.com\"\nallowed_domain_regex = \"^(.+)$\"\n",
+    )
+    .unwrap();
+    reloader.reload().unwrap();
+    assert!(!Arc::ptr_eq(before.dns_resolver.gateways().first(), state.load().dns_resolver.gateways().first()));
+
+    std::fs::remove_file(&path).unwrap();
+}